@@ -0,0 +1,16 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use divide_workload::{fast_sum, manual_sum, scoped_sum, threaded_sum};
+
+fn sums(c: &mut Criterion) {
+    let data: Vec<u32> = (0..1_000_000).collect();
+    let mut group = c.benchmark_group("sum");
+    group.bench_function("manual", |b| b.iter(|| manual_sum(black_box(&data))));
+    group.bench_function("fast", |b| b.iter(|| fast_sum(black_box(&data))));
+    group.bench_function("threaded", |b| b.iter(|| threaded_sum(black_box(&data), 8).unwrap()));
+    // The same threads, without copying each chunk first
+    group.bench_function("scoped", |b| b.iter(|| scoped_sum(black_box(&data), 8).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, sums);
+criterion_main!(benches);
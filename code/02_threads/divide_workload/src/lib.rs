@@ -0,0 +1,150 @@
+/// Add everything up one at a time, with a plain loop.
+pub fn manual_sum(data: &[u32]) -> u64 {
+    let mut sum = 0;
+    for i in data {
+        sum += *i as u64;
+    }
+    sum
+}
+
+/// The same thing as an iterator chain. There's no bounds checking or
+/// loop counter for the compiler to worry about, so it can turn this into
+/// SIMD instructions - adding several numbers at once. Build with
+/// `--release` to see the difference!
+pub fn fast_sum(data: &[u32]) -> u64 {
+    data.iter().map(|&x| x as u64).sum()
+}
+
+/// A worker thread panicked, so its part of the answer is missing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkPanicked {
+    /// Which chunk (counting from 0) the thread was working on
+    pub chunk: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ChunkPanicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the thread summing chunk {} panicked: {}", self.chunk, self.message)
+    }
+}
+
+impl std::error::Error for ChunkPanicked {}
+
+/// Divide the work between `n_threads` threads, and add up their results.
+pub fn threaded_sum(data: &[u32], n_threads: usize) -> Result<u64, ChunkPanicked> {
+    threaded_sum_with(data, n_threads, manual_sum)
+}
+
+/// Like `threaded_sum`, but each thread adds up its chunk with `sum_chunk`.
+pub fn threaded_sum_with<F>(data: &[u32], n_threads: usize, sum_chunk: F) -> Result<u64, ChunkPanicked>
+where
+    F: Fn(&[u32]) -> u64 + Send + Clone + 'static,
+{
+    // Round up, so we never make more than `n_threads` chunks. Zero threads
+    // would divide by zero - one is the least that can do the work.
+    let chunk_size = data.len().div_ceil(n_threads.max(1)).max(1);
+    let mut thread_handles = Vec::new();
+
+    // Notice that each chunk is a *slice* - a reference - to part of the array.
+    for chunk in data.chunks(chunk_size) {
+        // So we *move* the chunk into its own vector, taking ownership and
+        // passing that ownership to the thread. This adds a `memcpy` call
+        // to your code, but avoids ownership issues.
+        let my_chunk = chunk.to_owned();
+
+        // Each thread sums its own chunk. You could use .sum() for this!
+        let sum_chunk = sum_chunk.clone();
+        thread_handles.push(std::thread::spawn(move || sum_chunk(&my_chunk)));
+    }
+
+    // Sum the sums from each thread. `join` returns an error if the thread
+    // panicked - wait for all of them anyway, then report the first failure.
+    let mut sum = 0;
+    let mut failure = None;
+    for (chunk, handle) in thread_handles.into_iter().enumerate() {
+        match handle.join() {
+            Ok(chunk_sum) => sum += chunk_sum,
+            Err(payload) => {
+                failure.get_or_insert(ChunkPanicked { chunk, message: panic_message(payload) });
+            }
+        }
+    }
+    match failure {
+        Some(failure) => Err(failure),
+        None => Ok(sum),
+    }
+}
+
+/// Panic messages are usually a &str or a String
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// `threaded_sum` without the copies. Threads started in a scope have to
+/// finish before the scope ends, so Rust knows `data` outlives them and
+/// they can borrow their chunk instead of owning a copy of it.
+pub fn scoped_sum(data: &[u32], n_threads: usize) -> Result<u64, ChunkPanicked> {
+    let chunk_size = data.len().div_ceil(n_threads.max(1)).max(1);
+    std::thread::scope(|scope| {
+        let thread_handles: Vec<_> = data
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || manual_sum(chunk)))
+            .collect();
+
+        let mut sum = 0;
+        for (chunk, handle) in thread_handles.into_iter().enumerate() {
+            // Joining here means a panic is ours to report, rather than
+            // the scope's to re-throw
+            sum += handle.join().map_err(|payload| ChunkPanicked { chunk, message: panic_message(payload) })?;
+        }
+        Ok(sum)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_sum_agrees() {
+        let data: Vec<u32> = (0..100_000).collect();
+        let expected = 99_999 * 100_000 / 2;
+        assert_eq!(manual_sum(&data), expected);
+        assert_eq!(fast_sum(&data), expected);
+        assert_eq!(threaded_sum(&data, 8), Ok(expected));
+        assert_eq!(scoped_sum(&data, 8), Ok(expected));
+
+        // Big enough to overflow a u32
+        let big = vec![u32::MAX; 3];
+        assert_eq!(fast_sum(&big), u32::MAX as u64 * 3);
+        assert_eq!(threaded_sum(&big, 8), Ok(manual_sum(&big)));
+        assert_eq!(threaded_sum(&[], 8), Ok(0));
+        assert_eq!(scoped_sum(&big, 2), Ok(manual_sum(&big)));
+        assert_eq!(scoped_sum(&[], 8), Ok(0));
+
+        // Asking for no threads still gets the work done, on one
+        assert_eq!(threaded_sum(&data, 0), Ok(expected));
+        assert_eq!(scoped_sum(&data, 0), Ok(expected));
+    }
+
+    #[test]
+    fn panics_become_errors() {
+        let data: Vec<u32> = (0..100).collect();
+        // Four chunks of 25 - the third one starts at 50
+        let result = threaded_sum_with(&data, 4, |chunk| {
+            if chunk[0] == 50 {
+                panic!("chunk starting at {} is cursed", chunk[0]);
+            }
+            manual_sum(chunk)
+        });
+        let error = result.unwrap_err();
+        assert_eq!(error.chunk, 2);
+        assert_eq!(error.message, "chunk starting at 50 is cursed");
+        assert_eq!(error.to_string(), "the thread summing chunk 2 panicked: chunk starting at 50 is cursed");
+    }
+}
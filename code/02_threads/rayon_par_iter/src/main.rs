@@ -6,7 +6,71 @@ fn is_prime(n: u32) -> bool {
     (2 ..= n/2).into_par_iter().all(|i| n % i != 0 )
  }
 
+/// Don't let a typo start thousands of threads
+const MAX_THREADS: usize = 256;
+
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Parse a thread count. Missing, zero or invalid values fall back to
+/// one thread per core, and huge values are capped.
+fn parse_thread_count(value: Option<&str>) -> usize {
+    match value.map(|value| value.trim().parse::<usize>()) {
+        Some(Ok(threads)) if threads > 0 => threads.min(MAX_THREADS),
+        _ => default_threads(),
+    }
+}
+
+/// Size the global thread pool from `RAYON_THREADS`. This only works
+/// before anything has used the pool, so it needs to run first thing.
+fn init_thread_pool() -> usize {
+    let threads = parse_thread_count(std::env::var("RAYON_THREADS").ok().as_deref());
+    match rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+        Ok(()) => println!("Using {threads} threads"),
+        Err(e) => println!("The thread pool was already set up ({e}), using {} threads", rayon::current_num_threads()),
+    }
+    rayon::current_num_threads()
+}
+
+/// Which of `buckets` equal slices of the `u32` range `value` falls into
+fn bucket_of(value: u32, buckets: usize) -> usize {
+    ((value as u64 * buckets as u64) >> 32) as usize
+}
+
+fn serial_histogram(data: &[u32], buckets: usize) -> Vec<u64> {
+    let mut histogram = vec![0; buckets];
+    for value in data {
+        histogram[bucket_of(*value, buckets)] += 1;
+    }
+    histogram
+}
+
+/// Each thread fills in its own histogram with `fold`, and `reduce` adds
+/// them together. No thread ever waits on another to update a bucket.
+fn parallel_histogram(data: &[u32], buckets: usize) -> Vec<u64> {
+    if buckets == 0 {
+        return Vec::new();
+    }
+    data.par_iter()
+        .fold(
+            || vec![0; buckets],
+            |mut histogram, value| {
+                histogram[bucket_of(*value, buckets)] += 1;
+                histogram
+            },
+        )
+        .reduce(
+            || vec![0; buckets],
+            |mut total, partial| {
+                total.iter_mut().zip(partial).for_each(|(total, count)| *total += count);
+                total
+            },
+        )
+}
+
 fn main() {
+    init_thread_pool();
     let numbers: Vec<u64> = (0 .. 1_000_000).collect();
     let sum = numbers.par_iter().sum::<u64>();
     println!("Sum: {sum}");
@@ -29,4 +93,52 @@ fn main() {
     let elapsed = now.elapsed();
     //println!("{primes:?}");
     println!("It took {} ms to find {} primes, including a parallel sort", elapsed.as_millis(), primes.len());
+
+    // Reductions can build more than a single number
+    let values: Vec<u32> = (0 .. 1_000_000).map(|n: u32| n.wrapping_mul(2_654_435_761)).collect();
+    println!("Histogram: {:?}", parallel_histogram(&values, 10));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn thread_count_parsing() {
+        assert_eq!(parse_thread_count(Some("4")), 4);
+        assert_eq!(parse_thread_count(Some(" 2 ")), 2);
+        assert_eq!(parse_thread_count(Some("100000")), MAX_THREADS);
+        assert_eq!(parse_thread_count(None), default_threads());
+        assert_eq!(parse_thread_count(Some("0")), default_threads());
+        assert_eq!(parse_thread_count(Some("-1")), default_threads());
+        assert_eq!(parse_thread_count(Some("lots")), default_threads());
+    }
+
+    #[test]
+    fn histograms_match() {
+        // A quick xorshift, so we don't need a random number crate
+        let mut state = 0x2545_f491_u32;
+        let data: Vec<u32> = (0..100_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect();
+
+        for buckets in [1, 7, 64] {
+            let histogram = parallel_histogram(&data, buckets);
+            assert_eq!(histogram, serial_histogram(&data, buckets));
+            assert_eq!(histogram.iter().sum::<u64>(), data.len() as u64);
+        }
+        assert_eq!(parallel_histogram(&[0, u32::MAX], 2), vec![1, 1]);
+        assert!(parallel_histogram(&data, 0).is_empty());
+    }
+
+    #[test]
+    fn init_twice_is_harmless() {
+        let first = init_thread_pool();
+        assert_eq!(init_thread_pool(), first);
+    }
 }
@@ -1,7 +1,122 @@
-use std::{sync::Mutex, collections::VecDeque, time::Duration};
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc::RecvTimeoutError}, collections::VecDeque, time::Duration};
 use once_cell::sync::Lazy;
 
-static WORK_QUEUE: Lazy<Mutex<VecDeque<String>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+/// A job, and how long it's allowed to run (if there's a limit)
+#[derive(Debug, Clone, PartialEq)]
+struct Job {
+    name: String,
+    timeout: Option<Duration>,
+}
+
+/// A shared queue of jobs, counting what goes through it.
+struct WorkQueue {
+    queue: Mutex<VecDeque<Job>>,
+    submitted: AtomicUsize,
+    completed: AtomicUsize,
+    timed_out: AtomicUsize,
+    depth: AtomicUsize,
+}
+
+/// A snapshot of the queue's counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct QueueStats {
+    submitted: usize,
+    completed: usize,
+    timed_out: usize,
+    depth: usize,
+}
+
+impl WorkQueue {
+    fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            submitted: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            timed_out: AtomicUsize::new(0),
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    fn submit(&self, name: String) {
+        self.push(Job { name, timeout: None });
+    }
+
+    /// Submit a job that is abandoned if it runs for longer than `timeout`
+    fn submit_with_timeout(&self, name: String, timeout: Duration) {
+        self.push(Job { name, timeout: Some(timeout) });
+    }
+
+    fn push(&self, job: Job) {
+        let mut lock = self.queue.lock().unwrap();
+        lock.push_back(job);
+        // Update while we hold the lock, so depth always matches the queue
+        self.depth.store(lock.len(), Ordering::Relaxed);
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take the next job, if there is one. Call `complete` when it's done.
+    fn take(&self) -> Option<Job> {
+        let mut lock = self.queue.lock().unwrap();
+        let job = lock.pop_front();
+        self.depth.store(lock.len(), Ordering::Relaxed);
+        job
+    }
+
+    fn complete(&self) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Run `work` for a job on its own thread, and wait for it - up to the
+    /// job's timeout. If it overruns, the cancel flag passed to `work` is
+    /// raised (well-behaved jobs check it and stop), the timeout is counted,
+    /// and we stop waiting. Returns true if the job finished in time.
+    fn run<F>(&self, job: &Job, work: F) -> bool
+    where
+        F: FnOnce(&AtomicBool) + Send + 'static,
+    {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let job_cancel = cancel.clone();
+        std::thread::spawn(move || {
+            work(&job_cancel);
+            let _ = done_tx.send(());
+        });
+
+        let result = match job.timeout {
+            Some(timeout) => done_rx.recv_timeout(timeout),
+            None => done_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match result {
+            Ok(()) => {
+                self.complete();
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                cancel.store(true, Ordering::Relaxed);
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                println!("Job {} timed out", job.name);
+                false
+            }
+            // The job panicked
+            Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            submitted: self.submitted.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            depth: self.depth.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static WORK_QUEUE: Lazy<WorkQueue> = Lazy::new(WorkQueue::new);
 
 fn main() {
     // Commented out for clarity: a real work pool will use this
@@ -17,12 +132,20 @@ fn main() {
 
         let thread = std::thread::spawn(move || {
             while rx.recv().is_ok() {
-                let mut lock = WORK_QUEUE.lock().unwrap();
-                if let Some(work) = lock.pop_front() {
-                    std::mem::drop(lock);
-                    println!("CPU {cpu} got work: {work}");
-                    std::thread::sleep(Duration::from_secs(2));
-                    println!("CPU {cpu} finished!");
+                if let Some(job) = WORK_QUEUE.take() {
+                    println!("CPU {cpu} got work: {}", job.name);
+                    let finished = WORK_QUEUE.run(&job, |cancel| {
+                        // Work in small steps, so we notice being cancelled
+                        for _ in 0..20 {
+                            if cancel.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            std::thread::sleep(Duration::from_millis(100));
+                        }
+                    });
+                    if finished {
+                        println!("CPU {cpu} finished!");
+                    }
                 } else {
                     println!("CPU {cpu} found no work");
                 }
@@ -33,20 +156,66 @@ fn main() {
     }
 
     loop {
-        let sent = {
-            let mut lock = WORK_QUEUE.lock().unwrap();
-            let len = lock.len();
-            println!("There are {len} items in the queue");
-            if len < 5 {
-                lock.push_back("Hello".to_string());
-                true
+        let len = WORK_QUEUE.len();
+        println!("There are {len} items in the queue - {:?}", WORK_QUEUE.stats());
+        if len < 5 {
+            // Every so often, a job that can't possibly finish in time
+            if WORK_QUEUE.stats().submitted % 5 == 4 {
+                WORK_QUEUE.submit_with_timeout("Impatient".to_string(), Duration::from_secs(1));
             } else {
-                false
+                WORK_QUEUE.submit("Hello".to_string());
             }
-        };
-        if sent {
             broadcast.iter().for_each(|tx| tx.send(()).unwrap());
         }
         std::thread::sleep(Duration::from_secs(1));
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters_track_jobs() {
+        let queue = WorkQueue::new();
+        for n in 0..100 {
+            queue.submit(n.to_string());
+        }
+        assert_eq!(queue.stats(), QueueStats { submitted: 100, completed: 0, timed_out: 0, depth: 100 });
+
+        // Drain it from a few threads
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while queue.take().is_some() {
+                        queue.complete();
+                    }
+                });
+            }
+        });
+        assert_eq!(queue.stats(), QueueStats { submitted: 100, completed: 100, timed_out: 0, depth: 0 });
+    }
+
+    #[test]
+    fn overrunning_jobs_time_out() {
+        let queue = WorkQueue::new();
+        queue.submit_with_timeout("slow".to_string(), Duration::from_millis(50));
+        queue.submit("quick".to_string());
+
+        // The slow job only stops when it's told to
+        let (cancelled_tx, cancelled_rx) = std::sync::mpsc::channel();
+        let slow = queue.take().unwrap();
+        assert!(!queue.run(&slow, move |cancel| {
+            while !cancel.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            cancelled_tx.send(()).unwrap();
+        }));
+        cancelled_rx.recv_timeout(Duration::from_secs(5)).expect("the job wasn't cancelled");
+
+        // The worker carries on with the next one
+        let quick = queue.take().unwrap();
+        assert!(queue.run(&quick, |_cancel| {}));
+        assert_eq!(queue.stats(), QueueStats { submitted: 2, completed: 1, timed_out: 1, depth: 0 });
+    }
+}
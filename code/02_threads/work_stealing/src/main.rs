@@ -0,0 +1,130 @@
+use crossbeam_deque::{Injector, Stealer, Worker};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+/// A pool of threads, each with its own queue of work. New jobs go into a
+/// shared "injector" queue; a worker takes a batch from there into its own
+/// queue, and when it runs dry it steals from the other workers.
+struct WorkStealingPool<T> {
+    injector: Arc<Injector<T>>,
+    shutdown: Arc<AtomicBool>,
+    completed: Arc<Vec<AtomicUsize>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> WorkStealingPool<T> {
+    /// Start `workers` threads, each calling `handler` for every job it runs.
+    fn new<F>(workers: usize, handler: F) -> Self
+    where
+        F: Fn(usize, T) + Send + Sync + 'static,
+    {
+        let injector = Arc::new(Injector::new());
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let completed: Arc<Vec<AtomicUsize>> = Arc::new((0..workers).map(|_| AtomicUsize::new(0)).collect());
+        let handler = Arc::new(handler);
+
+        // Every worker needs a stealer for everyone's queue, so make them all first
+        let queues: Vec<Worker<T>> = (0..workers).map(|_| Worker::new_fifo()).collect();
+        let stealers: Arc<Vec<Stealer<T>>> = Arc::new(queues.iter().map(Worker::stealer).collect());
+
+        let threads = queues
+            .into_iter()
+            .enumerate()
+            .map(|(id, local)| {
+                let injector = injector.clone();
+                let stealers = stealers.clone();
+                let shutdown = shutdown.clone();
+                let completed = completed.clone();
+                let handler = handler.clone();
+                std::thread::spawn(move || loop {
+                    match find_job(&local, &injector, &stealers) {
+                        Some(job) => {
+                            handler(id, job);
+                            completed[id].fetch_add(1, Ordering::Relaxed);
+                        }
+                        // Only quit once there's nothing left anywhere
+                        None if shutdown.load(Ordering::Acquire) => break,
+                        // Nothing to do - rest briefly rather than spinning
+                        None => std::thread::park_timeout(Duration::from_millis(1)),
+                    }
+                })
+            })
+            .collect();
+
+        Self { injector, shutdown, completed, threads }
+    }
+
+    fn submit(&self, job: T) {
+        self.injector.push(job);
+    }
+
+    /// Finish every submitted job, stop the workers, and return how many
+    /// jobs each one ran.
+    fn join(self) -> Vec<usize> {
+        self.shutdown.store(true, Ordering::Release);
+        for thread in self.threads {
+            thread.join().unwrap();
+        }
+        self.completed.iter().map(|count| count.load(Ordering::Relaxed)).collect()
+    }
+}
+
+/// Our own queue first, then a batch from the injector, then steal from
+/// someone else. `Steal::Retry` means we lost a race, so try again.
+fn find_job<T>(local: &Worker<T>, injector: &Injector<T>, stealers: &[Stealer<T>]) -> Option<T> {
+    local.pop().or_else(|| {
+        std::iter::repeat_with(|| {
+            injector
+                .steal_batch_and_pop(local)
+                .or_else(|| stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(|steal| steal.success())
+    })
+}
+
+fn main() {
+    let pool = WorkStealingPool::new(4, |worker, n: u64| {
+        // Some jobs take much longer than others - that's where stealing helps
+        std::thread::sleep(Duration::from_millis(n % 10));
+        println!("Worker {worker} finished job {n}");
+    });
+    for n in 0..100 {
+        pool.submit(n);
+    }
+
+    let completed = pool.join();
+    for (worker, count) in completed.iter().enumerate() {
+        println!("Worker {worker} ran {count} jobs");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_job_runs_and_work_is_shared() {
+        let total = Arc::new(AtomicUsize::new(0));
+        let handler_total = total.clone();
+        let pool = WorkStealingPool::new(4, move |_worker, n: usize| {
+            std::thread::sleep(Duration::from_micros(200));
+            handler_total.fetch_add(n, Ordering::Relaxed);
+        });
+        for n in 0..1000 {
+            pool.submit(n);
+        }
+        let completed = pool.join();
+
+        assert_eq!(completed.iter().sum::<usize>(), 1000);
+        assert_eq!(total.load(Ordering::Relaxed), (0..1000).sum());
+        // More than one worker got a share
+        assert!(completed.iter().filter(|count| **count > 0).count() > 1, "{completed:?}");
+    }
+}
@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, BufReader};
 use std::path::Path;
 
 // Taken from: https://doc.rust-lang.org/rust-by-example/std_misc/file/read_lines.html
@@ -9,6 +9,38 @@ where P: AsRef<Path>, {
     Ok(io::BufReader::new(file).lines())
 }
 
+/// Count (lines, words, bytes) in a single pass, like the `wc` tool.
+/// Lines are newline characters; words are runs of non-whitespace.
+fn wc<P: AsRef<Path>>(filename: P) -> io::Result<(usize, usize, usize)> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    let (mut lines, mut words, mut bytes) = (0, 0, 0);
+    // A word can be split across two buffers, so remember where we were
+    let mut in_word = false;
+
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            break;
+        }
+        for byte in buffer {
+            if *byte == b'\n' {
+                lines += 1;
+            }
+            if byte.is_ascii_whitespace() {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                words += 1;
+            }
+        }
+        let length = buffer.len();
+        bytes += length;
+        reader.consume(length);
+    }
+
+    Ok((lines, words, bytes))
+}
+
 async fn line_count(filename: String) -> anyhow::Result<usize> {
     println!("Reading {filename}...");
     let now = std::time::Instant::now();
@@ -68,5 +100,21 @@ async fn main() -> anyhow::Result<()> {
     );
     println!("Total lines: {}", c1? + c2?);
     println!("In {:.3} seconds", now.elapsed().as_secs_f32());
+    println!("----------------------------------------------------");
+
+    // Everything at once
+    let (lines, words, bytes) = wc("warandpeace.txt")?;
+    println!("{lines} lines, {words} words, {bytes} bytes");
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wc_counts_match() {
+        // Checked against the real `wc`
+        assert_eq!(wc("wc_fixture.txt").unwrap(), (4, 16, 97));
+    }
+}
@@ -1,4 +1,39 @@
-use sqlx::{Row, FromRow};
+use clap::{Parser, Subcommand};
+use sqlx::{migrate::Migrate, Row, FromRow, SqliteConnection};
+use futures::Stream;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+#[command()]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Apply any pending migrations, and exit.
+    Migrate {
+        /// List the pending migrations without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print every message, then keep printing new ones as they arrive.
+    Watch,
+    /// Replace every message with the sample set, for a known starting point.
+    Seed,
+    /// Time inserts with and without prepared statement caching. Nothing
+    /// is kept: the inserts are rolled back.
+    Bench {
+        /// How many messages to insert each way
+        #[arg(long, default_value_t = 10_000)]
+        count: usize,
+    },
+}
+
+/// How often `watch_messages` checks for new rows.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Debug, FromRow)]
 struct Message {
@@ -15,8 +50,144 @@ async fn update_message(id: i64, message: &str, pool: &sqlx::SqlitePool) -> anyh
     Ok(())
 }
 
+/// A never-ending stream of messages. Starts with the existing messages,
+/// then polls for rows with an id greater than the last one it yielded.
+fn watch_messages(pool: sqlx::SqlitePool) -> impl Stream<Item = Message> {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    // If a query runs long, wait a full interval rather than catching up
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let state = (pool, 0_i64, VecDeque::<Message>::new(), interval);
+
+    futures::stream::unfold(state, |(pool, mut last_id, mut pending, mut interval)| async move {
+        // Wait for the next tick whenever there's nothing left to hand out
+        while pending.is_empty() {
+            interval.tick().await;
+            match sqlx::query_as::<_, Message>(
+                "SELECT id, message FROM messages WHERE id > ? ORDER BY id",
+            )
+            .bind(last_id)
+            .fetch_all(&pool)
+            .await
+            {
+                Ok(messages) => pending.extend(messages),
+                Err(e) => tracing::error!("Unable to poll for messages: {e:?}"),
+            }
+        }
+        let message = pending.pop_front()?;
+        last_id = message.id;
+        Some((message, (pool, last_id, pending, interval)))
+    })
+}
+
+/// The sample messages `seed` loads
+const SEED_MESSAGES: [(i64, &str); 6] = [
+    (1, "Hello World!"),
+    (2, "Hello Galaxy!"),
+    (3, "Hello Universe!"),
+    (4, "Another Message"),
+    (5, "Yet Another Message"),
+    (6, "Messages Never End"),
+];
+
+/// Empty the messages table and load the sample messages. Running it again
+/// gives the same result. Returns how many messages were added.
+async fn seed(pool: &sqlx::SqlitePool) -> anyhow::Result<usize> {
+    // All or nothing
+    let mut transaction = pool.begin().await?;
+    sqlx::query("DELETE FROM messages")
+        .execute(&mut transaction)
+        .await?;
+    for (id, message) in SEED_MESSAGES {
+        sqlx::query("INSERT INTO messages (id, message) VALUES (?, ?)")
+            .bind(id)
+            .bind(message)
+            .execute(&mut transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+    Ok(SEED_MESSAGES.len())
+}
+
+/// Insert every message on one connection, returning how many were added.
+///
+/// With `cached`, SQLite parses the statement once: sqlx keeps the prepared
+/// statement on the connection and reuses it for every later query with the
+/// same SQL. This is sqlx's default - without it, every insert is parsed
+/// and planned from scratch.
+async fn insert_messages(connection: &mut SqliteConnection, messages: &[String], cached: bool) -> anyhow::Result<u64> {
+    let mut inserted = 0;
+    for message in messages {
+        inserted += sqlx::query("INSERT INTO messages (message) VALUES (?)")
+            .bind(message)
+            .persistent(cached)
+            .execute(&mut *connection)
+            .await?
+            .rows_affected();
+    }
+    Ok(inserted)
+}
+
+/// How long it takes to insert `count` messages, without and with
+/// statement caching. Each run is rolled back.
+async fn bench_inserts(pool: &sqlx::SqlitePool, count: usize) -> anyhow::Result<(Duration, Duration)> {
+    let messages: Vec<String> = (0..count).map(|n| format!("Benchmark message {n}")).collect();
+    let mut timings = Vec::new();
+    for cached in [false, true] {
+        // A transaction keeps disk syncs from drowning out the difference
+        let mut transaction = pool.begin().await?;
+        let start = Instant::now();
+        insert_messages(&mut transaction, &messages, cached).await?;
+        timings.push(start.elapsed());
+        transaction.rollback().await?;
+    }
+    Ok((timings[0], timings[1]))
+}
+
+/// Has sqlx ever run migrations on this database?
+async fn has_migrations_table(connection: &mut SqliteConnection) -> anyhow::Result<bool> {
+    let tables: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'")
+        .fetch_one(connection)
+        .await?;
+    Ok(tables > 0)
+}
+
+/// Migrations (version and description) that haven't been applied yet.
+/// Only reads the database - without a migrations table, they all are.
+async fn pending_migrations(pool: &sqlx::SqlitePool) -> anyhow::Result<Vec<(i64, String)>> {
+    let migrator = sqlx::migrate!("./migrations");
+    let mut connection = pool.acquire().await?;
+    let applied: HashSet<i64> = if has_migrations_table(&mut connection).await? {
+        connection
+            .list_applied_migrations()
+            .await?
+            .into_iter()
+            .map(|migration| migration.version)
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    Ok(migrator
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| (migration.version, migration.description.to_string()))
+        .collect())
+}
+
+/// Run the pending migrations (unless `dry_run` is set), returning the
+/// ones that were pending.
+async fn migrate(pool: &sqlx::SqlitePool, dry_run: bool) -> anyhow::Result<Vec<(i64, String)>> {
+    let pending = pending_migrations(pool).await?;
+    if !dry_run {
+        sqlx::migrate!("./migrations").run(pool).await?;
+    }
+    Ok(pending)
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Args::parse();
+
     // Enable tracing
     tracing_subscriber::fmt::init();
 
@@ -27,11 +198,46 @@ async fn main() -> anyhow::Result<()> {
     // Get a database connection pool
     let pool = sqlx::SqlitePool::connect(&db_url).await?;
 
+    if let Some(Commands::Migrate { dry_run }) = cli.command {
+        let migrations = migrate(&pool, dry_run).await?;
+        if migrations.is_empty() {
+            println!("The database is up to date");
+        }
+        for (version, description) in migrations {
+            let status = if dry_run { "Pending" } else { "Applied" };
+            println!("{status}: {version} {description}");
+        }
+        return Ok(());
+    }
+
     // Run Migrations
     sqlx::migrate!("./migrations")
         .run(&pool)
         .await?;
 
+    if let Some(Commands::Seed) = cli.command {
+        let count = seed(&pool).await?;
+        println!("Seeded {count} messages");
+        return Ok(());
+    }
+
+    if let Some(Commands::Bench { count }) = cli.command {
+        let (uncached, cached) = bench_inserts(&pool, count).await?;
+        println!("{count} inserts, parsed every time: {uncached:?}");
+        println!("{count} inserts, prepared once:     {cached:?}");
+        return Ok(());
+    }
+
+    if let Some(Commands::Watch) = cli.command {
+        use futures::StreamExt;
+        let messages = watch_messages(pool);
+        tokio::pin!(messages);
+        while let Some(message) = messages.next().await {
+            println!("{message:?}");
+        }
+        return Ok(());
+    }
+
     // Update message 1
     update_message(1, "First Message", &pool).await?;
 
@@ -69,3 +275,108 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sqlx::sqlite::SqliteConnectOptions;
+
+    async fn temp_database(directory: &tempfile::TempDir) -> sqlx::SqlitePool {
+        let options = SqliteConnectOptions::new()
+            .filename(directory.path().join("test.db"))
+            .create_if_missing(true);
+        sqlx::SqlitePool::connect_with(options).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrations_apply_once() {
+        let directory = tempfile::tempdir().unwrap();
+        let pool = temp_database(&directory).await;
+
+        // A dry run doesn't change anything - not even adding sqlx's table
+        let pending = migrate(&pool, true).await.unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending_migrations(&pool).await.unwrap().len(), 2);
+        assert!(!has_migrations_table(&mut pool.acquire().await.unwrap()).await.unwrap());
+
+        // Apply them, then there's nothing left to do
+        let applied = migrate(&pool, false).await.unwrap();
+        assert_eq!(applied, pending);
+        assert!(migrate(&pool, false).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn seeding_is_repeatable() {
+        let directory = tempfile::tempdir().unwrap();
+        let pool = temp_database(&directory).await;
+        migrate(&pool, false).await.unwrap();
+
+        // Mess the table up, then seed twice
+        update_message(1, "Changed", &pool).await.unwrap();
+        sqlx::query("INSERT INTO messages (id, message) VALUES (99, 'Extra')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(seed(&pool).await.unwrap(), 6);
+            let messages = sqlx::query_as::<_, Message>("SELECT id, message FROM messages ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            let messages: Vec<(i64, &str)> = messages.iter().map(|m| (m.id, m.message.as_str())).collect();
+            assert_eq!(messages, SEED_MESSAGES);
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_inserts_all_succeed() {
+        let directory = tempfile::tempdir().unwrap();
+        let pool = temp_database(&directory).await;
+        migrate(&pool, false).await.unwrap();
+
+        let messages: Vec<String> = (0..100).map(|n| format!("Message {n}")).collect();
+        let mut connection = pool.acquire().await.unwrap();
+        assert_eq!(insert_messages(&mut connection, &messages, true).await.unwrap(), 100);
+        assert_eq!(insert_messages(&mut connection, &messages, false).await.unwrap(), 100);
+        drop(connection);
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE message LIKE 'Message %'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 200);
+
+        // The benchmark leaves nothing behind
+        bench_inserts(&pool, 10).await.unwrap();
+        let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages").fetch_one(&pool).await.unwrap();
+        assert_eq!(total, 206);
+    }
+
+    #[tokio::test]
+    async fn watch_yields_new_messages() {
+        use futures::StreamExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let pool = temp_database(&directory).await;
+        migrate(&pool, false).await.unwrap();
+
+        let messages = watch_messages(pool.clone());
+        tokio::pin!(messages);
+
+        // The six messages from the migrations come first
+        let existing: Vec<i64> = messages.as_mut().take(6).map(|m| m.id).collect().await;
+        assert_eq!(existing, vec![1, 2, 3, 4, 5, 6]);
+
+        sqlx::query("INSERT INTO messages (id, message) VALUES (7, 'Something new')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .expect("the new message wasn't noticed")
+            .unwrap();
+        assert_eq!(message.id, 7);
+        assert_eq!(message.message, "Something new");
+    }
+}
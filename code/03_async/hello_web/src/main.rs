@@ -1,21 +1,127 @@
-use axum::{routing::{get, post}, Router};
+use axum::{routing::{get, post}, Router, http::{header, HeaderValue, Method, Request, StatusCode, Uri}};
+use axum::{extract::{ConnectInfo, State}, middleware::{self, Next}, response::{IntoResponse, Response}};
 use serde::Serialize;
-use std::{net::SocketAddr, path::Path};
+use std::{collections::{HashMap, VecDeque}, net::{IpAddr, SocketAddr}, path::Path, sync::{Arc, Mutex}, time::{Duration, Instant}};
 use axum::response::Html;
+use tower_http::{
+    cors::CorsLayer,
+    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
+    LatencyUnit,
+};
+use tracing::Level;
+
+/// Allow browsers on the listed origins (comma separated) to call the JSON routes
+fn cors_layer(allowed_origins: &str) -> CorsLayer {
+    let origins: Vec<HeaderValue> = allowed_origins
+        .split(',')
+        .map(|origin| origin.trim())
+        .filter(|origin| !origin.is_empty())
+        .filter_map(|origin| origin.parse().ok())
+        .collect();
+
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE])
+}
+
+/// Allows each client address `limit` requests in any `window`
+#[derive(Clone)]
+struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    requests: Arc<Mutex<HashMap<IpAddr, VecDeque<Instant>>>>,
+}
+
+impl RateLimiter {
+    fn new(limit: usize, window: Duration) -> Self {
+        Self { limit, window, requests: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Read `RATE_LIMIT` (requests) and `RATE_LIMIT_WINDOW_SECS`
+    fn from_env() -> Self {
+        fn read(name: &str, default: u64) -> u64 {
+            std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+        }
+        Self::new(read("RATE_LIMIT", 60) as usize, Duration::from_secs(read("RATE_LIMIT_WINDOW_SECS", 60)))
+    }
+
+    /// Record a request from `ip`, returning false if it's over the limit
+    fn check(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        // Forget anyone whose requests have all slid out of the window
+        requests.retain(|_, times| times.back().map(|last| now.duration_since(*last) < self.window).unwrap_or(false));
+
+        let times = requests.entry(ip).or_default();
+        while times.front().map(|first| now.duration_since(*first) >= self.window).unwrap_or(false) {
+            times.pop_front();
+        }
+        if times.len() >= self.limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+async fn rate_limit<B>(State(limiter): State<RateLimiter>, request: Request<B>, next: Next<B>) -> Response {
+    // Without a client address (e.g. in some tests) there's nothing to limit by
+    let client = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    if let Some(ip) = client {
+        if !limiter.check(ip, Instant::now()) {
+            tracing::warn!("Rate limiting {ip}");
+            return (StatusCode::TOO_MANY_REQUESTS, "Too many requests - slow down").into_response();
+        }
+    }
+    next.run(request).await
+}
+
+fn app(cors: CorsLayer, limiter: RateLimiter) -> Router {
+    // Only the JSON API is available to other origins
+    let json_routes = Router::new()
+        .route("/json", get(say_hello_json))
+        .layer(cors);
+
+    Router::new()
+        .route("/", get(say_hello_file))
+        .route("/post", post(say_hello_post))
+        .merge(json_routes)
+        .fallback(not_found)
+        .layer(middleware::from_fn_with_state(limiter, rate_limit))
+        // Log the method, path, status and latency of every request
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::INFO)
+                        .latency_unit(LatencyUnit::Millis),
+                ),
+        )
+}
 
 #[tokio::main]
 async fn main() {
-    let app = Router::new()
-        .route("/", get(say_hello_file))
-        .route("/json", get(say_hello_json))
-        .route("/post", post(say_hello_post));
+    tracing_subscriber::fmt::init();
+
+    let origins = std::env::var("CORS_ORIGINS").unwrap_or("http://localhost:8080".to_string());
+    let app = app(cors_layer(&origins), RateLimiter::from_env());
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));    
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        // The rate limiter needs to know who is connecting
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
 }
 
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install the Ctrl-C handler");
+    println!("Ctrl-C received, shutting down");
+}
+
 async fn say_hello_text() -> &'static str {
     "Hello, world!"
 }
@@ -48,4 +154,142 @@ async fn say_hello_json() -> axum::Json<HelloJson> {
 
 async fn say_hello_post() -> &'static str {
     "Hello, POST!"
-}
\ No newline at end of file
+}
+
+async fn not_found(uri: Uri) -> (StatusCode, Html<String>) {
+    // The path comes from the client, so escape it before putting it in the page
+    let path = uri
+        .path()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+    (
+        StatusCode::NOT_FOUND,
+        Html(format!("<h1>404 - Not Found</h1><p>Nothing lives at {path}</p>")),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tracing_subscriber::fmt::MakeWriter;
+
+    /// Collects everything written to it, so tests can inspect the logs
+    #[derive(Clone, Default)]
+    struct CaptureWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CaptureWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for CaptureWriter {
+        type Writer = CaptureWriter;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn test_app() -> Router {
+        app(cors_layer(""), RateLimiter::new(100, Duration::from_secs(60)))
+    }
+
+    fn request_from(ip: &str) -> Request<Body> {
+        let mut request = Request::builder().uri("/json").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip.parse().unwrap(), 1234)));
+        request
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_are_rejected() {
+        let app = app(cors_layer(""), RateLimiter::new(2, Duration::from_secs(60)));
+        for _ in 0..2 {
+            let response = app.clone().oneshot(request_from("10.0.0.1")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app.clone().oneshot(request_from("10.0.0.1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Someone else is fine
+        let response = app.oneshot(request_from("10.0.0.2")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn rate_limit_window_slides() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        assert!(limiter.check(ip, start));
+        assert!(limiter.check(ip, start + Duration::from_secs(5)));
+        assert!(!limiter.check(ip, start + Duration::from_secs(6)));
+        // The first request has dropped out of the window
+        assert!(limiter.check(ip, start + Duration::from_secs(10)));
+        assert!(!limiter.check(ip, start + Duration::from_secs(11)));
+    }
+
+    #[tokio::test]
+    async fn unknown_path_is_404() {
+        let response = test_app()
+            .oneshot(Request::builder().uri("/does/not/exist").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("/does/not/exist"));
+    }
+
+    #[tokio::test]
+    async fn cors_preflight_allows_origin() {
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/json")
+            .header(header::ORIGIN, "http://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap();
+        let response = app(cors_layer("http://example.com, http://localhost:8080"), RateLimiter::new(100, Duration::from_secs(60)))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "http://example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_are_logged() {
+        let writer = CaptureWriter::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let response = test_app()
+            .oneshot(Request::builder().uri("/json").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let logs = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("method=GET"));
+        assert!(logs.contains("uri=/json"));
+        assert!(logs.contains("status=200"));
+    }
+}
@@ -29,8 +29,54 @@ async fn async_fibonacci_easier(n: u64) -> u64 {
     }
 }
 
+// Remember every value we've calculated, so each n is only computed once
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+type FibCache = Arc<Mutex<HashMap<u64, u64>>>;
+
+#[async_recursion]
+async fn async_fibonacci_memo(n: u64, cache: FibCache) -> u64 {
+    if n < 2 {
+        return n;
+    }
+
+    // Don't hold the lock across an await!
+    let cached = cache.lock().unwrap().get(&n).copied();
+    if let Some(value) = cached {
+        return value;
+    }
+
+    let value = async_fibonacci_memo(n - 1, cache.clone()).await
+        + async_fibonacci_memo(n - 2, cache.clone()).await;
+    cache.lock().unwrap().insert(n, value);
+    value
+}
+
 #[tokio::main]
 async fn main() {
     println!("fibonacci(10) = {}", async_fibonacci(10).await);
     println!("fibonacci(10) = {}", async_fibonacci_easier(10).await);
+
+    let cache = FibCache::default();
+    println!("fibonacci(40) = {}", async_fibonacci_memo(40, cache).await);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn memo_matches_naive() {
+        let cache = FibCache::default();
+        for n in 0..20 {
+            assert_eq!(async_fibonacci_memo(n, cache.clone()).await, async_fibonacci_easier(n).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn memo_is_fast() {
+        let start = std::time::Instant::now();
+        let result = async_fibonacci_memo(90, FibCache::default()).await;
+        assert_eq!(result, 2_880_067_194_370_816_120);
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
 }
\ No newline at end of file
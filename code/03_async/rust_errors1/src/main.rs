@@ -22,28 +22,50 @@ fn main() {
 
     match contents {
         Ok(contents) => println!("File contents: {contents}"),
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::NotFound => println!("File not found"),
-            std::io::ErrorKind::PermissionDenied => println!("Permission denied"),
-            std::io::ErrorKind::ConnectionRefused => todo!(),
-            std::io::ErrorKind::ConnectionReset => todo!(),
-            std::io::ErrorKind::ConnectionAborted => todo!(),
-            std::io::ErrorKind::NotConnected => todo!(),
-            std::io::ErrorKind::AddrInUse => todo!(),
-            std::io::ErrorKind::AddrNotAvailable => todo!(),
-            std::io::ErrorKind::BrokenPipe => todo!(),
-            std::io::ErrorKind::AlreadyExists => todo!(),
-            std::io::ErrorKind::WouldBlock => todo!(),
-            std::io::ErrorKind::InvalidInput => todo!(),
-            std::io::ErrorKind::InvalidData => todo!(),
-            std::io::ErrorKind::TimedOut => todo!(),
-            std::io::ErrorKind::WriteZero => todo!(),
-            std::io::ErrorKind::Interrupted => todo!(),
-            std::io::ErrorKind::Unsupported => todo!(),
-            std::io::ErrorKind::UnexpectedEof => todo!(),
-            std::io::ErrorKind::OutOfMemory => todo!(),
-            std::io::ErrorKind::Other => todo!(),
-            _ => todo!(),            
-        },
+        Err(e) => println!("{}", describe_io_error(e.kind())),
+    }
+}
+
+/// A friendly message for each kind of I/O error
+fn describe_io_error(kind: std::io::ErrorKind) -> &'static str {
+    use std::io::ErrorKind;
+    match kind {
+        ErrorKind::NotFound => "File not found",
+        ErrorKind::PermissionDenied => "Permission denied",
+        ErrorKind::ConnectionRefused => "The connection was refused",
+        ErrorKind::ConnectionReset => "The connection was reset",
+        ErrorKind::ConnectionAborted => "The connection was aborted",
+        ErrorKind::NotConnected => "Not connected",
+        ErrorKind::AddrInUse => "The address is already in use",
+        ErrorKind::AddrNotAvailable => "The address isn't available",
+        ErrorKind::BrokenPipe => "The pipe was broken",
+        ErrorKind::AlreadyExists => "It already exists",
+        ErrorKind::WouldBlock => "The operation would block",
+        ErrorKind::InvalidInput => "Invalid input",
+        ErrorKind::InvalidData => "The data isn't valid",
+        ErrorKind::TimedOut => "The operation timed out",
+        ErrorKind::WriteZero => "Nothing could be written",
+        ErrorKind::Interrupted => "The operation was interrupted",
+        ErrorKind::Unsupported => "The operation isn't supported",
+        ErrorKind::UnexpectedEof => "Reached the end of the file unexpectedly",
+        ErrorKind::OutOfMemory => "Out of memory",
+        ErrorKind::Other => "Something went wrong",
+        // ErrorKind is non_exhaustive, so new kinds may be added
+        _ => "An unknown error occurred",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn describes_error_kinds() {
+        assert_eq!(describe_io_error(ErrorKind::NotFound), "File not found");
+        assert_eq!(describe_io_error(ErrorKind::PermissionDenied), "Permission denied");
+        assert_eq!(describe_io_error(ErrorKind::TimedOut), "The operation timed out");
+        assert_eq!(describe_io_error(ErrorKind::AlreadyExists), "It already exists");
+        assert_eq!(describe_io_error(ErrorKind::Other), "Something went wrong");
     }
 }
@@ -1,3 +1,5 @@
+use std::{future::Future, time::Duration};
+
 async fn divide(number: u32, divisor: u32) -> anyhow::Result<u32> {
     if divisor == 0 {
         anyhow::bail!("Dividing by zero is a bad idea")
@@ -6,6 +8,29 @@ async fn divide(number: u32, divisor: u32) -> anyhow::Result<u32> {
     }
 }
 
+/// Run `op` up to `attempts` times, waiting twice as long after each failure.
+/// Returns the first success, or the last error once we run out of attempts.
+async fn retry<T, E, F, Fut>(attempts: u32, mut op: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delay = Duration::from_millis(100);
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            // Always try at least once, even if attempts is 0
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(_) => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Crash!
@@ -31,5 +56,45 @@ async fn main() -> anyhow::Result<()> {
         .collect();
     println!("{good:?}");
     println!("{errors:?}");
+
+    // Retrying won't help if the operation can never succeed
+    let retried = retry(3, || divide(20, 0)).await;
+    println!("{retried:?}");
     Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn succeeds_after_failures() {
+        let calls = AtomicU32::new(0);
+        let result = retry(5, || {
+            let n = calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                if n < 2 {
+                    Err(anyhow::anyhow!("failure {n}"))
+                } else {
+                    Ok(n)
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_error() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), String> = retry(3, || {
+            let n = calls.fetch_add(1, Ordering::Relaxed);
+            async move { Err(format!("failure {n}")) }
+        })
+        .await;
+        assert_eq!(result.unwrap_err(), "failure 2");
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
 }
\ No newline at end of file
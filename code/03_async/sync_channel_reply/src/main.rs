@@ -1,44 +1,116 @@
-use std::{time::Duration, sync::mpsc};
+use std::{time::Duration, sync::mpsc, ops::ControlFlow, thread::JoinHandle};
+use tokio::sync::oneshot;
 
 enum Command {
     Print(String),
+    Shutdown,
+}
+
+/// A request, along with a one-shot channel to send its reply down.
+struct Envelope<Req, Resp> {
+    request: Req,
+    reply: oneshot::Sender<Resp>,
+}
+
+/// Sends requests to a worker thread. Every request carries its own reply
+/// channel, so each caller awaits *its* answer - rather than everyone
+/// sharing one stream of replies.
+struct CommandBus<Req, Resp> {
+    tx: mpsc::Sender<Envelope<Req, Resp>>,
+    worker: JoinHandle<()>,
+}
+
+impl<Req: Send + 'static, Resp: Send + 'static> CommandBus<Req, Resp> {
+    /// Spawn a thread that runs `handler` for every request it receives.
+    /// The handler returns `ControlFlow::Break` to stop the thread.
+    fn new<F>(mut handler: F) -> Self
+    where
+        F: FnMut(Req) -> ControlFlow<(), Resp> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<Envelope<Req, Resp>>();
+        let worker = std::thread::spawn(move || {
+            while let Ok(envelope) = rx.recv() {
+                match handler(envelope.request) {
+                    // The caller may have stopped waiting - that's ok
+                    ControlFlow::Continue(response) => { let _ = envelope.reply.send(response); }
+                    // Dropping the reply channel tells the caller there's no answer
+                    ControlFlow::Break(()) => break,
+                }
+            }
+        });
+        Self { tx, worker }
+    }
+
+    /// Submit a request and wait for the reply. Returns `None` if the
+    /// worker thread has gone away.
+    async fn request(&self, request: Req) -> Option<Resp> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.tx.send(Envelope { request, reply }).ok()?;
+        reply_rx.await.ok()
+    }
+
+    /// Send a request that stops the worker, and wait for the thread to finish.
+    async fn shutdown(self, request: Req) -> std::thread::Result<()> {
+        let _ = self.request(request).await;
+        // Joining blocks, so do it somewhere that won't stall the runtime
+        let worker = self.worker;
+        tokio::task::spawn_blocking(move || worker.join())
+            .await
+            .expect("join task failed")
+    }
+}
+
+// Runs in thread-land, doing the "heavy lifting"
+fn handle_command(command: Command) -> ControlFlow<(), String> {
+    match command {
+        Command::Print(s) => ControlFlow::Continue(s),
+        Command::Shutdown => ControlFlow::Break(()),
+    }
 }
 
 #[tokio::main]
 async fn main() {
     // Spawn a command thread for "heavy lifting"
-    let (tx, rx) = mpsc::channel::<Command>();
-
-    // Spawn a TOKIO Async channel for replies
-    let (tx_reply, mut rx_reply) = tokio::sync::mpsc::channel::<String>(10);
-
-    let handle = tokio::runtime::Handle::current();
-    std::thread::spawn(move || {
-        while let Ok(command) = rx.recv() {
-            match command {
-                Command::Print(s) => {
-                    // Make our very own copy of the transmitter
-                    let tx_reply = tx_reply.clone();
-                    handle.spawn(async move {
-                        tx_reply.send(s).await.unwrap();
-                    });
-                },
-            }
-        }
-    });
+    let bus = CommandBus::new(handle_command);
 
-    // Launch a Tokio process to receive replies from thread-land
-    tokio::spawn(async move {
-        while let Some(reply) = rx_reply.recv().await {
-            println!("{reply}");
-        }
-    });
-
-    // Launch the async sender
+    // Launch the async sender, until Ctrl-C is pressed
     let mut counter = 0;
     loop {
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        tx.send(Command::Print(format!("Hello {counter}"))).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+        let reply = bus.request(Command::Print(format!("Hello {counter}"))).await.unwrap();
+        println!("{reply}");
         counter += 1;
     }
+
+    // Stop the worker thread
+    bus.shutdown(Command::Shutdown).await.unwrap();
+    println!("Worker thread stopped");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn each_request_gets_its_own_reply() {
+        let bus = CommandBus::new(handle_command);
+        let (first, second) = tokio::join!(
+            bus.request(Command::Print("first".to_string())),
+            bus.request(Command::Print("second".to_string())),
+        );
+        assert_eq!(first.as_deref(), Some("first"));
+        assert_eq!(second.as_deref(), Some("second"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_the_worker() {
+        let bus = CommandBus::new(handle_command);
+        let result = tokio::time::timeout(Duration::from_secs(5), bus.shutdown(Command::Shutdown))
+            .await
+            .expect("the worker thread didn't stop");
+        assert!(result.is_ok());
+    }
 }
@@ -0,0 +1,3 @@
+-- When each image was uploaded, in seconds since the Unix epoch.
+-- Images from before this was tracked get 0.
+ALTER TABLE images ADD COLUMN uploaded_at INTEGER NOT NULL DEFAULT 0;
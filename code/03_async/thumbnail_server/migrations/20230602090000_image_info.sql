@@ -0,0 +1,5 @@
+-- The size and format of the stored image, found when it's uploaded.
+-- They're NULL for older images, and for uploads we couldn't decode.
+ALTER TABLE images ADD COLUMN width INTEGER;
+ALTER TABLE images ADD COLUMN height INTEGER;
+ALTER TABLE images ADD COLUMN format TEXT;
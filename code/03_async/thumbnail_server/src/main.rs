@@ -1,14 +1,22 @@
+use askama::Template;
 use axum::{
-    extract::{Multipart, Path},
-    response::{Html, IntoResponse},
+    extract::{multipart::Field, DefaultBodyLimit, Multipart, Path, Query},
+    response::{Html, IntoResponse, Response},
     routing::{get, post},
-    Extension, Form, Router, http::{HeaderMap, header}, body::StreamBody, Json,
+    Extension, Form, Router, http::{header, StatusCode}, body::StreamBody, Json,
 };
 use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
 use sqlx::{Row, Pool, Sqlite, FromRow};
-use tokio::task::spawn_blocking;
-use std::net::SocketAddr;
+use tokio::{io::AsyncWriteExt, task::spawn_blocking};
+use std::{
+    collections::HashMap,
+    io::{Cursor, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    hash::{BuildHasher, Hasher},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 use tokio_util::io::ReaderStream;
 
 #[tokio::main]
@@ -26,15 +34,24 @@ async fn main() -> anyhow::Result<()> {
     // Check thumbnails
     fill_missing_thumbnails(&pool).await?;
 
+    let upload_settings = UploadSettings::from_env();
+
     // Build Axum with an "extension" to hold the database connection pool
     let app = Router::new()
         .route("/", get(index_page))
-        .route("/upload", post(uploader))
-        .route("/image/:id", get(get_image))
+        // Uploads are streamed to disk, and `max_upload_bytes` limits them
+        // instead. Every other route keeps the default limit.
+        .route("/upload", post(uploader).layer(DefaultBodyLimit::disable()))
+        .route("/image/:id", get(get_image).delete(delete_image))
+        .route("/image/:id/rethumb", post(rethumb))
         .route("/thumb/:id", get(get_thumbnail))
         .route("/images", get(list_images))
         .route("/search", post(search_images))
-        .layer(Extension(pool));
+        .route("/tag/:tag", get(tag_page))
+        .route("/api/search.zip", get(download_search))
+        .route("/api/tags", get(suggest_tags))
+        .layer(Extension(pool))
+        .layer(Extension(upload_settings));
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
@@ -53,117 +70,436 @@ async fn main() -> anyhow::Result<()> {
     format!("{count} images in the database")
 }*/
 
-async fn index_page() -> Html<String> {
-    let path = std::path::Path::new("src/index.html");
-    let content = tokio::fs::read_to_string(path).await.unwrap();
-    Html(content)
+/// The home page, with every image. Askama escapes everything we give it,
+/// so tags can't inject HTML.
+#[derive(Template)]
+#[template(path = "index.html")]
+struct IndexTemplate {
+    images: Vec<ImageRecord>,
+}
+
+/// Search results, with the search box filled in
+#[derive(Template)]
+#[template(path = "search.html")]
+struct SearchTemplate {
+    tags: String,
+    /// Which option the match box shows
+    match_all: bool,
+    images: Vec<ImageRecord>,
+}
+
+/// Every image with one particular tag
+#[derive(Template)]
+#[template(path = "tag.html")]
+struct TagTemplate {
+    tag: String,
+    images: Vec<ImageRecord>,
+}
+
+async fn all_images(pool: &Pool<Sqlite>) -> anyhow::Result<Vec<ImageRecord>> {
+    Ok(sqlx::query_as::<_, ImageRecord>("SELECT id, tags, width, height, format FROM images ORDER BY id")
+        .fetch_all(pool)
+        .await?)
+}
+
+async fn index_page(Extension(pool): Extension<sqlx::SqlitePool>) -> Result<Html<String>, AppError> {
+    let images = all_images(&pool).await?;
+    Ok(Html(IndexTemplate { images }.render()?))
+}
+
+/// A failed request, sent back as `{ "error": "..." }` with a status code
+#[derive(Debug)]
+struct AppError {
+    status: StatusCode,
+    message: String,
+}
+
+impl AppError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self { status: StatusCode::BAD_REQUEST, message: message.into() }
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self { status: StatusCode::NOT_FOUND, message: message.into() }
+    }
+}
+
+/// Missing files are a 404 and unreadable uploads a 400. Anything else is
+/// our problem, so it's logged and the client gets a 500.
+impl<E: Into<anyhow::Error>> From<E> for AppError {
+    fn from(error: E) -> Self {
+        let error = error.into();
+        let missing = error
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .any(|e| e.kind() == std::io::ErrorKind::NotFound);
+        if missing {
+            Self::not_found(error.to_string())
+        } else if error.is::<axum::extract::multipart::MultipartError>() {
+            Self::bad_request(error.to_string())
+        } else {
+            println!("Request failed: {error:?}");
+            Self { status: StatusCode::INTERNAL_SERVER_ERROR, message: "Internal server error".to_string() }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        (self.status, Json(ErrorBody { error: self.message })).into_response()
+    }
+}
+
+/// Images bigger than this (in either direction) are shrunk on upload
+const DEFAULT_MAX_DIMENSION: u32 = 2048;
+
+/// The largest image file we'll accept, unless `MAX_UPLOAD_BYTES` says otherwise
+const DEFAULT_MAX_UPLOAD_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Tags are held in memory, so they get a much smaller limit
+const MAX_TAGS_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct UploadSettings {
+    max_dimension: u32,
+    /// Keep the full-size upload as well, as `{id}_original.jpg`
+    keep_originals: bool,
+    max_upload_bytes: u64,
+}
+
+impl UploadSettings {
+    /// Read `MAX_IMAGE_DIMENSION`, `KEEP_ORIGINALS` and `MAX_UPLOAD_BYTES`
+    fn from_env() -> Self {
+        let max_dimension = std::env::var("MAX_IMAGE_DIMENSION")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(DEFAULT_MAX_DIMENSION);
+        let keep_originals = std::env::var("KEEP_ORIGINALS").map(|value| value.trim() == "1").unwrap_or(false);
+        let max_upload_bytes = std::env::var("MAX_UPLOAD_BYTES")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES);
+        Self { max_dimension, keep_originals, max_upload_bytes }
+    }
+}
+
+/// What decoding an upload told us. It describes the file we store, so a
+/// downscaled image is a JPEG of the smaller size.
+#[derive(Debug, Clone, PartialEq)]
+struct ImageInfo {
+    width: u32,
+    height: u32,
+    /// e.g. "jpeg" or "png"
+    format: String,
+}
+
+#[derive(Debug)]
+struct DecodedUpload {
+    info: ImageInfo,
+    /// A smaller copy as a JPEG, if the upload was larger than allowed
+    downscaled: Option<Vec<u8>>,
+}
+
+/// Decode an upload once, finding its size and format. If it's larger than
+/// `max_dimension`, shrink it to fit (keeping the aspect ratio).
+fn decode_upload(path: &std::path::Path, max_dimension: u32) -> anyhow::Result<DecodedUpload> {
+    let reader = image::io::Reader::open(path)?.with_guessed_format()?;
+    let format = reader.format().ok_or_else(|| anyhow::anyhow!("Unknown image format"))?;
+    let image = reader.decode()?;
+    if image.width() <= max_dimension && image.height() <= max_dimension {
+        let format = format!("{format:?}").to_lowercase();
+        let info = ImageInfo { width: image.width(), height: image.height(), format };
+        return Ok(DecodedUpload { info, downscaled: None });
+    }
+
+    let resized = image.resize(max_dimension, max_dimension, image::imageops::FilterType::Lanczos3);
+    let mut result = Vec::new();
+    resized.write_to(&mut Cursor::new(&mut result), image::ImageOutputFormat::Jpeg(90))?;
+    let info = ImageInfo { width: resized.width(), height: resized.height(), format: "jpeg".to_string() };
+    Ok(DecodedUpload { info, downscaled: Some(result) })
 }
 
 async fn uploader(
     Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<UploadSettings>,
+    multipart: Multipart,
+) -> Result<Html<String>, AppError> {
+    store_upload(&pool, std::path::Path::new("images"), settings, multipart).await?;
+
+    let path = std::path::Path::new("src/redirect.html");
+    let content = tokio::fs::read_to_string(path).await?;
+    Ok(Html(content))
+}
+
+/// An upload that's been written to disk, but not yet given its final
+/// name. If it's still there when this is dropped, it's deleted - so a
+/// failed upload doesn't leave part of a file behind.
+struct PartialUpload(PathBuf);
+
+impl PartialUpload {
+    fn new(images_dir: &std::path::Path) -> Self {
+        // Each RandomState has its own random keys, so this differs every time
+        let nonce = std::collections::hash_map::RandomState::new().build_hasher().finish();
+        Self(images_dir.join(format!("upload_{nonce:016x}.part")))
+    }
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        // Usually it's been renamed, so there's nothing to remove
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn too_large(max_bytes: u64) -> AppError {
+    AppError {
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+        message: format!("Uploads are limited to {max_bytes} bytes"),
+    }
+}
+
+/// Copy a field to `path` a chunk at a time, so only one chunk is ever in
+/// memory. Returns the number of bytes written.
+async fn stream_to_file(field: &mut Field<'_>, path: &std::path::Path, max_bytes: u64) -> Result<u64, AppError> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut written = 0;
+    while let Some(chunk) = field.chunk().await? {
+        written += chunk.len() as u64;
+        if written > max_bytes {
+            return Err(too_large(max_bytes));
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(written)
+}
+
+async fn read_text_field(field: &mut Field<'_>, max_bytes: u64) -> Result<String, AppError> {
+    let mut text = Vec::new();
+    while let Some(chunk) = field.chunk().await? {
+        if (text.len() + chunk.len()) as u64 > max_bytes {
+            return Err(too_large(max_bytes));
+        }
+        text.extend_from_slice(&chunk);
+    }
+    String::from_utf8(text).map_err(|_| AppError::bad_request("Tags must be UTF-8"))
+}
+
+/// Move a finished file to its place in `images_dir`
+async fn move_into_place(from: &std::path::Path, images_dir: &std::path::Path, filename: &str) -> anyhow::Result<()> {
+    let image_path = images_dir.join(filename);
+    if image_path.exists() {
+        // The file exists. That shouldn't happen.
+        anyhow::bail!("File already exists");
+    }
+    tokio::fs::rename(from, image_path).await?;
+    Ok(())
+}
+
+/// Save an uploaded image and its tags, returning the new image's id
+async fn store_upload(
+    pool: &Pool<Sqlite>,
+    images_dir: &std::path::Path,
+    settings: UploadSettings,
     mut multipart: Multipart,
-) -> Html<String> {
-    let mut tags = None;
-    let mut image = None;
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
-        let data = field.bytes().await.unwrap();
+) -> Result<i64, AppError> {
+    // Upload straight into the images folder, so the final rename is cheap
+    tokio::fs::create_dir_all(images_dir).await?;
 
+    let mut tags = None;
+    let mut upload = None;
+    while let Some(mut field) = multipart.next_field().await? {
+        let name = field.name().ok_or_else(|| AppError::bad_request("Every field needs a name"))?.to_string();
         match name.as_str() {
-            "tags" => tags = Some(String::from_utf8(data.to_vec()).unwrap()),
-            "image" => image = Some(data.to_vec()),
-            _ => panic!("Unknown field: {name}"),
+            "tags" => tags = Some(read_text_field(&mut field, MAX_TAGS_BYTES).await?),
+            "image" => {
+                let partial = PartialUpload::new(images_dir);
+                stream_to_file(&mut field, &partial.0, settings.max_upload_bytes).await?;
+                upload = Some(partial);
+            }
+            _ => return Err(AppError::bad_request(format!("Unknown field: {name}"))),
         }
     }
 
-    if let (Some(tags), Some(image)) = (tags, image) {
-        let new_image_id = insert_image_into_database(&pool, &tags).await.unwrap();
-        save_image(new_image_id, &image).await.unwrap();
-        spawn_blocking(move || {
-            make_thumbnail(new_image_id).unwrap();
-        });
-    } else {
-        panic!("Missing field");
+    let (Some(tags), Some(upload)) = (tags, upload) else {
+        return Err(AppError::bad_request("Missing field - send both tags and image"));
+    };
+
+    // Decoding a big photo takes a while, so keep it off the async threads.
+    // We keep what we can't decode - it just has no size or format.
+    let path = upload.0.clone();
+    let decoded = match spawn_blocking(move || decode_upload(&path, settings.max_dimension)).await? {
+        Ok(decoded) => Some(decoded),
+        Err(e) => {
+            println!("Unable to decode an upload, storing it as it is: {e:?}");
+            None
+        }
+    };
+    let (info, downscaled) = match decoded {
+        Some(DecodedUpload { info, downscaled }) => (Some(info), downscaled),
+        None => (None, None),
+    };
+
+    let new_image_id = insert_image_with_info(pool, &tags, info.as_ref()).await?;
+    match downscaled {
+        Some(smaller) => {
+            if settings.keep_originals {
+                move_into_place(&upload.0, images_dir, &format!("{new_image_id}_original.jpg")).await?;
+            }
+            let partial = PartialUpload::new(images_dir);
+            tokio::fs::write(&partial.0, &smaller).await?;
+            move_into_place(&partial.0, images_dir, &format!("{new_image_id}.jpg")).await?;
+        }
+        None => move_into_place(&upload.0, images_dir, &format!("{new_image_id}.jpg")).await?,
     }
 
-    let path = std::path::Path::new("src/redirect.html");
-    let content = tokio::fs::read_to_string(path).await.unwrap();
-    Html(content)
+    let images_dir = images_dir.to_path_buf();
+    spawn_blocking(move || {
+        if let Err(e) = make_thumbnail_in(&images_dir, new_image_id) {
+            println!("Unable to make a thumbnail for image {new_image_id}: {e:?}");
+        }
+    });
+    Ok(new_image_id)
 }
 
 async fn insert_image_into_database(pool: &Pool<Sqlite>, tags: &str) -> anyhow::Result<i64> {
-    let row = sqlx::query("INSERT INTO images (tags) VALUES (?) RETURNING id")
+    insert_image_with_info(pool, tags, None).await
+}
+
+async fn insert_image_with_info(pool: &Pool<Sqlite>, tags: &str, info: Option<&ImageInfo>) -> anyhow::Result<i64> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    insert_image_uploaded_at(pool, tags, now as i64, info).await
+}
+
+async fn insert_image_uploaded_at(
+    pool: &Pool<Sqlite>,
+    tags: &str,
+    uploaded_at: i64,
+    info: Option<&ImageInfo>,
+) -> anyhow::Result<i64> {
+    let row = sqlx::query("INSERT INTO images (tags, uploaded_at, width, height, format) VALUES (?, ?, ?, ?, ?) RETURNING id")
         .bind(tags)
+        .bind(uploaded_at)
+        .bind(info.map(|info| info.width as i64))
+        .bind(info.map(|info| info.height as i64))
+        .bind(info.map(|info| info.format.clone()))
         .fetch_one(pool)
         .await?;
 
     Ok(row.get(0))
 }
 
-async fn save_image(id: i64, bytes: &[u8]) -> anyhow::Result<()> {
-    // Check that the images folder exists and is a directory
-    // If it doesn't, create it.
-    let base_path = std::path::Path::new("images");
-    if !base_path.exists() || !base_path.is_dir() {
-        tokio::fs::create_dir_all(base_path).await?;
+/// Send an image file as a JPEG
+async fn send_jpeg(filename: &str) -> Result<Response, AppError> {
+    let attachment = format!("filename={filename}");
+    let file = tokio::fs::File::open(filename).await?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, header::HeaderValue::from_static("image/jpeg"))
+        .header(header::CONTENT_DISPOSITION, header::HeaderValue::from_str(&attachment)?)
+        .body(StreamBody::new(ReaderStream::new(file)))?
+        .into_response())
+}
+
+async fn get_image(Path(id): Path<i64>) -> Result<impl IntoResponse, AppError> {
+    send_jpeg(&format!("images/{id}.jpg"))
+        .await
+        .map_err(|e| if e.status == StatusCode::NOT_FOUND { AppError::not_found(format!("No image {id}")) } else { e })
+}
+
+/// Styles that can be applied to a thumbnail with `?filter=`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ThumbnailFilter {
+    Grayscale,
+    Sepia,
+}
+
+impl ThumbnailFilter {
+    const ALL: [ThumbnailFilter; 2] = [Self::Grayscale, Self::Sepia];
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "grayscale" => Some(Self::Grayscale),
+            "sepia" => Some(Self::Sepia),
+            _ => None,
+        }
     }
 
-    // Use "join" to create a path to the image file. Join is platform aware,
-    // it will handle the differences between Windows and Linux.
-    let image_path = base_path.join(format!("{id}.jpg"));
-    if image_path.exists() {
-        // The file exists. That shouldn't happen.
-        anyhow::bail!("File already exists");
+    /// Used in the cached file name
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Grayscale => "grayscale",
+            Self::Sepia => "sepia",
+        }
     }
 
-    // Write the image to the file
-    tokio::fs::write(image_path, bytes).await?;
-    Ok(())
+    fn apply(&self, image: image::DynamicImage) -> image::DynamicImage {
+        match self {
+            Self::Grayscale => image.grayscale(),
+            Self::Sepia => {
+                let mut rgb = image.to_rgb8();
+                for pixel in rgb.pixels_mut() {
+                    let [r, g, b] = pixel.0.map(|c| c as f32);
+                    let tone = |rw: f32, gw: f32, bw: f32| (r * rw + g * gw + b * bw).min(255.0) as u8;
+                    pixel.0 = [tone(0.393, 0.769, 0.189), tone(0.349, 0.686, 0.168), tone(0.272, 0.534, 0.131)];
+                }
+                image::DynamicImage::ImageRgb8(rgb)
+            }
+        }
+    }
 }
 
-async fn get_image(Path(id): Path<i64>) -> impl IntoResponse {
-    let filename = format!("images/{id}.jpg");
-    let attachment = format!("filename={filename}");
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        header::CONTENT_TYPE,
-        header::HeaderValue::from_static("image/jpeg"),
-    );
-    headers.insert(
-        header::CONTENT_DISPOSITION,
-        header::HeaderValue::from_str(&attachment).unwrap()
-    );
-    let file = tokio::fs::File::open(&filename).await.unwrap();
-    axum::response::Response::builder()
-        .header(header::CONTENT_TYPE, header::HeaderValue::from_static("image/jpeg"))
-        .header(header::CONTENT_DISPOSITION, header::HeaderValue::from_str(&attachment).unwrap())
-        .body(StreamBody::new(ReaderStream::new(file)))
-        .unwrap()
+/// Apply a filter to an encoded thumbnail, returning a JPEG
+fn filter_thumbnail(bytes: &[u8], filter: ThumbnailFilter) -> anyhow::Result<Vec<u8>> {
+    let filtered = filter.apply(image::load_from_memory(bytes)?);
+    let mut result = Vec::new();
+    filtered.write_to(&mut Cursor::new(&mut result), image::ImageOutputFormat::Jpeg(90))?;
+    Ok(result)
 }
 
-async fn get_thumbnail(Path(id): Path<i64>) -> impl IntoResponse {
-    let filename = format!("images/{id}_thumb.jpg");
-    let attachment = format!("filename={filename}");
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        header::CONTENT_TYPE,
-        header::HeaderValue::from_static("image/jpeg"),
-    );
-    headers.insert(
-        header::CONTENT_DISPOSITION,
-        header::HeaderValue::from_str(&attachment).unwrap()
-    );
-    let file = tokio::fs::File::open(&filename).await.unwrap();
-    axum::response::Response::builder()
-        .header(header::CONTENT_TYPE, header::HeaderValue::from_static("image/jpeg"))
-        .header(header::CONTENT_DISPOSITION, header::HeaderValue::from_str(&attachment).unwrap())
-        .body(StreamBody::new(ReaderStream::new(file)))
-        .unwrap()
+/// Filtered thumbnails are made from the regular thumbnail the first time
+/// they are asked for, and kept next to it.
+fn make_filtered_thumbnail(id: i64, filter: ThumbnailFilter) -> anyhow::Result<String> {
+    let filtered_path = format!("images/{id}_thumb_{}.jpg", filter.name());
+    if !std::path::Path::new(&filtered_path).exists() {
+        let thumbnail = std::fs::read(format!("images/{id}_thumb.jpg"))?;
+        std::fs::write(&filtered_path, filter_thumbnail(&thumbnail, filter)?)?;
+    }
+    Ok(filtered_path)
+}
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    filter: Option<String>,
+}
+
+async fn get_thumbnail(Path(id): Path<i64>, Query(query): Query<ThumbnailQuery>) -> Result<impl IntoResponse, AppError> {
+    let filename = match query.filter {
+        None => format!("images/{id}_thumb.jpg"),
+        Some(name) => {
+            let filter = ThumbnailFilter::parse(&name)
+                .ok_or_else(|| AppError::bad_request(format!("Unknown filter: {name}")))?;
+            spawn_blocking(move || make_filtered_thumbnail(id, filter)).await??
+        }
+    };
+    send_jpeg(&filename)
+        .await
+        .map_err(|e| if e.status == StatusCode::NOT_FOUND { AppError::not_found(format!("No thumbnail for image {id}")) } else { e })
 }
 
 fn make_thumbnail(id: i64) -> anyhow::Result<()> {
-    let image_path = format!("images/{id}.jpg");
-    let thumbnail_path = format!("images/{id}_thumb.jpg");
+    make_thumbnail_in(std::path::Path::new("images"), id)
+}
+
+fn make_thumbnail_in(images_dir: &std::path::Path, id: i64) -> anyhow::Result<()> {
+    let image_path = images_dir.join(format!("{id}.jpg"));
+    let thumbnail_path = images_dir.join(format!("{id}_thumb.jpg"));
     let image_bytes: Vec<u8> = std::fs::read(image_path)?;
     let image = if let Ok(format) = image::guess_format(&image_bytes) {
         image::load_from_memory_with_format(&image_bytes, format)?
@@ -175,6 +511,78 @@ fn make_thumbnail(id: i64) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Rebuild an image's thumbnail. The filtered versions were made from the
+/// old one, so they're removed and will be made again when next asked for.
+fn regenerate_thumbnails(images_dir: &std::path::Path, id: i64) -> anyhow::Result<()> {
+    make_thumbnail_in(images_dir, id)?;
+    for filter in ThumbnailFilter::ALL {
+        let filtered = images_dir.join(format!("{id}_thumb_{}.jpg", filter.name()));
+        if filtered.exists() {
+            std::fs::remove_file(filtered)?;
+        }
+    }
+    Ok(())
+}
+
+async fn rethumb(Extension(pool): Extension<sqlx::SqlitePool>, Path(id): Path<i64>) -> Result<StatusCode, AppError> {
+    let known: Option<i64> = sqlx::query_scalar("SELECT id FROM images WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?;
+    if known.is_none() {
+        return Err(AppError::not_found(format!("No image {id}")));
+    }
+
+    spawn_blocking(move || regenerate_thumbnails(std::path::Path::new("images"), id)).await??;
+    Ok(StatusCode::OK)
+}
+
+/// Every file an image can have: the image, its original, and thumbnails
+fn image_files(images_dir: &std::path::Path, id: i64) -> Vec<PathBuf> {
+    let mut files = vec![
+        images_dir.join(format!("{id}.jpg")),
+        images_dir.join(format!("{id}_original.jpg")),
+        images_dir.join(format!("{id}_thumb.jpg")),
+    ];
+    for filter in ThumbnailFilter::ALL {
+        files.push(images_dir.join(format!("{id}_thumb_{}.jpg", filter.name())));
+    }
+    files
+}
+
+/// Remove an image's row and files. Returns false if there was no such
+/// image. Files that are already gone are fine - we wanted them gone.
+async fn delete_image_in(pool: &Pool<Sqlite>, images_dir: &std::path::Path, id: i64) -> anyhow::Result<bool> {
+    let result = sqlx::query("DELETE FROM images WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    for file in image_files(images_dir, id) {
+        match tokio::fs::remove_file(&file).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(true)
+}
+
+#[derive(Serialize, Debug)]
+struct Deleted {
+    deleted: bool,
+}
+
+async fn delete_image(Extension(pool): Extension<sqlx::SqlitePool>, Path(id): Path<i64>) -> Result<Json<Deleted>, AppError> {
+    if delete_image_in(&pool, std::path::Path::new("images"), id).await? {
+        Ok(Json(Deleted { deleted: true }))
+    } else {
+        Err(AppError::not_found(format!("No image {id}")))
+    }
+}
+
 async fn fill_missing_thumbnails(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
     let mut rows = sqlx::query("SELECT id FROM images")
         .fetch(pool);
@@ -192,42 +600,665 @@ async fn fill_missing_thumbnails(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
     Ok(())
 }
 
-#[derive(Deserialize, Serialize, FromRow, Debug)]
+#[derive(Deserialize, Serialize, FromRow, Debug, Clone, Default)]
 struct ImageRecord {
     id: i64,
     tags: String,
+    /// The size and format are `None` if we couldn't decode the upload
+    width: Option<i64>,
+    height: Option<i64>,
+    format: Option<String>,
 }
 
-async fn list_images(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<ImageRecord>> {
-    sqlx::query_as::<_, ImageRecord>("SELECT id, tags FROM images ORDER BY id")
-        .fetch_all(&pool)
-        .await
-        .unwrap()
-        .into()
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ImageOrder {
+    /// Newest uploads first
+    Recent,
+    Oldest,
+    #[default]
+    Id,
+}
+
+#[derive(Deserialize, Default)]
+struct ListQuery {
+    #[serde(default)]
+    sort: ImageOrder,
+    /// Only images with exactly this tag
+    tag: Option<String>,
+}
+
+async fn query_images(pool: &Pool<Sqlite>, query: &ListQuery) -> anyhow::Result<Vec<ImageRecord>> {
+    let sql = match query.sort {
+        ImageOrder::Recent => "SELECT id, tags, width, height, format FROM images ORDER BY uploaded_at DESC, id DESC",
+        ImageOrder::Oldest => "SELECT id, tags, width, height, format FROM images ORDER BY uploaded_at, id",
+        ImageOrder::Id => "SELECT id, tags, width, height, format FROM images ORDER BY id",
+    };
+    let mut images = sqlx::query_as::<_, ImageRecord>(sql).fetch_all(pool).await?;
+
+    // Tags are a comma-separated list, so match whole tags here rather than with LIKE
+    if let Some(tag) = &query.tag {
+        let tag = tag.trim().to_lowercase();
+        images.retain(|image| split_tags(&image.tags).any(|t| t == tag));
+    }
+    Ok(images)
+}
+
+/// Unlike the search, this only matches whole tags: "cat" doesn't find
+/// "category" or "black cat".
+async fn tag_page(Extension(pool): Extension<sqlx::SqlitePool>, Path(tag): Path<String>) -> Result<Html<String>, AppError> {
+    let query = ListQuery { sort: ImageOrder::Recent, tag: Some(tag.clone()) };
+    let images = query_images(&pool, &query).await?;
+    Ok(Html(TagTemplate { tag, images }.render()?))
+}
+
+async fn list_images(Extension(pool): Extension<sqlx::SqlitePool>, Query(query): Query<ListQuery>) -> Result<Json<Vec<ImageRecord>>, AppError> {
+    Ok(query_images(&pool, &query).await?.into())
+}
+
+/// With several search terms, must an image match all of them or just one?
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MatchMode {
+    #[default]
+    All,
+    Any,
 }
 
 #[derive(Deserialize)]
 struct Search {
-    tags: String
+    tags: String,
+    #[serde(default, rename = "match")]
+    mode: MatchMode,
+}
+
+/// Make `%` and `_` (and the escape character itself) match literally in a
+/// `LIKE ... ESCAPE '\'` pattern
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// Search terms are separated by commas, and each one matches any part of
+/// an image's tags. No terms at all matches everything.
+async fn find_images(pool: &Pool<Sqlite>, tags: &str, mode: MatchMode) -> anyhow::Result<Vec<ImageRecord>> {
+    let patterns: Vec<String> = split_tags(tags).map(|tag| format!("%{}%", escape_like(&tag))).collect();
+    let joiner = match mode {
+        MatchMode::All => " AND ",
+        MatchMode::Any => " OR ",
+    };
+    let conditions = vec![r"tags LIKE ? ESCAPE '\'"; patterns.len()].join(joiner);
+    let sql = if conditions.is_empty() {
+        "SELECT id, tags, width, height, format FROM images ORDER BY id".to_string()
+    } else {
+        format!("SELECT id, tags, width, height, format FROM images WHERE {conditions} ORDER BY id")
+    };
+
+    let mut query = sqlx::query_as::<_, ImageRecord>(&sql);
+    for pattern in patterns {
+        query = query.bind(pattern);
+    }
+    Ok(query.fetch_all(pool).await?)
+}
+
+async fn search_images(Extension(pool): Extension<sqlx::SqlitePool>, Form(form): Form<Search>) -> Result<Html<String>, AppError> {
+    let images = find_images(&pool, &form.tags, form.mode).await?;
+    let match_all = form.mode == MatchMode::All;
+    Ok(Html(SearchTemplate { tags: form.tags, match_all, images }.render()?))
 }
 
-async fn search_images(Extension(pool): Extension<sqlx::SqlitePool>, Form(form): Form<Search>) -> Html<String> {
-    let tag = format!("%{}%", form.tags);
+/// A `Write + Seek` target for `ZipWriter` that lets us send the finished
+/// part of the archive while the rest is still being written. The zip
+/// writer only seeks back into the entry it is working on, so everything
+/// before the start of that entry can be handed over with `take`.
+#[derive(Clone, Default)]
+struct ChunkWriter(Arc<Mutex<ChunkBuffer>>);
 
-    let rows = sqlx::query_as::<_, ImageRecord>("SELECT id, tags FROM images WHERE tags LIKE ? ORDER BY id")
-        .bind(tag)
+#[derive(Default)]
+struct ChunkBuffer {
+    bytes: Vec<u8>,
+    /// Archive offset of `bytes[0]`
+    base: u64,
+    position: u64,
+}
+
+impl ChunkWriter {
+    /// Remove and return everything before `offset`
+    fn take(&self, offset: u64) -> Vec<u8> {
+        let mut buffer = self.0.lock().unwrap();
+        let count = (offset - buffer.base) as usize;
+        buffer.base = offset;
+        buffer.bytes.drain(..count).collect()
+    }
+
+    fn position(&self) -> u64 {
+        self.0.lock().unwrap().position
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let mut buffer = self.0.lock().unwrap();
+        let start = (buffer.position - buffer.base) as usize;
+        let end = start + data.len();
+        if end > buffer.bytes.len() {
+            buffer.bytes.resize(end, 0);
+        }
+        buffer.bytes[start..end].copy_from_slice(data);
+        buffer.position += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ChunkWriter {
+    fn seek(&mut self, to: SeekFrom) -> std::io::Result<u64> {
+        let mut buffer = self.0.lock().unwrap();
+        let end = buffer.base + buffer.bytes.len() as u64;
+        let position = match to {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => buffer.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => end.checked_add_signed(delta),
+        };
+        match position {
+            Some(position) if position >= buffer.base && position <= end => {
+                buffer.position = position;
+                Ok(position)
+            }
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek outside of the unsent data")),
+        }
+    }
+}
+
+/// The name an image gets inside a zip file, e.g. "12_cat-food.jpg"
+fn zip_entry_name(image: &ImageRecord) -> String {
+    let tags: Vec<String> = split_tags(&image.tags)
+        .map(|tag| tag.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect())
+        .collect();
+    if tags.is_empty() {
+        format!("{}.jpg", image.id)
+    } else {
+        format!("{}_{}.jpg", image.id, tags.join("-"))
+    }
+}
+
+/// The full size upload if we kept it, otherwise the stored image
+fn original_image_path(images_dir: &std::path::Path, id: i64) -> PathBuf {
+    let original = images_dir.join(format!("{id}_original.jpg"));
+    if original.exists() {
+        original
+    } else {
+        images_dir.join(format!("{id}.jpg"))
+    }
+}
+
+/// Write a zip of `images`, sending each finished piece to `tx` as soon as
+/// it's ready. Only one image is held in memory at a time.
+fn zip_images(
+    images: &[ImageRecord],
+    images_dir: &std::path::Path,
+    tx: &tokio::sync::mpsc::Sender<std::io::Result<Vec<u8>>>,
+) -> anyhow::Result<()> {
+    let chunks = ChunkWriter::default();
+    let mut zip = zip::ZipWriter::new(chunks.clone());
+    // JPEGs are already compressed
+    let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for image in images {
+        // Starting an entry finishes the previous one
+        let entry_start = chunks.position();
+        zip.start_file(zip_entry_name(image), options)?;
+        tx.blocking_send(Ok(chunks.take(entry_start)))?;
+
+        let mut file = std::fs::File::open(original_image_path(images_dir, image.id))?;
+        std::io::copy(&mut file, &mut zip)?;
+    }
+    zip.finish()?;
+    tx.blocking_send(Ok(chunks.take(chunks.position())))?;
+    Ok(())
+}
+
+async fn download_search(Extension(pool): Extension<sqlx::SqlitePool>, Query(search): Query<Search>) -> Result<impl IntoResponse, AppError> {
+    let images = find_images(&pool, &search.tags, search.mode).await?;
+
+    // Build the archive on a blocking thread, and stream the pieces out
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    spawn_blocking(move || {
+        if let Err(e) = zip_images(&images, std::path::Path::new("images"), &tx) {
+            let _ = tx.blocking_send(Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())));
+        }
+    });
+    let body = futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|chunk| (chunk, rx)) });
+
+    Ok(axum::response::Response::builder()
+        .header(header::CONTENT_TYPE, header::HeaderValue::from_static("application/zip"))
+        .header(header::CONTENT_DISPOSITION, header::HeaderValue::from_static("attachment; filename=search.zip"))
+        .body(StreamBody::new(body))?)
+}
+
+/// Tags are stored as a comma-separated list
+fn split_tags(tags: &str) -> impl Iterator<Item = String> + '_ {
+    tags.split(',')
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+}
+
+const MAX_TAG_SUGGESTIONS: usize = 10;
+
+#[derive(Deserialize)]
+struct TagQuery {
+    prefix: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn suggest_tags(Extension(pool): Extension<sqlx::SqlitePool>, Query(query): Query<TagQuery>) -> Result<Json<Vec<String>>, AppError> {
+    let rows: Vec<String> = sqlx::query_scalar("SELECT tags FROM images")
         .fetch_all(&pool)
+        .await?;
+
+    // Count how often each tag is used
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for tags in rows.iter() {
+        for tag in split_tags(tags) {
+            *counts.entry(tag).or_default() += 1;
+        }
+    }
+
+    // An empty prefix matches everything, giving the most common tags
+    let prefix = query.prefix.unwrap_or_default().trim().to_lowercase();
+    let limit = query.limit.unwrap_or(MAX_TAG_SUGGESTIONS).min(MAX_TAG_SUGGESTIONS);
+    let mut matches: Vec<(String, usize)> = counts
+        .into_iter()
+        .filter(|(tag, _)| tag.starts_with(&prefix))
+        .collect();
+
+    // Most used first, then alphabetically
+    matches.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(Json(matches.into_iter().take(limit).map(|(tag, _)| tag).collect()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An empty in-memory database, with the migrations applied
+    async fn test_pool() -> sqlx::SqlitePool {
+        // Every connection to ":memory:" gets its own database, so only use one
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+        pool
+    }
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let image = image::DynamicImage::new_rgb8(width, height);
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Jpeg(90)).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn large_uploads_are_downscaled() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("upload.part");
+        std::fs::write(&path, jpeg(3000, 1500)).unwrap();
+        let decoded = decode_upload(&path, 1000).unwrap();
+        let stored = image::load_from_memory(&decoded.downscaled.unwrap()).unwrap();
+        assert_eq!((stored.width(), stored.height()), (1000, 500));
+        assert_eq!(decoded.info, ImageInfo { width: 1000, height: 500, format: "jpeg".to_string() });
+
+        // Small enough already - leave it alone
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(800, 600)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        std::fs::write(&path, png).unwrap();
+        let decoded = decode_upload(&path, 1000).unwrap();
+        assert!(decoded.downscaled.is_none());
+        assert_eq!(decoded.info, ImageInfo { width: 800, height: 600, format: "png".to_string() });
+
+        std::fs::write(&path, b"not a picture").unwrap();
+        assert!(decode_upload(&path, 1000).is_err());
+    }
+
+    /// A multipart form, as a browser would send it
+    async fn multipart(fields: &[(&str, &[u8])]) -> Multipart {
+        use axum::extract::FromRequest;
+        let mut body = Vec::new();
+        for (name, data) in fields {
+            body.extend_from_slice(format!("--BOUNDARY\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n").as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--BOUNDARY--\r\n");
+        let request = axum::http::Request::builder()
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        Multipart::from_request(request, &()).await.unwrap()
+    }
+
+    fn file_names(directory: &std::path::Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn uploads_are_streamed_to_disk() {
+        let pool = test_pool().await;
+        let directory = tempfile::tempdir().unwrap();
+        let settings = UploadSettings { max_dimension: 1000, keep_originals: true, max_upload_bytes: 1024 * 1024 };
+
+        let small = jpeg(100, 50);
+        let form = multipart(&[("tags", b"cat"), ("image", &small)]).await;
+        let id = store_upload(&pool, directory.path(), settings, form).await.unwrap();
+        assert_eq!(std::fs::read(directory.path().join(format!("{id}.jpg"))).unwrap(), small);
+
+        // Too big, so the upload is kept and a smaller copy stored
+        let form = multipart(&[("image", &jpeg(2000, 1000)), ("tags", b"dog")]).await;
+        let id = store_upload(&pool, directory.path(), settings, form).await.unwrap();
+        let stored = image::open(directory.path().join(format!("{id}.jpg"))).unwrap();
+        assert_eq!(stored.width(), 1000);
+        assert_eq!(image::open(directory.path().join(format!("{id}_original.jpg"))).unwrap().width(), 2000);
+
+        // We can't read it, but it's kept anyway
+        let form = multipart(&[("tags", b"mystery"), ("image", b"not a picture")]).await;
+        let id = store_upload(&pool, directory.path(), settings, form).await.unwrap();
+        assert_eq!(std::fs::read(directory.path().join(format!("{id}.jpg"))).unwrap(), b"not a picture");
+
+        assert!(file_names(directory.path()).iter().all(|name| !name.ends_with(".part")));
+        let info: Vec<_> = all_images(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|image| (image.width, image.height, image.format))
+            .collect();
+        let jpeg_info = |width, height| (Some(width), Some(height), Some("jpeg".to_string()));
+        assert_eq!(info, vec![jpeg_info(100, 50), jpeg_info(1000, 500), (None, None, None)]);
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_leave_nothing_behind() {
+        let pool = test_pool().await;
+        let directory = tempfile::tempdir().unwrap();
+        let settings = UploadSettings { max_dimension: 1000, keep_originals: false, max_upload_bytes: 1024 * 1024 };
+
+        let tiny_limit = UploadSettings { max_upload_bytes: 100, ..settings };
+        let form = multipart(&[("tags", b"cat"), ("image", &jpeg(500, 500))]).await;
+        let error = store_upload(&pool, directory.path(), tiny_limit, form).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // The image was fine, but there were no tags
+        let form = multipart(&[("image", &jpeg(10, 10))]).await;
+        let error = store_upload(&pool, directory.path(), settings, form).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+
+        assert!(file_names(directory.path()).is_empty());
+        assert!(all_images(&pool).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn grayscale_thumbnails() {
+        let mut colorful = image::RgbImage::new(100, 100);
+        for (x, y, pixel) in colorful.enumerate_pixels_mut() {
+            *pixel = image::Rgb([x as u8 * 2, y as u8 * 2, 200]);
+        }
+        let mut bytes = Vec::new();
+        image::DynamicImage::ImageRgb8(colorful)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Jpeg(90))
+            .unwrap();
+
+        let filtered = filter_thumbnail(&bytes, ThumbnailFilter::Grayscale).unwrap();
+        let filtered = image::load_from_memory(&filtered).unwrap();
+        assert_eq!((filtered.width(), filtered.height()), (100, 100));
+        assert_eq!(filtered.color(), image::ColorType::L8);
+    }
+
+    #[tokio::test]
+    async fn unknown_filters_are_rejected() {
+        let query = ThumbnailQuery { filter: Some("blurry".to_string()) };
+        let Err(error) = get_thumbnail(Path(1), Query(query)).await else {
+            panic!("Expected an error");
+        };
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(ThumbnailFilter::parse("sepia"), Some(ThumbnailFilter::Sepia));
+    }
+
+    #[tokio::test]
+    async fn missing_images_are_404() {
+        let Err(error) = get_image(Path(99999)).await else {
+            panic!("Expected an error");
+        };
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        assert_eq!(error.message, "No image 99999");
+
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let query = ThumbnailQuery { filter: None };
+        let Err(error) = get_thumbnail(Path(99999), Query(query)).await else {
+            panic!("Expected an error");
+        };
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        // There's no thumbnail to filter, either
+        let query = ThumbnailQuery { filter: Some("sepia".to_string()) };
+        let Err(error) = get_thumbnail(Path(99999), Query(query)).await else {
+            panic!("Expected an error");
+        };
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "gone");
+        assert_eq!(AppError::from(missing).status, StatusCode::NOT_FOUND);
+        let context = anyhow::Error::new(std::io::Error::new(std::io::ErrorKind::NotFound, "gone")).context("reading");
+        assert_eq!(AppError::from(context).status, StatusCode::NOT_FOUND);
+
+        let error = AppError::from(anyhow::anyhow!("database on fire"));
+        assert_eq!(error.status, StatusCode::INTERNAL_SERVER_ERROR);
+        // Don't tell the client about our internals
+        assert!(!error.message.contains("fire"));
+    }
+
+    #[test]
+    fn search_results_as_zip() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("1.jpg"), jpeg(10, 10)).unwrap();
+        std::fs::write(directory.path().join("2.jpg"), jpeg(20, 20)).unwrap();
+        std::fs::write(directory.path().join("2_original.jpg"), jpeg(40, 40)).unwrap();
+        let images = vec![
+            ImageRecord { id: 1, tags: "cat, food".to_string(), ..Default::default() },
+            ImageRecord { id: 2, tags: "Black Cat".to_string(), ..Default::default() },
+        ];
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        zip_images(&images, directory.path(), &tx).unwrap();
+        drop(tx);
+        let mut chunks = Vec::new();
+        while let Ok(chunk) = rx.try_recv() {
+            chunks.push(chunk.unwrap());
+        }
+        // Sent a piece at a time, not all at the end
+        assert!(chunks.len() > 1);
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(chunks.concat())).unwrap();
+        assert_eq!(archive.len(), 2);
+        assert_eq!(archive.by_index(0).unwrap().name(), "1_cat-food.jpg");
+        let mut original = archive.by_index(1).unwrap();
+        assert_eq!(original.name(), "2_black_cat.jpg");
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut original, &mut bytes).unwrap();
+        assert_eq!(image::load_from_memory(&bytes).unwrap().width(), 40);
+    }
+
+    #[test]
+    fn tags_are_escaped() {
+        let tags = "<script>alert(\"hi\")</script> & friends".to_string();
+        let images = vec![ImageRecord { id: 1, tags: tags.clone(), ..Default::default() }];
+        let pages = [
+            IndexTemplate { images: images.clone() }.render().unwrap(),
+            SearchTemplate { tags, match_all: true, images }.render().unwrap(),
+        ];
+        for page in pages {
+            assert!(!page.contains("<script>"));
+            assert!(page.contains("&lt;script&gt;"));
+            assert!(page.contains("&amp; friends"));
+            assert!(page.contains("/thumb/1"));
+        }
+    }
+
+    #[tokio::test]
+    async fn sorted_and_filtered_lists() {
+        let pool = test_pool().await;
+        // Ids go up, but the upload times don't
+        for (tags, uploaded_at) in [("cat", 200), ("dog, cat", 100), ("Cat, fox", 300), ("category", 150)] {
+            insert_image_uploaded_at(&pool, tags, uploaded_at, None).await.unwrap();
+        }
+
+        let ids = |images: Vec<ImageRecord>| images.iter().map(|image| image.id).collect::<Vec<_>>();
+        let list = |sort, tag: Option<&str>| {
+            let pool = pool.clone();
+            let query = ListQuery { sort, tag: tag.map(|tag| tag.to_string()) };
+            async move { ids(query_images(&pool, &query).await.unwrap()) }
+        };
+
+        assert_eq!(list(ImageOrder::Id, None).await, vec![1, 2, 3, 4]);
+        assert_eq!(list(ImageOrder::Recent, None).await, vec![3, 1, 4, 2]);
+        assert_eq!(list(ImageOrder::Oldest, None).await, vec![2, 4, 1, 3]);
+        // Whole tags only - "category" isn't a cat
+        assert_eq!(list(ImageOrder::Recent, Some("CAT")).await, vec![3, 1, 2]);
+        assert_eq!(list(ImageOrder::Id, Some("bird")).await, Vec::<i64>::new());
+
+        let uri = "/images?sort=recent&tag=fox".parse().unwrap();
+        let Query(query) = Query::<ListQuery>::try_from_uri(&uri).unwrap();
+        assert_eq!((query.sort, query.tag.as_deref()), (ImageOrder::Recent, Some("fox")));
+        let Query(query) = Query::<ListQuery>::try_from_uri(&"/images".parse().unwrap()).unwrap();
+        assert_eq!(query.sort, ImageOrder::Id);
+        assert!(Query::<ListQuery>::try_from_uri(&"/images?sort=sideways".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn missing_thumbnails_are_rebuilt() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("1.jpg"), jpeg(400, 200)).unwrap();
+        let thumbnail = directory.path().join("1_thumb.jpg");
+        let grayscale = directory.path().join("1_thumb_grayscale.jpg");
+        std::fs::write(&grayscale, b"stale").unwrap();
+
+        regenerate_thumbnails(directory.path(), 1).unwrap();
+        let rebuilt = image::open(&thumbnail).unwrap();
+        assert_eq!((rebuilt.width(), rebuilt.height()), (100, 50));
+        assert!(!grayscale.exists());
+
+        std::fs::remove_file(&thumbnail).unwrap();
+        regenerate_thumbnails(directory.path(), 1).unwrap();
+        assert!(thumbnail.exists());
+    }
+
+    #[tokio::test]
+    async fn rethumb_unknown_image() {
+        let pool = test_pool().await;
+        let error = rethumb(Extension(pool), Path(42)).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn deleting_an_image() {
+        let pool = test_pool().await;
+        let directory = tempfile::tempdir().unwrap();
+        let id = insert_image_into_database(&pool, "cat").await.unwrap();
+        std::fs::write(directory.path().join(format!("{id}.jpg")), jpeg(200, 200)).unwrap();
+        make_thumbnail_in(directory.path(), id).unwrap();
+
+        assert!(delete_image_in(&pool, directory.path(), id).await.unwrap());
+        assert!(image_files(directory.path(), id).iter().all(|file| !file.exists()));
+        assert!(all_images(&pool).await.unwrap().is_empty());
+
+        // Already gone
+        assert!(!delete_image_in(&pool, directory.path(), id).await.unwrap());
+        let error = delete_image(Extension(pool.clone()), Path(id)).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+
+        // A row without files can still be deleted
+        let id = insert_image_into_database(&pool, "dog").await.unwrap();
+        assert!(delete_image_in(&pool, directory.path(), id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn tag_pages_match_whole_tags() {
+        let pool = test_pool().await;
+        for tags in ["cat", "cats", "black cat", "dog, Cat", "category, fox"] {
+            insert_image_into_database(&pool, tags).await.unwrap();
+        }
+
+        let Html(page) = tag_page(Extension(pool.clone()), Path("cat".to_string())).await.unwrap();
+        let linked: Vec<i64> = (1..=5).filter(|id| page.contains(&format!("/thumb/{id}\""))).collect();
+        assert_eq!(linked, vec![1, 4]);
+
+        // The fuzzy search finds them all
+        assert_eq!(find_images(&pool, "cat", MatchMode::All).await.unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn searching_several_tags() {
+        let pool = test_pool().await;
+        for tags in ["cat, dog", "cat", "dog", "fox", "dog, black cat", "100%_wild"] {
+            insert_image_into_database(&pool, tags).await.unwrap();
+        }
+        let search = |tags: &'static str, mode| {
+            let pool = pool.clone();
+            async move { find_images(&pool, tags, mode).await.unwrap().iter().map(|image| image.id).collect::<Vec<_>>() }
+        };
+
+        assert_eq!(search("cat,dog", MatchMode::All).await, vec![1, 5]);
+        assert_eq!(search("cat, dog", MatchMode::Any).await, vec![1, 2, 3, 5]);
+        assert_eq!(search(" , ", MatchMode::All).await, vec![1, 2, 3, 4, 5, 6]);
+
+        // Wildcards are only wildcards when we add them
+        assert_eq!(search("%", MatchMode::Any).await, vec![6]);
+        assert_eq!(search("0%_w", MatchMode::Any).await, vec![6]);
+        assert_eq!(search("c_t", MatchMode::Any).await, Vec::<i64>::new());
+
+        use axum::extract::FromRequest;
+        let Form(form) = Form::<Search>::from_request(
+            axum::http::Request::builder()
+                .method("POST")
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(axum::body::Body::from("tags=cat%2Cdog&match=any"))
+                .unwrap(),
+            &(),
+        )
         .await
         .unwrap();
-
-    let mut results = String::new();
-    for row in rows {
-        results.push_str(&format!("<a href=\"/image/{}\"><img src='/thumb/{}' /></a><br />", row.id, row.id));
+        assert_eq!((form.tags.as_str(), form.mode), ("cat,dog", MatchMode::Any));
+        let Query(search) = Query::<Search>::try_from_uri(&"/api/search.zip?tags=cat".parse().unwrap()).unwrap();
+        assert_eq!(search.mode, MatchMode::All);
     }
 
-    let path = std::path::Path::new("src/search.html");
-    let mut content = tokio::fs::read_to_string(path).await.unwrap();
-    content = content.replace("{results}", &results);
+    #[tokio::test]
+    async fn tag_suggestions() {
+        let pool = test_pool().await;
+        for tags in ["cat, food", "cat, fox", "dog, fox", "Fox"] {
+            insert_image_into_database(&pool, tags).await.unwrap();
+        }
+
+        let query = TagQuery { prefix: Some("fo".to_string()), limit: None };
+        let Json(tags) = suggest_tags(Extension(pool.clone()), Query(query)).await.unwrap();
+        assert_eq!(tags, vec!["fox", "food"]);
 
-    Html(content)
-}
\ No newline at end of file
+        // No prefix gives the most common tags
+        let query = TagQuery { prefix: None, limit: Some(2) };
+        let Json(tags) = suggest_tags(Extension(pool), Query(query)).await.unwrap();
+        assert_eq!(tags, vec!["fox", "cat"]);
+    }
+}
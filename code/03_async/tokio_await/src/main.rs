@@ -1,4 +1,5 @@
-use tokio::task::JoinSet;
+use std::{future::Future, time::Duration};
+use tokio::{task::JoinSet, time::error::Elapsed};
 
 async fn double(n: i32) -> i32 {
     n * 4
@@ -33,6 +34,16 @@ async fn ticker() {
     }
 }
 
+/// Give up on `fut` if it hasn't finished within `dur`
+async fn with_timeout<F: Future>(dur: Duration, fut: F) -> Result<F::Output, Elapsed> {
+    tokio::time::timeout(dur, fut).await
+}
+
+async fn slow_double(n: i32, delay: Duration) -> i32 {
+    tokio::time::sleep(delay).await;
+    double(n).await
+}
+
 //#[tokio:main]
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
@@ -40,5 +51,30 @@ async fn main() {
         tokio::spawn(hello()), 
         tokio::spawn(ticker()),
     );
+
+    // One task finishes in time, the other doesn't
+    let deadline = Duration::from_millis(500);
+    let fast = with_timeout(deadline, slow_double(2, Duration::from_millis(10))).await;
+    println!("Fast: {fast:?}");
+    let slow = with_timeout(deadline, slow_double(2, Duration::from_secs(2))).await;
+    println!("Slow: {slow:?}");
+
     println!("Finished");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn completes_before_timeout() {
+        let result = with_timeout(Duration::from_secs(1), slow_double(2, Duration::from_millis(10))).await;
+        assert_eq!(result.unwrap(), 8);
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let result = with_timeout(Duration::from_millis(10), slow_double(2, Duration::from_secs(5))).await;
+        assert!(result.is_err());
+    }
+}
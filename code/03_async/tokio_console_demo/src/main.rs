@@ -1,43 +1,114 @@
 use std::net::SocketAddr;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     spawn,
+    task::JoinSet,
 };
+use tokio_util::sync::CancellationToken;
+
+/// Refuse to allocate buffers for absurdly large messages
+const MAX_FRAME_SIZE: u32 = 1024 * 1024;
+/// How long to let open connections finish when shutting down
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Write a message as a big-endian `u32` length, followed by the payload.
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(payload).await
+}
+
+/// Read one length-prefixed message. Returns `None` if the other end
+/// closed the connection before starting a new message.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let length = match reader.read_u32().await {
+        Ok(length) => length,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if length > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {length} bytes is too large"),
+        ));
+    }
+
+    // read_exact keeps reading until the buffer is full, so partial reads are handled
+    let mut buf = vec![0; length as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
 
 #[tracing::instrument(name="echo", fields(address=%address))]
 async fn echo_stream(mut socket: TcpStream, address: SocketAddr) {
     tracing::info!("New Connection from {:?}", address);
-    let mut buf = vec![0; 1024];
     loop {
-        let n = socket
-            .read(&mut buf)
-            .await
-            .expect("failed to read data from socket");
-        tracing::info!("Received {} bytes from {address:?}", n);
+        // Clients come and go - a broken connection only ends this task
+        let message = match read_frame(&mut socket).await {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                tracing::warn!("No bytes received from {address:?}. Closing connection.");
+                return;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to read from {address:?}: {e}. Closing connection.");
+                return;
+            }
+        };
+        tracing::info!("Received {} bytes from {address:?}", message.len());
 
-        if n == 0 {
-            tracing::warn!("No bytes received from {address:?}. Closing connection.");
+        if let Err(e) = write_frame(&mut socket, &message).await {
+            tracing::warn!("Failed to write to {address:?}: {e}. Closing connection.");
             return;
         }
-
-        socket
-            .write_all(&buf[0..n])
-            .await
-            .expect("failed to write data to socket");
     }
 }
 
-#[tracing::instrument(name = "listener")]
-async fn listen() -> anyhow::Result<()> {
+#[tracing::instrument(name = "listener", skip(shutdown))]
+async fn listen(shutdown: CancellationToken) -> anyhow::Result<()> {
     // Listen for connections
     let listener = TcpListener::bind("127.0.0.1:8123").await?;
     tracing::info!("Listening on port 8123");
 
+    accept_loop(listener, shutdown).await
+}
+
+async fn accept_loop(listener: TcpListener, shutdown: CancellationToken) -> anyhow::Result<()> {
+    // Keep track of the connections, so we can wait for them on shutdown
+    let mut connections = JoinSet::new();
+
     loop {
-        let (socket, address) = listener.accept().await?;
-        spawn(echo_stream(socket, address));
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => {
+                // Failing to accept one connection (say, out of file
+                // handles) shouldn't take the others down with it
+                let (socket, address) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept a connection: {e}");
+                        continue;
+                    }
+                };
+                connections.spawn(echo_stream(socket, address));
+            }
+            // Tidy up connections that have finished
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    // Stop accepting new connections, and let the in-flight ones finish -
+    // but a client that never hangs up mustn't keep us running forever
+    tracing::info!("Shutting down: draining {} connections", connections.len());
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!("Gave up waiting for {} connections", connections.len());
+        connections.abort_all();
     }
+    Ok(())
 }
 
 #[tracing::instrument(name = "client")]
@@ -47,12 +118,13 @@ async fn client() -> anyhow::Result<()> {
 
     for _ in 0..10 {
         // Send "Hello World"
-        stream.write_all(b"Hello World!").await?;
+        write_frame(&mut stream, b"Hello World!").await?;
 
         // Read the response
-        let mut buf = vec![0; 1024];
-        let bytes_read = stream.read(&mut buf).await?;
-        tracing::info!("Response: {}", String::from_utf8_lossy(&buf[..bytes_read]));
+        let response = read_frame(&mut stream)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Server closed the connection"))?;
+        tracing::info!("Response: {}", String::from_utf8_lossy(&response));
         tokio::time::sleep(std::time::Duration::from_secs_f32(0.1)).await;
     }
 
@@ -74,10 +146,96 @@ async fn main() -> anyhow::Result<()> {
     console_subscriber::init();
 
     // Start the server
-    spawn(listen());
+    let shutdown = CancellationToken::new();
+    let server = spawn(listen(shutdown.clone()));
 
-    // Start the periodic client
-    client_spawner().await?;
+    // Start the periodic client, until Ctrl-C is pressed
+    tokio::select! {
+        result = client_spawner() => result?,
+        _ = tokio::signal::ctrl_c() => tracing::info!("Ctrl-C received"),
+    }
+
+    // Tell the server to stop, and wait for it to drain
+    shutdown.cancel();
+    server.await??;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn cancelling_stops_the_accept_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let shutdown = CancellationToken::new();
+        let server = spawn(accept_loop(listener, shutdown.clone()));
+
+        shutdown.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("the accept loop didn't stop");
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn idle_clients_dont_hold_up_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = spawn(accept_loop(listener, shutdown.clone()));
+
+        // A client that says hello, and then never says anything again
+        let mut idle = TcpStream::connect(address).await.unwrap();
+        write_frame(&mut idle, b"hello").await.unwrap();
+        read_frame(&mut idle).await.unwrap().unwrap();
+        shutdown.cancel();
+        let result = tokio::time::timeout(DRAIN_TIMEOUT * 2, server)
+            .await
+            .expect("the accept loop waited for the idle client");
+        assert!(result.unwrap().is_ok());
+        drop(idle);
+    }
+
+    #[tokio::test]
+    async fn dropped_clients_end_the_task_cleanly() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        let (socket, client_address) = listener.accept().await.unwrap();
+        let connection = spawn(echo_stream(socket, client_address));
+
+        // Start a frame, then reset the connection halfway through it
+        client.write_u32(100).await.unwrap();
+        client.write_all(b"abc").await.unwrap();
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), connection)
+            .await
+            .expect("the connection task didn't finish");
+        assert!(result.is_ok(), "the connection task panicked");
+    }
+
+    #[tokio::test]
+    async fn large_messages_echo_intact() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = spawn(accept_loop(listener, shutdown.clone()));
+
+        // Bigger than the old 1024 byte buffer, and not a nice round number
+        let message: Vec<u8> = (0..5000u32).map(|n| (n % 251) as u8).collect();
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        write_frame(&mut stream, &message).await.unwrap();
+        let response = read_frame(&mut stream).await.unwrap().unwrap();
+        assert_eq!(response, message);
+
+        drop(stream);
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
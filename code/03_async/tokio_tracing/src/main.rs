@@ -1,4 +1,16 @@
-use tracing_subscriber::fmt::format::FmtSpan;
+use hdrhistogram::Histogram;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tracing::{span, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, MakeWriter},
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    Layer,
+};
 
 #[tracing::instrument]
 async fn hello_world() {
@@ -17,13 +29,12 @@ async fn double(n: u32) -> u32 {
     n * 2
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // Applications that receive events need to subscribe
-    //let subscriber = tracing_subscriber::FmtSubscriber::new();
-
-    // Start configuring a `fmt` subscriber
-    let subscriber = tracing_subscriber::fmt()
+fn compact_layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // Start configuring a `fmt` layer
+    tracing_subscriber::fmt::layer()
         // Use a more compact, abbreviated log format
         .compact()
         // Display source code file paths
@@ -36,8 +47,130 @@ async fn main() -> anyhow::Result<()> {
         .with_target(false)
         // Add span events
         .with_span_events(FmtSpan::ENTER | FmtSpan::CLOSE)
-        // Build the subscriber
-        .finish();
+}
+
+/// The same fields as the compact format, but one JSON object per line -
+/// for log collectors rather than people.
+fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_target(false)
+        .with_span_events(FmtSpan::ENTER | FmtSpan::CLOSE)
+        .with_writer(writer)
+}
+
+/// Plain text without color codes, for writing to log files
+fn file_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_target(false)
+        .with_span_events(FmtSpan::ENTER | FmtSpan::CLOSE)
+        .with_writer(writer)
+}
+
+/// Records how long each span lives (in nanoseconds), with a histogram
+/// for each span name. Clones share the same histograms, so keep one to
+/// print the `report` at the end.
+#[derive(Clone, Default)]
+struct SpanTimings {
+    histograms: Arc<Mutex<HashMap<&'static str, Histogram<u64>>>>,
+}
+
+/// Stored in each span's extensions, so we know when it started
+struct SpanStart(Instant);
+
+impl<S> Layer<S> for SpanTimings
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanStart(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(start) = span.extensions().get::<SpanStart>().map(|start| start.0) else { return };
+        let nanos = start.elapsed().as_nanos() as u64;
+
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms
+            .entry(span.name())
+            // Three significant figures, growing as needed
+            .or_insert_with(|| Histogram::new(3).unwrap())
+            .saturating_record(nanos);
+    }
+}
+
+impl SpanTimings {
+    /// Percentiles for every span name, in microseconds
+    fn report(&self) -> String {
+        let histograms = self.histograms.lock().unwrap();
+        let mut names: Vec<&&str> = histograms.keys().collect();
+        names.sort();
+
+        let mut report = String::new();
+        for name in names {
+            let histogram = &histograms[*name];
+            let micros = |nanos: u64| nanos as f64 / 1000.0;
+            report.push_str(&format!(
+                "{name}: count {}, p50 {:.1}µs, p90 {:.1}µs, p99 {:.1}µs, max {:.1}µs\n",
+                histogram.len(),
+                micros(histogram.value_at_quantile(0.5)),
+                micros(histogram.value_at_quantile(0.9)),
+                micros(histogram.value_at_quantile(0.99)),
+                micros(histogram.max()),
+            ));
+        }
+        report
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // Applications that receive events need to subscribe
+    //let subscriber = tracing_subscriber::FmtSubscriber::new();
+
+    // Set LOG_FORMAT=json for machine-readable output
+    let use_json = std::env::var("LOG_FORMAT").map(|f| f == "json").unwrap_or(false);
+    let stdout_layer = if use_json {
+        json_layer(std::io::stdout).boxed()
+    } else {
+        compact_layer().boxed()
+    };
+
+    // Set LOG_DIR to also log to a file, which rotates daily.
+    // Keep the guard around: dropping it flushes the remaining logs.
+    let (file_layer, _guard) = match std::env::var("LOG_DIR") {
+        Ok(directory) => {
+            let appender = tracing_appender::rolling::daily(directory, "tokio_tracing.log");
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (Some(file_layer(writer)), Some(guard))
+        }
+        Err(_) => (None, None),
+    };
+
+    // Stack the layers, so every event goes to each of them
+    let timings = SpanTimings::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(stdout_layer)
+        .with(file_layer)
+        .with(timings.clone());
 
     // Set the subscriber as the default
     tracing::subscriber::set_global_default(subscriber)?;
@@ -51,5 +184,95 @@ async fn main() -> anyhow::Result<()> {
     tokio::join!(hello_world(), sleepy_greeting());
     double(4).await;
 
+    // How long did everything take?
+    print!("{}", timings.report());
+
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Collects everything written to it, so tests can inspect the logs
+    #[derive(Clone, Default)]
+    struct CaptureWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CaptureWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for CaptureWriter {
+        type Writer = CaptureWriter;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn json_output_has_expected_fields() {
+        let writer = CaptureWriter::default();
+        let subscriber = tracing_subscriber::registry().with(json_layer(writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Hello JSON");
+        });
+
+        let output = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "Hello JSON");
+        assert!(line["filename"].is_string());
+        assert!(line["line_number"].is_number());
+        assert!(line["threadId"].is_string());
+    }
+
+    #[tokio::test]
+    async fn span_timings_are_recorded() {
+        let timings = SpanTimings::default();
+        let subscriber = tracing_subscriber::registry().with(timings.clone());
+        {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            hello_world().await;
+            double(2).await;
+            double(3).await;
+        }
+
+        let histograms = timings.histograms.lock().unwrap();
+        let mut names: Vec<&str> = histograms.keys().copied().collect();
+        names.sort();
+        assert_eq!(names, vec!["doubler", "hello_world"]);
+        assert_eq!(histograms["hello_world"].len(), 1);
+        assert_eq!(histograms["doubler"].len(), 2);
+        assert!(histograms.values().all(|histogram| histogram.max() > 0));
+        drop(histograms);
+
+        assert!(timings.report().contains("doubler: count 2"));
+    }
+
+    #[test]
+    fn file_layer_writes_to_the_log_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        let appender = tracing_appender::rolling::daily(directory.path(), "test.log");
+        let subscriber = tracing_subscriber::registry().with(file_layer(appender));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Hello log file");
+        });
+
+        // The file name has the date appended, so find it
+        let log_file = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("test.log"))
+            .expect("no log file was created");
+        let contents = std::fs::read_to_string(log_file).unwrap();
+        assert!(contents.contains("Hello log file"));
+    }
+}
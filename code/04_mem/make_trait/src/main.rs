@@ -1,10 +1,12 @@
 use std::{fmt::Debug, rc::Rc};
 use std::any::Any;
+use serde::{Deserialize, Serialize};
 
 trait Animal {
     fn speak(&self);
 }
 
+#[derive(Serialize, Deserialize)]
 struct Cat;
 
 impl Animal for Cat {
@@ -24,7 +26,7 @@ fn get_animal() -> impl Animal {
 
 trait DebuggableClonableAnimal: Animal+Debug+Clone {}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Dog;
 
 impl Animal for Dog {
@@ -59,6 +61,27 @@ impl DowncastableAnimal for Tortoise {
     }
 }
 
+/// An animal that can be saved and loaded. `typetag` records which
+/// concrete type each one was, so a mixed `Vec` comes back the same.
+#[typetag::serde]
+trait SerializableAnimal: Animal {
+    fn as_any(&self) -> &dyn Any;
+}
+
+#[typetag::serde]
+impl SerializableAnimal for Cat {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[typetag::serde]
+impl SerializableAnimal for Dog {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
 fn main() {
     let cat = Cat;
     cat.speak();
@@ -79,4 +102,30 @@ fn main() {
         }
         animal.speak();
     }
+
+    // A mixed bag of animals, to JSON and back
+    let pets: Vec<Box<dyn SerializableAnimal>> = vec![Box::new(Cat), Box::new(Dog)];
+    let json = serde_json::to_string(&pets).unwrap();
+    println!("{json}");
+    let pets: Vec<Box<dyn SerializableAnimal>> = serde_json::from_str(&json).unwrap();
+    for pet in pets.iter() {
+        pet.speak();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mixed_animals_round_trip() {
+        let pets: Vec<Box<dyn SerializableAnimal>> = vec![Box::new(Cat), Box::new(Dog)];
+        let json = serde_json::to_string(&pets).unwrap();
+        assert_eq!(json, r#"[{"Cat":null},{"Dog":null}]"#);
+
+        let pets: Vec<Box<dyn SerializableAnimal>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(pets.len(), 2);
+        assert!(pets[0].as_any().downcast_ref::<Cat>().is_some());
+        assert!(pets[1].as_any().downcast_ref::<Dog>().is_some());
+    }
 }
@@ -1,23 +1,69 @@
-use std::alloc::{Layout, alloc, dealloc};
+use std::alloc::Layout;
+use std::{ops::Deref, ptr::NonNull, sync::atomic::{fence, AtomicUsize, Ordering}};
 
-struct SmartPointer<T> {
+/// Somewhere to get memory from. `SmartPointer` uses the global allocator
+/// unless you give it something else - a bump allocator, say.
+trait Allocator {
+    /// # Safety
+    /// Same rules as `std::alloc::alloc`: `layout` mustn't be zero-sized.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    /// `ptr` must have come from `alloc` on this allocator, with the same `layout`.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+}
+
+/// The allocator everything else uses
+#[derive(Debug, Default, Clone, Copy)]
+struct Global;
+
+impl Allocator for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        std::alloc::alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        std::alloc::dealloc(ptr, layout)
+    }
+}
+
+// So that several pointers can share one allocator
+impl<A: Allocator> Allocator for &A {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        (**self).alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        (**self).dealloc(ptr, layout)
+    }
+}
+
+struct SmartPointer<T, A: Allocator = Global> {
     ptr: *mut u8,
     data: *mut T,
-    layout: Layout
+    layout: Layout,
+    allocator: A,
 }
 
 impl <T> SmartPointer<T> {
     fn new() -> SmartPointer<T> {
+        Self::new_in(Global)
+    }
+}
+
+impl <T, A: Allocator> SmartPointer<T, A> {
+    fn new_in(allocator: A) -> SmartPointer<T, A> {
         println!("Allocating memory for SmartPointer");
 
         unsafe {
             let layout = Layout::new::<T>();
-            let ptr = alloc(layout);
+            let ptr = allocator.alloc(layout);
 
             SmartPointer {
                 ptr,
                 data: ptr as *mut T,
-                layout
+                layout,
+                allocator,
             }
         }
     }
@@ -35,11 +81,73 @@ impl <T> SmartPointer<T> {
     }
 }
 
-impl <T> Drop for SmartPointer<T> {
+impl <T, A: Allocator> Drop for SmartPointer<T, A> {
     fn drop(&mut self) {
         println!("Deallocating memory from SmartPointer");
         unsafe {
-            dealloc(self.ptr, self.layout);
+            self.allocator.dealloc(self.ptr, self.layout);
+        }
+    }
+}
+
+/// The count and the data live in one allocation, like `Arc`
+struct SharedInner<T> {
+    count: AtomicUsize,
+    data: T,
+}
+
+/// A home-made `Arc`: every clone points at the same data, and the last
+/// one to be dropped frees it.
+struct SharedPointer<T> {
+    inner: NonNull<SharedInner<T>>,
+}
+
+// Clones can end up on other threads, so T has to be ok with that
+unsafe impl<T: Send + Sync> Send for SharedPointer<T> {}
+unsafe impl<T: Send + Sync> Sync for SharedPointer<T> {}
+
+impl<T> SharedPointer<T> {
+    fn new(data: T) -> SharedPointer<T> {
+        let inner = Box::new(SharedInner { count: AtomicUsize::new(1), data });
+        SharedPointer { inner: NonNull::from(Box::leak(inner)) }
+    }
+
+    fn inner(&self) -> &SharedInner<T> {
+        // Safe: the allocation lives until the last pointer is dropped
+        unsafe { self.inner.as_ref() }
+    }
+
+    fn strong_count(this: &Self) -> usize {
+        this.inner().count.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for SharedPointer<T> {
+    fn clone(&self) -> Self {
+        // Nothing to synchronize - we already have a reference
+        self.inner().count.fetch_add(1, Ordering::Relaxed);
+        SharedPointer { inner: self.inner }
+    }
+}
+
+impl<T> Deref for SharedPointer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().data
+    }
+}
+
+impl<T> Drop for SharedPointer<T> {
+    fn drop(&mut self) {
+        if self.inner().count.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        // We were the last one. Make sure every other thread's use of the
+        // data happens before we free it.
+        fence(Ordering::Acquire);
+        unsafe {
+            drop(Box::from_raw(self.inner.as_ptr()));
         }
     }
 }
@@ -51,4 +159,87 @@ fn main() {
 
     let my_num = Box::new(12u32);
     println!("my_num = {}", *my_num);
+
+    let shared = SharedPointer::new(12u32);
+    let handles: Vec<_> = (0..4).map(|n| {
+        let shared = shared.clone();
+        std::thread::spawn(move || println!("Thread {n} sees {}", *shared))
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    println!("{} reference(s) left", SharedPointer::strong_count(&shared));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Hands out global memory, counting as it goes
+    #[derive(Default)]
+    struct CountingAllocator {
+        allocations: Cell<usize>,
+        frees: Cell<usize>,
+    }
+
+    impl Allocator for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.allocations.set(self.allocations.get() + 1);
+            Global.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.frees.set(self.frees.get() + 1);
+            Global.dealloc(ptr, layout)
+        }
+    }
+
+    /// Counts how many times it has been dropped
+    struct DropCounter(std::sync::Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn shared_pointer_frees_once() {
+        let drops = std::sync::Arc::new(AtomicUsize::new(0));
+        let shared = SharedPointer::new(DropCounter(drops.clone()));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    let more: Vec<_> = (0..100).map(|_| shared.clone()).collect();
+                    drop(more);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(SharedPointer::strong_count(&shared), 1);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        drop(shared);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn allocations_balance() {
+        let allocator = CountingAllocator::default();
+        {
+            let mut a = SmartPointer::<u64, _>::new_in(&allocator);
+            let mut b = SmartPointer::<u32, _>::new_in(&allocator);
+            a.set(1);
+            b.set(2);
+            assert_eq!((*a.get(), *b.get()), (1, 2));
+            assert_eq!(allocator.allocations.get(), 2);
+            assert_eq!(allocator.frees.get(), 0);
+        }
+        assert_eq!(allocator.frees.get(), 2);
+    }
 }
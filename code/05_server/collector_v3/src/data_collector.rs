@@ -1,44 +1,210 @@
 use shared_v3::CollectorCommandV1;
-use sysinfo::{SystemExt, CpuExt};
-use std::{time::Instant, sync::mpsc::Sender};
+use sysinfo::{System, SystemExt, CpuExt, NetworkExt, NetworksExt};
+use std::{time::{Duration, Instant}, sync::{mpsc::Sender, Arc, atomic::{AtomicBool, Ordering}}};
 
-pub fn collect_data(tx: Sender<CollectorCommandV1>, collector_id: u128) {
+const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often to check for a shutdown while waiting for the next sample
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Parse a number of seconds between samples. Missing, zero or invalid
+/// values fall back to the default.
+pub fn parse_sample_interval(value: Option<&str>) -> Duration {
+    match value.map(|value| value.trim().parse::<u64>()) {
+        Some(Ok(seconds)) if seconds > 0 => Duration::from_secs(seconds),
+        Some(_) => {
+            println!("Invalid sample interval, using {DEFAULT_SAMPLE_INTERVAL:?}");
+            DEFAULT_SAMPLE_INTERVAL
+        }
+        None => DEFAULT_SAMPLE_INTERVAL,
+    }
+}
+
+/// The sample interval, from the SAMPLE_INTERVAL_SECS environment variable
+pub fn sample_interval() -> Duration {
+    parse_sample_interval(std::env::var("SAMPLE_INTERVAL_SECS").ok().as_deref())
+}
+
+/// The usage (0-100%) of each logical core
+pub fn per_core_cpu_usage(sys: &System) -> Vec<f32> {
+    sys.cpus().iter().map(|cpu| cpu.cpu_usage()).collect()
+}
+
+/// Total bytes (received, transmitted) across every network interface
+/// since the machine started.
+pub fn network_totals(sys: &System) -> (u64, u64) {
+    sys.networks()
+        .iter()
+        .fold((0, 0), |(received, transmitted), (_name, network)| {
+            (received + network.total_received(), transmitted + network.total_transmitted())
+        })
+}
+
+/// Turns running network totals into "bytes since the last sample".
+#[derive(Default)]
+pub struct NetworkCounter {
+    previous: Option<(u64, u64)>,
+}
+
+impl NetworkCounter {
+    pub fn sample(&mut self, total_received: u64, total_transmitted: u64) -> (u64, u64) {
+        let delta = match self.previous {
+            // Saturate, in case an interface went away and the totals dropped
+            Some((received, transmitted)) => (
+                total_received.saturating_sub(received),
+                total_transmitted.saturating_sub(transmitted),
+            ),
+            // There's nothing to compare the first sample with
+            None => (0, 0),
+        };
+        self.previous = Some((total_received, total_transmitted));
+        delta
+    }
+}
+
+/// Introduce ourselves to the server: what we're running on
+pub fn hello(collector_id: u128) -> CollectorCommandV1 {
+    let mut sys = System::new();
+    sys.refresh_cpu();
+    CollectorCommandV1::Hello {
+        collector_id,
+        os: std::env::consts::OS.to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+        cores: sys.cpus().len() as u32,
+    }
+}
+
+/// Sleep until `deadline`, or until `shutdown` is raised - whichever is first.
+fn sleep_until(deadline: Instant, shutdown: &AtomicBool) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline || shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+        std::thread::sleep((deadline - now).min(SHUTDOWN_CHECK_INTERVAL));
+    }
+}
+
+/// Sample every `interval` until `shutdown` is raised.
+pub fn collect_data(tx: Sender<CollectorCommandV1>, collector_id: u128, interval: Duration, shutdown: Arc<AtomicBool>) {
     let mut sys = sysinfo::System::new_all();
     sys.refresh_memory();
     sys.refresh_cpu();
-    std::thread::sleep(std::time::Duration::from_secs_f32(1.0));
-    loop {
+    let mut network_counter = NetworkCounter::default();
+    sleep_until(Instant::now() + Duration::from_secs(1), &shutdown);
+    while !shutdown.load(Ordering::Relaxed) {
         let now = Instant::now();
 
         // Refresh the stored data
         sys.refresh_memory();
         sys.refresh_cpu();
+        sys.refresh_networks();
 
         // Get new values
         let total_memory = sys.total_memory();
         let used_memory = sys.used_memory();
-        let num_cpus = sys.cpus().len();
-        let total_cpu_usage = sys.cpus().iter().map(|cpu| cpu.cpu_usage()).sum::<f32>();
-        let average_cpu_usage = total_cpu_usage / num_cpus as f32;
+        let cpu_usage = per_core_cpu_usage(&sys);
+        let (total_received, total_transmitted) = network_totals(&sys);
+        let (bytes_received, bytes_transmitted) = network_counter.sample(total_received, total_transmitted);
 
         // Submit
-        let send_result = tx.send(CollectorCommandV1::SubmitData {
+        let send_result = tx.send(CollectorCommandV1::SubmitDataV2 {
             collector_id,
             total_memory,
             used_memory,
-            average_cpu_usage,
+            cpu_usage,
+            bytes_received,
+            bytes_transmitted,
         });
         if let Err(e) = send_result {
             println!("Error sending data: {e:?}");
         }
 
-        // Wait for the next cycle
-        let elapsed_seconds = now.elapsed().as_secs_f32();
-        if elapsed_seconds < 1.0 {
-            std::thread::sleep(std::time::Duration::from_secs_f32(1.0 - elapsed_seconds));
-        } else {
-            // Warning: we're running behind!
-            std::thread::sleep(std::time::Duration::from_secs_f32(1.0));
+        // Wait for the next cycle. If we're running behind, start it now.
+        sleep_until(now + interval, &shutdown);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn one_value_per_core() {
+        let mut sys = System::new_all();
+        sys.refresh_cpu();
+        let usage = per_core_cpu_usage(&sys);
+
+        // We may be restricted to fewer cores than the machine has, but never more
+        let available = std::thread::available_parallelism().unwrap().get();
+        assert!(usage.len() >= available);
+        assert!(usage.iter().all(|cpu| (0.0..=100.0).contains(cpu)));
+    }
+
+    #[test]
+    fn hello_describes_this_machine() {
+        let command = hello(1234);
+        match &command {
+            CollectorCommandV1::Hello { collector_id, os, arch, cores } => {
+                assert_eq!(*collector_id, 1234);
+                assert_eq!(os, std::env::consts::OS);
+                assert_eq!(arch, std::env::consts::ARCH);
+                assert!(*cores > 0);
+            }
+            _ => panic!("Expected a Hello command"),
         }
+
+        let (_timestamp, decoded) = shared_v3::decode_v1(&shared_v3::encode_v1(&command));
+        assert_eq!(decoded, command);
+    }
+
+    #[test]
+    fn shutdown_cuts_the_wait_short() {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let raiser = shutdown.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            raiser.store(true, Ordering::Relaxed);
+        });
+
+        let start = Instant::now();
+        sleep_until(start + Duration::from_secs(60), &shutdown);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // A deadline that has already passed doesn't wait at all
+        shutdown.store(false, Ordering::Relaxed);
+        let start = Instant::now();
+        sleep_until(start, &shutdown);
+        assert!(start.elapsed() < SHUTDOWN_CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn sample_interval_parsing() {
+        assert_eq!(parse_sample_interval(None), DEFAULT_SAMPLE_INTERVAL);
+        assert_eq!(parse_sample_interval(Some("5")), Duration::from_secs(5));
+        assert_eq!(parse_sample_interval(Some("0")), DEFAULT_SAMPLE_INTERVAL);
+        assert_eq!(parse_sample_interval(Some("soon")), DEFAULT_SAMPLE_INTERVAL);
+    }
+
+    #[test]
+    fn network_delta_between_samples() {
+        let mut counter = NetworkCounter::default();
+        assert_eq!(counter.sample(1000, 500), (0, 0));
+        assert_eq!(counter.sample(1500, 600), (500, 100));
+        // Totals going backwards (an interface was removed) don't underflow
+        assert_eq!(counter.sample(100, 50), (0, 0));
+    }
+
+    #[test]
+    fn network_samples_from_this_machine() {
+        let mut sys = System::new_all();
+        let mut counter = NetworkCounter::default();
+        let (received, transmitted) = network_totals(&sys);
+        assert_eq!(counter.sample(received, transmitted), (0, 0));
+
+        sys.refresh_networks();
+        let (new_received, new_transmitted) = network_totals(&sys);
+        let (delta_received, delta_transmitted) = counter.sample(new_received, new_transmitted);
+        assert_eq!(delta_received, new_received.saturating_sub(received));
+        assert_eq!(delta_transmitted, new_transmitted.saturating_sub(transmitted));
     }
-}
\ No newline at end of file
+}
@@ -1,8 +1,29 @@
-use std::collections::VecDeque;
-use shared_v3::CollectorCommandV1;
+use std::{collections::VecDeque, sync::{mpsc::RecvTimeoutError, Arc, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+use clap::Parser;
+use shared_v3::{CollectorCommandV1, DATA_COLLECTOR_ADDRESS};
 mod data_collector;
 mod sender;
 mod errors;
+mod ring_buffer;
+
+/// Ping the server if we haven't sent anything for this long
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Wait at least this long before retrying an unreachable server...
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// ...and no longer than this
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Parser)]
+#[command()]
+struct Args {
+    /// Seconds between samples. Without it, SAMPLE_INTERVAL_SECS is used,
+    /// or 10 seconds if that isn't set.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    interval_seconds: Option<u64>,
+    /// Where to send the samples
+    #[arg(long, default_value = DATA_COLLECTOR_ADDRESS)]
+    server: String,
+}
 
 fn get_uuid() -> u128 {
     let path = std::path::Path::new("uuid");
@@ -17,23 +38,109 @@ fn get_uuid() -> u128 {
 }
 
 fn main() {
+    let args = Args::parse();
     let uuid = get_uuid();
+    let interval = args.interval_seconds.map(Duration::from_secs).unwrap_or_else(data_collector::sample_interval);
     let (tx, rx) = std::sync::mpsc::channel::<CollectorCommandV1>();
+    // Raised when the server tells us to stop
+    let shutdown = Arc::new(AtomicBool::new(false));
 
     // Start the collector thread
-    let _collector_thread = std::thread::spawn(move || {
-        data_collector::collect_data(tx, uuid);
+    let collector_shutdown = shutdown.clone();
+    let collector_thread = std::thread::spawn(move || {
+        data_collector::collect_data(tx, uuid, interval, collector_shutdown);
     });
 
     // Listen for commands to send
-    let mut send_queue = VecDeque::with_capacity(120);
-    while let Ok(command) = rx.recv() {
-        let encoded = shared_v3::encode_v1(&command);
-        //println!("Encoded: {} bytes", encoded.len());
-        send_queue.push_back(encoded);
-        let result = sender::send_queue(&mut send_queue, uuid);
-        if result.is_err() {
-            println!("{result:?}");
+    let mut encoder = sender::Encoder::new(sender::compression_enabled(), shared_v3::hmac_key_from_env());
+    // Anything we didn't manage to send last time goes first
+    let ring = ring_buffer::RingBuffer::new("ring_buffer", ring_buffer::RingBuffer::capacity_from_env())
+        .with_max_bytes(ring_buffer::RingBuffer::max_bytes_from_env());
+    let mut send_queue = match sender::replay_backlog(&args.server, &ring, &shutdown) {
+        Ok(replayed) => {
+            if replayed > 0 {
+                println!("Replayed {replayed} unsent readings");
+            }
+            VecDeque::new()
+        }
+        // Keep them queued, and try again with the next send
+        Err(_) => ring.load(),
+    };
+    // Introduce ourselves - this goes out with the first batch of data
+    send_queue.push_back(encoder.encode(&data_collector::hello(uuid)));
+    let mut backoff = sender::Backoff::new(MIN_RETRY_DELAY, MAX_RETRY_DELAY);
+    let mut retry_at = None;
+    loop {
+        match rx.recv_timeout(PING_INTERVAL) {
+            Ok(command) => {
+                let encoded = encoder.encode(&command);
+                //println!("Encoded: {} bytes", encoded.len());
+                send_queue.push_back(encoded);
+                // While the server is unreachable, let the queue build up
+                if retry_at.is_none_or(|retry_at| Instant::now() >= retry_at) {
+                    match sender::send_queue(&mut send_queue, &encoder, &args.server, uuid, &shutdown) {
+                        Ok(()) => {
+                            backoff.reset();
+                            retry_at = None;
+                        }
+                        Err(e) => {
+                            let delay = backoff.next_delay();
+                            println!("{e:?}, retrying in {delay:?}");
+                            retry_at = Some(Instant::now() + delay);
+                        }
+                    }
+                }
+                // Keep whatever is still unsent, in case we stop
+                if let Err(e) = ring.save(&mut send_queue) {
+                    println!("Unable to save the ring buffer: {e:?}");
+                }
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // We've been quiet for a while - let the server know we're alive
+                let result = sender::send_ping(&encoder, &args.server, uuid, &shutdown);
+                if result.is_err() {
+                    println!("Ping failed: {result:?}");
+                }
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    // Stop the collector thread, and send anything it had already queued up
+    shutdown.store(true, Ordering::Relaxed);
+    let _ = collector_thread.join();
+    send_queue.extend(rx.try_iter().map(|command| encoder.encode(&command)));
+    if !send_queue.is_empty() {
+        if let Err(e) = sender::send_queue(&mut send_queue, &encoder, &args.server, uuid, &shutdown) {
+            println!("Unable to flush the queue: {e:?}");
         }
     }
+    if let Err(e) = ring.save(&mut send_queue) {
+        println!("Unable to save the ring buffer: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_line() {
+        let args = Args::try_parse_from(["collector_v3"]).unwrap();
+        assert_eq!(args.interval_seconds, None);
+        assert_eq!(args.server, DATA_COLLECTOR_ADDRESS);
+
+        let args = Args::try_parse_from(["collector_v3", "--interval-seconds", "60", "--server", "10.0.0.1:9004"]).unwrap();
+        assert_eq!(args.interval_seconds, Some(60));
+        assert_eq!(args.server, "10.0.0.1:9004");
+
+        // Less than a second isn't allowed
+        assert!(Args::try_parse_from(["collector_v3", "--interval-seconds", "0"]).is_err());
+    }
 }
@@ -0,0 +1,208 @@
+use std::{collections::VecDeque, io::{self, Read, Write}, path::{Path, PathBuf}};
+
+const DEFAULT_CAPACITY: usize = 120;
+const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+
+/// Unsent frames, kept on disk so they survive a restart or a long outage.
+/// Only the newest `capacity` frames are kept, and the file never grows
+/// past `max_bytes` - older frames are overwritten.
+pub struct RingBuffer {
+    path: PathBuf,
+    capacity: usize,
+    max_bytes: usize,
+}
+
+impl RingBuffer {
+    pub fn new(path: impl Into<PathBuf>, capacity: usize) -> Self {
+        Self { path: path.into(), capacity: capacity.max(1), max_bytes: DEFAULT_MAX_BYTES }
+    }
+
+    /// Where the frames are kept
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Limit the size of the file, as well as the number of frames
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Read the size limit from `RING_BUFFER_MAX_BYTES`, or use the default.
+    pub fn max_bytes_from_env() -> usize {
+        std::env::var("RING_BUFFER_MAX_BYTES")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES)
+    }
+
+    /// Drop the oldest frames until the rest fit, in number and in bytes
+    fn trim(&self, queue: &mut VecDeque<Vec<u8>>) {
+        // Each frame is stored after its length
+        let stored_size = |frame: &Vec<u8>| frame.len() + 4;
+        let mut bytes: usize = queue.iter().map(stored_size).sum();
+        while queue.len() > self.capacity || bytes > self.max_bytes {
+            let Some(frame) = queue.pop_front() else { break };
+            bytes -= stored_size(&frame);
+        }
+    }
+
+    /// Read the capacity from `RING_BUFFER_CAPACITY`, or use the default.
+    pub fn capacity_from_env() -> usize {
+        std::env::var("RING_BUFFER_CAPACITY")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .filter(|capacity| *capacity > 0)
+            .unwrap_or(DEFAULT_CAPACITY)
+    }
+
+    /// Load the frames that survived the last run. A missing or damaged
+    /// file just means there's nothing to replay.
+    pub fn load(&self) -> VecDeque<Vec<u8>> {
+        let Ok(file) = std::fs::File::open(&self.path) else {
+            return VecDeque::new();
+        };
+        let mut frames = read_frames(file);
+        // In case the limits shrank since last time
+        self.trim(&mut frames);
+        frames
+    }
+
+    /// Drop the oldest frames until `queue` fits, then store it.
+    pub fn save(&self, queue: &mut VecDeque<Vec<u8>>) -> io::Result<()> {
+        self.trim(queue);
+
+        // Write somewhere else first, so a crash can't leave half a file
+        let temp_path = self.path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temp_path)?;
+        for frame in queue.iter() {
+            file.write_all(&(frame.len() as u32).to_be_bytes())?;
+            file.write_all(frame)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(temp_path, &self.path)
+    }
+}
+
+/// Each frame in the file, oldest first. A frame cut short by a crash ends
+/// the list.
+fn read_frames(mut file: impl Read) -> VecDeque<Vec<u8>> {
+    let mut frames = VecDeque::new();
+    let mut len = [0u8; 4];
+    while file.read_exact(&mut len).is_ok() {
+        let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+        if file.read_exact(&mut frame).is_err() {
+            break;
+        }
+        frames.push_back(frame);
+    }
+    frames
+}
+
+/// Write the frames saved at `path` to `stream`, oldest first, returning how
+/// many there were. The file is left alone: it's only safe to forget the
+/// frames once the server has acknowledged them.
+pub fn replay_spool<S: Write>(stream: &mut S, path: &Path) -> io::Result<usize> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let frames = read_frames(file);
+    for frame in &frames {
+        stream.write_all(frame)?;
+    }
+    stream.flush()?;
+    Ok(frames.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overflow_keeps_the_newest() {
+        let directory = tempfile::tempdir().unwrap();
+        let ring = RingBuffer::new(directory.path().join("ring"), 3);
+
+        let mut queue: VecDeque<Vec<u8>> = (0u8..5).map(|n| vec![n; n as usize + 1]).collect();
+        ring.save(&mut queue).unwrap();
+        assert_eq!(queue.len(), 3);
+
+        // A fresh start sees the same three frames
+        let loaded = RingBuffer::new(directory.path().join("ring"), 3).load();
+        assert_eq!(loaded, VecDeque::from(vec![vec![2; 3], vec![3; 4], vec![4; 5]]));
+    }
+
+    #[test]
+    fn size_limit_keeps_the_newest() {
+        let directory = tempfile::tempdir().unwrap();
+        // Room for two 10 byte frames and their lengths, but not three
+        let ring = RingBuffer::new(directory.path().join("ring"), 100).with_max_bytes(30);
+
+        let mut queue: VecDeque<Vec<u8>> = (0u8..4).map(|n| vec![n; 10]).collect();
+        ring.save(&mut queue).unwrap();
+        assert_eq!(queue, VecDeque::from(vec![vec![2; 10], vec![3; 10]]));
+        assert_eq!(std::fs::metadata(directory.path().join("ring")).unwrap().len(), 28);
+    }
+
+    #[test]
+    fn replay_comes_before_new_frames() {
+        let directory = tempfile::tempdir().unwrap();
+        let ring = RingBuffer::new(directory.path().join("ring"), 10);
+
+        // The server was down, so these were saved instead of sent
+        let mut unsent: VecDeque<Vec<u8>> = VecDeque::from(vec![vec![1], vec![2]]);
+        ring.save(&mut unsent).unwrap();
+
+        // After a restart, they go out ahead of anything new - in order
+        let mut queue = ring.load();
+        queue.push_back(vec![3]);
+        assert_eq!(queue, VecDeque::from(vec![vec![1], vec![2], vec![3]]));
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(RingBuffer::new(directory.path().join("nothing"), 3).load().is_empty());
+        assert_eq!(replay_spool(&mut Vec::new(), &directory.path().join("nothing")).unwrap(), 0);
+    }
+
+    /// A connection that drops after `limit` bytes
+    struct FlakyStream {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for FlakyStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() + buf.len() > self.limit {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn spool_replays_in_order_after_a_reconnect() {
+        let directory = tempfile::tempdir().unwrap();
+        let ring = RingBuffer::new(directory.path().join("ring"), 10);
+        let mut unsent: VecDeque<Vec<u8>> = VecDeque::from(vec![vec![1; 3], vec![2; 2], vec![3; 4]]);
+        ring.save(&mut unsent).unwrap();
+
+        // The connection drops part way through, and nothing is lost
+        let mut dropped = FlakyStream { written: Vec::new(), limit: 4 };
+        assert!(replay_spool(&mut dropped, ring.path()).is_err());
+        assert_eq!(ring.load(), unsent);
+
+        // Once we reconnect, every frame goes out - oldest first, as is
+        let mut stream = FlakyStream { written: Vec::new(), limit: usize::MAX };
+        assert_eq!(replay_spool(&mut stream, ring.path()).unwrap(), 3);
+        assert_eq!(stream.written, vec![1, 1, 1, 2, 2, 3, 3, 3, 3]);
+    }
+}
@@ -1,6 +1,6 @@
-use crate::errors::CollectorError;
-use shared_v3::{DATA_COLLECTOR_ADDRESS, decode_response_v1, CollectorResponseV1};
-use std::{io::{Write, Read}, collections::VecDeque};
+use crate::{errors::CollectorError, ring_buffer::RingBuffer};
+use shared_v3::{decode_responses_v1, CollectorResponseV1, ProtocolError, TaskType};
+use std::{io::{Write, Read}, collections::VecDeque, net::TcpStream, sync::atomic::{AtomicBool, Ordering}, time::Duration};
 
 /*pub fn send_command(bytes: &[u8]) -> Result<(), CollectorError> {
     let mut stream = std::net::TcpStream::connect(DATA_COLLECTOR_ADDRESS)
@@ -12,49 +12,410 @@ use std::{io::{Write, Read}, collections::VecDeque};
     Ok(())
 }*/
 
-pub fn send_queue(queue: &mut VecDeque<Vec<u8>>, collector_id: u128) -> Result<(), CollectorError> {
-    // Connect
-    let mut stream = std::net::TcpStream::connect(DATA_COLLECTOR_ADDRESS)
-        .map_err(|_| CollectorError::UnableToConnect)?;
+/// Log the compression ratio after this many frames
+const COMPRESSION_REPORT_INTERVAL: u64 = 60;
+/// Give up on a reply after this long. The frame stays queued, and goes
+/// out again next time.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
 
-    // Send every queue item
-    let mut buf = vec![0u8; 512];
-    while let Some(command) = queue.pop_front() {
-        if stream.write_all(&command).is_err() {
-            queue.push_front(command);
-            return Err(CollectorError::UnableToSendData);
+/// Set `COLLECTOR_COMPRESS=1` to compress payloads on the wire.
+pub fn compression_enabled() -> bool {
+    std::env::var("COLLECTOR_COMPRESS").map(|value| value.trim() == "1").unwrap_or(false)
+}
+
+/// Encodes commands for the send queue, compressing them if asked to and
+/// keeping track of how much that saves. With a key, every frame is signed
+/// so the server knows it's us.
+pub struct Encoder {
+    compress: bool,
+    hmac_key: Option<Vec<u8>>,
+    frames: u64,
+    plain_bytes: u64,
+    sent_bytes: u64,
+}
+
+impl Encoder {
+    pub fn new(compress: bool, hmac_key: Option<Vec<u8>>) -> Self {
+        Self { compress, hmac_key, frames: 0, plain_bytes: 0, sent_bytes: 0 }
+    }
+
+    /// Sign a frame, if we have a key
+    fn sign(&self, frame: Vec<u8>) -> Vec<u8> {
+        match &self.hmac_key {
+            Some(key) => shared_v3::sign_frame(frame, key),
+            None => frame,
+        }
+    }
+
+    pub fn encode(&mut self, command: &shared_v3::CollectorCommandV1) -> Vec<u8> {
+        if !self.compress {
+            return self.sign(shared_v3::encode_v1(command));
         }
-        let bytes_read = stream.read(&mut buf).map_err(|_| CollectorError::UnableToReceiveData)?;
+        let encoded = shared_v3::encode_v1_compressed(command);
+        self.frames += 1;
+        self.plain_bytes += shared_v3::encode_v1(command).len() as u64;
+        self.sent_bytes += encoded.len() as u64;
+        if self.frames % COMPRESSION_REPORT_INTERVAL == 0 {
+            println!(
+                "Compression: {} frames, {} bytes down to {} ({:.2}x)",
+                self.frames, self.plain_bytes, self.sent_bytes, self.ratio()
+            );
+        }
+        self.sign(encoded)
+    }
+
+    /// Encode queued commands as one frame, each keeping its own timestamp
+    pub fn encode_batch(&self, commands: &[(u32, shared_v3::CollectorCommandV1)]) -> Vec<u8> {
+        if self.compress {
+            self.sign(shared_v3::encode_batch_v1_compressed(commands))
+        } else {
+            self.sign(shared_v3::encode_batch_v1(commands))
+        }
+    }
+
+    /// Uncompressed size over sent size - above 1.0 means we're saving bytes
+    pub fn ratio(&self) -> f64 {
+        if self.sent_bytes == 0 {
+            1.0
+        } else {
+            self.plain_bytes as f64 / self.sent_bytes as f64
+        }
+    }
+}
+
+/// How long to wait before trying the server again, doubling after each
+/// failure. The jitter stops a fleet of collectors from all coming back at
+/// the same moment when the server recovers.
+pub struct Backoff {
+    base: Duration,
+    current: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, current: base, max }
+    }
+
+    /// The delay before the next attempt: the current delay, give or take
+    /// 20%. The one after will be twice as long, up to `max`.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current.mul_f64(1.0 + jitter() * 0.2);
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    /// Back to the base delay, after a successful send
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+}
+
+/// A random number between -1 and 1. `RandomState` is seeded differently
+/// each time, which is plenty random for spreading out retries.
+fn jitter() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (random as f64 / u64::MAX as f64) * 2.0 - 1.0
+}
+
+/// Act on a task from the server - either the answer to a work request, or
+/// one it pushed to us. A `Shutdown` task raises the `shutdown` flag, which
+/// stops the collector.
+pub fn handle_work(work: &CollectorResponseV1, shutdown: &AtomicBool) {
+    if let CollectorResponseV1::Task(TaskType::Shutdown) | CollectorResponseV1::Push(TaskType::Shutdown) = work {
+        println!("Shutdown requested by the server");
+        shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Connect to the server, giving up on any read that takes longer than
+/// `reply_timeout` - a server that stays quiet mustn't hang the collector.
+fn connect(server: &str, reply_timeout: Duration) -> Result<TcpStream, CollectorError> {
+    let stream = TcpStream::connect(server)
+        .map_err(|_| CollectorError::UnableToConnect)?;
+    stream.set_read_timeout(Some(reply_timeout)).map_err(|_| CollectorError::UnableToConnect)?;
+    Ok(stream)
+}
+
+/// Wait for the reply to what we just sent, acting on any tasks the server
+/// pushes in the meantime.
+fn read_reply(stream: &mut TcpStream, buf: &mut [u8], shutdown: &AtomicBool) -> Result<CollectorResponseV1, CollectorError> {
+    loop {
+        let bytes_read = stream.read(buf).map_err(|_| CollectorError::UnableToReceiveData)?;
         if bytes_read == 0 {
-            queue.push_front(command);
             return Err(CollectorError::UnableToReceiveData);
         }
-        let ack = decode_response_v1(&buf[0..bytes_read]);
-        if ack != CollectorResponseV1::Ack {
-            queue.push_front(command);
+        let mut reply = None;
+        for response in decode_responses_v1(&buf[0..bytes_read]) {
+            match response {
+                CollectorResponseV1::Push(_) => handle_work(&response, shutdown),
+                _ => reply = Some(response),
+            }
+        }
+        if let Some(reply) = reply {
+            return Ok(reply);
+        }
+    }
+}
+
+/// Send the frames left over from the last run over a fresh connection,
+/// waiting until the server has acknowledged every one before forgetting
+/// them. Returns how many were replayed. If the server stops answering, or
+/// we're told to stop, the frames it hasn't acknowledged stay spooled.
+pub fn replay_backlog(server: &str, ring: &RingBuffer, shutdown: &AtomicBool) -> Result<usize, CollectorError> {
+    replay(server, ring, shutdown, REPLY_TIMEOUT)
+}
+
+fn replay(server: &str, ring: &RingBuffer, shutdown: &AtomicBool, reply_timeout: Duration) -> Result<usize, CollectorError> {
+    let mut stream = connect(server, reply_timeout)?;
+    let replayed = crate::ring_buffer::replay_spool(&mut stream, ring.path()).map_err(|_| CollectorError::UnableToSendData)?;
+
+    // The acks can arrive several to a read, in the order the frames went
+    let mut buf = vec![0u8; 512];
+    let mut acked = 0;
+    let mut result = Ok(replayed);
+    while acked < replayed && result.is_ok() {
+        if shutdown.load(Ordering::Relaxed) {
+            result = Err(CollectorError::UnableToReceiveData);
+            break;
+        }
+        let bytes_read = match stream.read(&mut buf) {
+            Ok(0) | Err(_) => {
+                result = Err(CollectorError::UnableToReceiveData);
+                break;
+            }
+            Ok(n) => n,
+        };
+        for response in decode_responses_v1(&buf[0..bytes_read]) {
+            match response {
+                CollectorResponseV1::Ack => acked += 1,
+                CollectorResponseV1::Push(_) => handle_work(&response, shutdown),
+                _ => result = Err(CollectorError::UnableToReceiveData),
+            }
+        }
+    }
+
+    // Forget what the server has, and keep the rest for next time
+    if acked >= replayed && replayed > 0 {
+        std::fs::remove_file(ring.path()).map_err(|_| CollectorError::UnableToSendData)?;
+    } else if acked > 0 {
+        let mut unacked = ring.load();
+        unacked.drain(..acked.min(unacked.len()));
+        ring.save(&mut unacked).map_err(|_| CollectorError::UnableToSendData)?;
+    }
+    result
+}
+
+/// The most queued frames to send as one batch
+const MAX_BATCH_SIZE: usize = 32;
+
+/// The next frame to send, and how many queued frames it covers. A backlog
+/// goes out as batches, with one reply each instead of one per frame.
+/// Anything we can't decode (like a frame from an older version) goes out
+/// on its own, as it is.
+fn next_frame(queue: &VecDeque<Vec<u8>>, encoder: &Encoder) -> (Vec<u8>, usize) {
+    let commands: Vec<(u32, shared_v3::CollectorCommandV1)> = queue
+        .iter()
+        .take(MAX_BATCH_SIZE)
+        .map_while(|frame| shared_v3::try_decode_v1(frame).ok().map(|(timestamp, command, _)| (timestamp, command)))
+        .collect();
+    if commands.len() < 2 {
+        (queue[0].clone(), 1)
+    } else {
+        (encoder.encode_batch(&commands), commands.len())
+    }
+}
+
+pub fn send_queue(
+    queue: &mut VecDeque<Vec<u8>>,
+    encoder: &Encoder,
+    server: &str,
+    collector_id: u128,
+    shutdown: &AtomicBool,
+) -> Result<(), CollectorError> {
+    // Connect
+    let mut stream = connect(server, REPLY_TIMEOUT)?;
+
+    // Send every queue item. They only leave the queue once they're acknowledged.
+    let mut buf = vec![0u8; 512];
+    while !queue.is_empty() {
+        let (frame, count) = next_frame(queue, encoder);
+        stream.write_all(&frame).map_err(|_| CollectorError::UnableToSendData)?;
+        if read_reply(&mut stream, &mut buf, shutdown)? != CollectorResponseV1::Ack {
             return Err(CollectorError::UnableToReceiveData);
-        } else {
-            println!("Ack received");
         }
+        println!("Ack received");
+        queue.drain(..count);
     }
 
     // Ask for work
-    let bytes = shared_v3::encode_v1(&shared_v3::CollectorCommandV1::RequestWork(collector_id));
-    if stream.write_all(&bytes).is_err() {
-        return Err(CollectorError::UnableToSendData);
+    match shared_v3::request_work(&mut stream, collector_id, encoder.hmac_key.as_deref()) {
+        Ok(Some(task)) => handle_work(&CollectorResponseV1::Task(task), shutdown),
+        Ok(None) => {}
+        Err(ProtocolError::Send(_)) => return Err(CollectorError::UnableToSendData),
+        Err(e) => {
+            println!("Work request failed: {e}");
+            return Err(CollectorError::UnableToReceiveData);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the server is still there, when we haven't sent anything for a while.
+pub fn send_ping(encoder: &Encoder, server: &str, collector_id: u128, shutdown: &AtomicBool) -> Result<(), CollectorError> {
+    let mut stream = connect(server, REPLY_TIMEOUT)?;
+
+    // Any number will do, as long as it comes back
+    let nonce = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.subsec_nanos())
+        .unwrap_or(0);
+    let bytes = encoder.sign(shared_v3::encode_v1(&shared_v3::CollectorCommandV1::Ping { collector_id, nonce }));
+    stream.write_all(&bytes).map_err(|_| CollectorError::UnableToSendData)?;
+
+    let mut buf = vec![0u8; 512];
+    match read_reply(&mut stream, &mut buf, shutdown)? {
+        CollectorResponseV1::Pong(n) if n == nonce => Ok(()),
+        _ => Err(CollectorError::UnableToReceiveData),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backlogs_are_batched() {
+        let ping = |nonce| shared_v3::CollectorCommandV1::Ping { collector_id: 1, nonce };
+        let mut queue: VecDeque<Vec<u8>> = (0..40).map(|nonce| shared_v3::encode_v1(&ping(nonce))).collect();
+        let encoder = Encoder::new(false, None);
+
+        // A full batch, then the rest
+        let (frame, count) = next_frame(&queue, &encoder);
+        assert_eq!(count, MAX_BATCH_SIZE);
+        let commands: Vec<_> = shared_v3::decode_batch_v1(&frame).into_iter().map(|(_, command)| command).collect();
+        assert_eq!(commands, (0..32).map(ping).collect::<Vec<_>>());
+
+        // With a key, the batch is signed like any other frame
+        let key = b"a key for testing";
+        let (signed, _) = next_frame(&queue, &Encoder::new(false, Some(key.to_vec())));
+        let unsigned = shared_v3::verify_frame(&signed, key).unwrap();
+        assert_eq!(shared_v3::decode_batch_v1(unsigned), shared_v3::decode_batch_v1(&frame));
+        queue.drain(..count);
+        assert_eq!(next_frame(&queue, &encoder).1, 8);
+
+        // A lone frame, or one we can't decode, is sent as it is
+        let lone = VecDeque::from(vec![queue[0].clone()]);
+        assert_eq!(next_frame(&lone, &encoder), (queue[0].clone(), 1));
+        queue.push_front(vec![1, 2, 3]);
+        assert_eq!(next_frame(&queue, &encoder), (vec![1, 2, 3], 1));
     }
-    let bytes_read = stream.read(&mut buf).map_err(|_| CollectorError::UnableToReceiveData)?;
-    if bytes_read == 0 {
-        return Err(CollectorError::UnableToReceiveData);
+
+    #[test]
+    fn batches_keep_each_timestamp() {
+        // Frames queued up while the server was away, one a minute
+        let sample = |used_memory| shared_v3::CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory,
+            cpu_usage: vec![12.5; 4],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        let queued = [(1_686_000_000, sample(10)), (1_686_000_060, sample(20))];
+        let queue: VecDeque<Vec<u8>> = queued
+            .iter()
+            .map(|(timestamp, command)| {
+                // The CRC only covers the payload, so the time can be changed
+                let mut frame = shared_v3::encode_v1_compressed(command);
+                frame[4..8].copy_from_slice(&timestamp.to_be_bytes());
+                frame
+            })
+            .collect();
+
+        // A compressing collector compresses its batches too
+        let (frame, count) = next_frame(&queue, &Encoder::new(true, None));
+        assert_eq!(count, 2);
+        assert_eq!(shared_v3::decode_batch_v1(&frame), queued);
+        assert!(frame.len() < shared_v3::encode_batch_v1(&queued).len());
     }
-    let work = decode_response_v1(&buf[0..bytes_read]);
-    match work {
-        CollectorResponseV1::NoWork => {}
-        CollectorResponseV1::Task(task) => {
-            println!("Task received: {task:?}");
+
+    #[test]
+    fn compressed_frames_decode() {
+        let command = shared_v3::CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory: 50,
+            cpu_usage: vec![12.5; 32],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        let mut encoder = Encoder::new(true, None);
+        let frame = encoder.encode(&command);
+        assert_eq!(shared_v3::decode_v1(&frame).1, command);
+        assert!(encoder.ratio() > 1.0);
+
+        // Without compression, frames are exactly as before
+        let mut encoder = Encoder::new(false, None);
+        assert_eq!(encoder.encode(&command).len(), shared_v3::encode_v1(&command).len());
+    }
+
+    #[test]
+    fn a_quiet_server_leaves_the_rest_spooled() {
+        let directory = tempfile::tempdir().unwrap();
+        let ring = RingBuffer::new(directory.path().join("ring"), 10);
+        let ping = |nonce| shared_v3::encode_v1(&shared_v3::CollectorCommandV1::Ping { collector_id: 1, nonce });
+        let mut unsent = VecDeque::from(vec![ping(1), ping(2), ping(3)]);
+        ring.save(&mut unsent).unwrap();
+
+        // A server that acknowledges the first frame, then goes quiet
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let quiet_server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            shared_v3::read_frame(&mut socket).unwrap();
+            socket.write_all(&shared_v3::encode_response_v1(CollectorResponseV1::Ack)).unwrap();
+            let _ = socket.read_to_end(&mut Vec::new());
+        });
+
+        let shutdown = AtomicBool::new(false);
+        assert!(replay(&server, &ring, &shutdown, Duration::from_millis(200)).is_err());
+        quiet_server.join().unwrap();
+        assert_eq!(ring.load(), VecDeque::from(vec![ping(2), ping(3)]));
+    }
+
+    #[test]
+    fn backoff_grows_to_the_cap_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let within_jitter = |delay: Duration, expected: u64| {
+            let expected = Duration::from_secs(expected);
+            delay >= expected.mul_f64(0.8) && delay <= expected.mul_f64(1.2)
+        };
+
+        for expected in [1, 2, 4, 8, 10, 10] {
+            assert_eq!(backoff.current, Duration::from_secs(expected));
+            assert!(within_jitter(backoff.next_delay(), expected));
         }
-        _ => {}
+
+        backoff.reset();
+        assert!(within_jitter(backoff.next_delay(), 1));
+        assert_eq!(backoff.current, Duration::from_secs(2));
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn shutdown_task_raises_the_flag() {
+        let shutdown = AtomicBool::new(false);
+        handle_work(&CollectorResponseV1::NoWork, &shutdown);
+        assert!(!shutdown.load(Ordering::Relaxed));
+
+        handle_work(&CollectorResponseV1::Task(TaskType::Shutdown), &shutdown);
+        assert!(shutdown.load(Ordering::Relaxed));
+
+        // A pushed shutdown works the same way
+        let shutdown = AtomicBool::new(false);
+        handle_work(&CollectorResponseV1::Push(TaskType::Shutdown), &shutdown);
+        assert!(shutdown.load(Ordering::Relaxed));
+    }
+}
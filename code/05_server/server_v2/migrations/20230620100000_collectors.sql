@@ -0,0 +1,6 @@
+-- One row per collector, so we know which ones are alive
+CREATE TABLE IF NOT EXISTS collectors
+(
+    collector_id VARCHAR(255) PRIMARY KEY NOT NULL,
+    last_seen    TIMESTAMP
+);
@@ -0,0 +1,4 @@
+-- What the collector told us about its host when it said hello
+ALTER TABLE collectors ADD COLUMN os TEXT;
+ALTER TABLE collectors ADD COLUMN arch TEXT;
+ALTER TABLE collectors ADD COLUMN cores INTEGER;
@@ -0,0 +1,8 @@
+-- The usage of each core for a sample, so busy cores aren't hidden by the average
+CREATE TABLE IF NOT EXISTS timeseries_cores
+(
+    sample_id INTEGER NOT NULL,
+    core      INTEGER NOT NULL,
+    cpu       FLOAT NOT NULL,
+    PRIMARY KEY (sample_id, core)
+);
@@ -1,6 +1,6 @@
-use axum::{Extension, Json, extract::Path};
+use axum::{Extension, Json, extract::{Path, Query}, http::StatusCode};
 use sqlx::FromRow;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(FromRow, Debug, Serialize)]
 pub struct DataPoint {
@@ -12,40 +12,619 @@ pub struct DataPoint {
     average_cpu: f32,
 }
 
-pub async fn show_all(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<DataPoint>> {
-    let rows = sqlx::query_as::<_, DataPoint>("SELECT * FROM timeseries")
+// The id column was declared SERIAL, which SQLite doesn't fill in - so use the rowid
+const DATA_POINT_COLUMNS: &str = "rowid AS id, collector_id, received, total_memory, used_memory, average_cpu";
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Which part of a long list to return
+#[derive(Debug, Default, Deserialize)]
+pub struct Paging {
+    limit: Option<i64>,
+    offset: Option<i64>,
+    /// Only rows received at or after this time (Unix seconds)
+    since: Option<i64>,
+}
+
+/// One page of results, with the paging that was actually used - the
+/// limit may have been capped.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    limit: i64,
+    offset: i64,
+    since: Option<i64>,
+    rows: Vec<T>,
+}
+
+pub async fn show_all(Extension(pool): Extension<sqlx::SqlitePool>, Query(paging): Query<Paging>) -> Json<Page<DataPoint>> {
+    let limit = paging.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = paging.offset.unwrap_or(0).max(0);
+    let sql = format!(
+        "SELECT {DATA_POINT_COLUMNS} FROM timeseries WHERE (?1 IS NULL OR received >= ?1) ORDER BY received, rowid LIMIT ?2 OFFSET ?3"
+    );
+    let rows = sqlx::query_as::<_, DataPoint>(&sql)
+        .bind(paging.since)
+        .bind(limit)
+        .bind(offset)
         .fetch_all(&pool)
         .await
         .unwrap();
 
-    Json(rows)
+    Json(Page { limit, offset, since: paging.since, rows })
 }
 
 #[derive(FromRow, Debug, Serialize)]
 pub struct Collector {
-    id: i32,
     collector_id: String,
     last_seen: i64,
+    os: Option<String>,
+    arch: Option<String>,
+    cores: Option<i64>,
 }
 
+// A collector may have sent data, said hello/pinged, or both
+const COLLECTORS_SQL: &str = "SELECT 
+    ids.collector_id, 
+    MAX(
+        COALESCE((SELECT MAX(received) FROM timeseries WHERE collector_id = ids.collector_id), 0),
+        COALESCE(c.last_seen, 0)
+    ) AS last_seen,
+    c.os,
+    c.arch,
+    c.cores
+    FROM (SELECT collector_id FROM timeseries UNION SELECT collector_id FROM collectors) ids
+    LEFT JOIN collectors c ON c.collector_id = ids.collector_id";
+
 pub async fn show_collectors(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<Collector>> {
-    const SQL: &str = "SELECT 
-    DISTINCT(id) AS id, 
-    collector_id, 
-    (SELECT MAX(received) FROM timeseries WHERE collector_id = ts.collector_id) AS last_seen 
-    FROM timeseries ts";
-    Json(sqlx::query_as::<_, Collector>(SQL)
+    Json(sqlx::query_as::<_, Collector>(COLLECTORS_SQL)
         .fetch_all(&pool)
         .await
         .unwrap())
 }
 
-pub async fn collector_data(Extension(pool): Extension<sqlx::SqlitePool>, uuid: Path<String>) -> Json<Vec<DataPoint>> {
-    let rows = sqlx::query_as::<_, DataPoint>("SELECT * FROM timeseries WHERE collector_id = ? ORDER BY received")
+/// A collector counts as online if we've heard from it this recently
+const ONLINE_WINDOW_SECONDS: i64 = 30;
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
+
+/// The collector registry, for tools rather than the web page
+#[derive(Debug, Serialize)]
+pub struct CollectorInfo {
+    collector_id: String,
+    last_seen: i64,
+    online: bool,
+    os: Option<String>,
+    arch: Option<String>,
+    cores: Option<i64>,
+}
+
+pub async fn collectors_json(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<CollectorInfo>> {
+    let now = unix_now();
+    let collectors = sqlx::query_as::<_, Collector>(COLLECTORS_SQL)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+    Json(collectors
+        .into_iter()
+        .map(|collector| CollectorInfo {
+            online: now - collector.last_seen <= ONLINE_WINDOW_SECONDS,
+            collector_id: collector.collector_id,
+            last_seen: collector.last_seen,
+            os: collector.os,
+            arch: collector.arch,
+            cores: collector.cores,
+        })
+        .collect())
+}
+
+/// Whether a collector is still sending samples
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CollectorStatus {
+    collector_id: String,
+    last_sample: i64,
+    online: bool,
+}
+
+/// Red/green status for every collector that has sent a sample. Unlike
+/// `collectors_json`, pings don't count - only data does.
+pub async fn collector_status(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<CollectorStatus>> {
+    let now = unix_now();
+    let rows = sqlx::query_as::<_, (String, i64)>(
+        "SELECT collector_id, MAX(received) FROM timeseries GROUP BY collector_id ORDER BY collector_id",
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+
+    Json(rows
+        .into_iter()
+        .map(|(collector_id, last_sample)| CollectorStatus {
+            collector_id,
+            last_sample,
+            online: now - last_sample <= ONLINE_WINDOW_SECONDS,
+        })
+        .collect())
+}
+
+/// How far back `collector_data` looks when it isn't told
+const DEFAULT_TIME_RANGE_SECONDS: i64 = 60 * 60;
+
+/// Which samples to return (Unix seconds, inclusive). `until` defaults to
+/// now and `since` to an hour before `until`. Ask for `since=0` and a far
+/// off `until` to get everything.
+#[derive(Debug, Default, Deserialize)]
+pub struct TimeRange {
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl TimeRange {
+    fn resolve(&self, now: i64) -> Result<(i64, i64), String> {
+        let until = self.until.unwrap_or(now);
+        let since = self.since.unwrap_or(until.saturating_sub(DEFAULT_TIME_RANGE_SECONDS));
+        if since > until {
+            return Err(format!("since ({since}) must not be after until ({until})"));
+        }
+        Ok((since, until))
+    }
+}
+
+/// Average the samples in each `bucket` seconds, rather than returning
+/// every one - an hour of one second samples is a lot for a chart.
+#[derive(Debug, Default, Deserialize)]
+pub struct Downsample {
+    bucket: Option<i64>,
+}
+
+// Each bucket is labelled with its start time, and the lowest id in it
+const BUCKETED_DATA_SQL: &str = "SELECT
+    MIN(rowid) AS id,
+    collector_id,
+    (received / ?4) * ?4 AS received,
+    CAST(AVG(total_memory) AS INTEGER) AS total_memory,
+    CAST(AVG(used_memory) AS INTEGER) AS used_memory,
+    AVG(average_cpu) AS average_cpu
+    FROM timeseries
+    WHERE collector_id = ?1 AND received BETWEEN ?2 AND ?3
+    GROUP BY received / ?4
+    ORDER BY received";
+
+pub async fn collector_data(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    uuid: Path<String>,
+    Query(range): Query<TimeRange>,
+    Query(downsample): Query<Downsample>,
+) -> Result<Json<Vec<DataPoint>>, (StatusCode, Json<ApiError>)> {
+    let bad_request = |error| (StatusCode::BAD_REQUEST, Json(ApiError { error }));
+    let (since, until) = range.resolve(unix_now()).map_err(bad_request)?;
+    let sql = match downsample.bucket {
+        None => format!("SELECT {DATA_POINT_COLUMNS} FROM timeseries WHERE collector_id = ?1 AND received BETWEEN ?2 AND ?3 ORDER BY received"),
+        Some(bucket) if bucket > 0 => BUCKETED_DATA_SQL.to_string(),
+        Some(bucket) => return Err(bad_request(format!("bucket ({bucket}) must be at least one second"))),
+    };
+    let rows = sqlx::query_as::<_, DataPoint>(&sql)
         .bind(uuid.as_str())
+        .bind(since)
+        .bind(until)
+        .bind(downsample.bucket)
         .fetch_all(&pool)
         .await
         .unwrap();
 
-    Json(rows)
-}
\ No newline at end of file
+    Ok(Json(rows))
+}
+
+/// How many rows `delete_collector` removed
+#[derive(Debug, Serialize)]
+pub struct Deleted {
+    deleted: u64,
+}
+
+/// The body of an error response
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    error: String,
+}
+
+/// Forget a collector: its samples (and their per-core usage) and its
+/// registry entry. The count is of samples plus registry rows.
+pub async fn delete_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    uuid: Path<String>,
+) -> Result<Json<Deleted>, (StatusCode, Json<ApiError>)> {
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, Json(ApiError { error: e.to_string() }));
+
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    sqlx::query("DELETE FROM timeseries_cores WHERE sample_id IN (SELECT rowid FROM timeseries WHERE collector_id = ?)")
+        .bind(uuid.as_str())
+        .execute(&mut tx)
+        .await
+        .map_err(internal_error)?;
+    let mut deleted = 0;
+    for sql in ["DELETE FROM timeseries WHERE collector_id = ?", "DELETE FROM collectors WHERE collector_id = ?"] {
+        deleted += sqlx::query(sql)
+            .bind(uuid.as_str())
+            .execute(&mut tx)
+            .await
+            .map_err(internal_error)?
+            .rows_affected();
+    }
+    tx.commit().await.map_err(internal_error)?;
+
+    if deleted == 0 {
+        let error = format!("no collector {}", uuid.as_str());
+        return Err((StatusCode::NOT_FOUND, Json(ApiError { error })));
+    }
+    Ok(Json(Deleted { deleted }))
+}
+
+/// The usage of each core for one sample
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CoreSample {
+    id: i64,
+    received: i64,
+    cpu: Vec<f32>,
+}
+
+/// Per-core usage for every sample from a collector, to see which cores
+/// were busy behind an average.
+pub async fn collector_cores(Extension(pool): Extension<sqlx::SqlitePool>, uuid: Path<String>) -> Json<Vec<CoreSample>> {
+    let rows = sqlx::query_as::<_, (i64, i64, f32)>(
+        "SELECT t.rowid, t.received, c.cpu FROM timeseries t \
+        JOIN timeseries_cores c ON c.sample_id = t.rowid \
+        WHERE t.collector_id = ? ORDER BY t.received, t.rowid, c.core",
+    )
+    .bind(uuid.as_str())
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+
+    // One row per core, so gather them up into samples
+    let mut samples: Vec<CoreSample> = Vec::new();
+    for (id, received, cpu) in rows {
+        match samples.last_mut() {
+            Some(sample) if sample.id == id => sample.cpu.push(cpu),
+            _ => samples.push(CoreSample { id, received, cpu: vec![cpu] }),
+        }
+    }
+    Json(samples)
+}
+
+/// Optional time window (Unix seconds, inclusive) for the stats endpoint
+#[derive(Debug, Default, Deserialize)]
+pub struct StatsWindow {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+/// Summary statistics for one collector. The aggregates are `None` if
+/// there are no samples in the window.
+#[derive(FromRow, Debug, Serialize)]
+pub struct CollectorStats {
+    #[serde(skip)]
+    known: bool,
+    samples: i64,
+    min_cpu: Option<f64>,
+    max_cpu: Option<f64>,
+    avg_cpu: Option<f64>,
+    min_used_memory: Option<i64>,
+    max_used_memory: Option<i64>,
+    avg_used_memory: Option<f64>,
+}
+
+// One query: the aggregates, plus whether we've ever heard of the collector
+const STATS_SQL: &str = "SELECT
+    EXISTS(SELECT 1 FROM timeseries WHERE collector_id = ?1)
+        OR EXISTS(SELECT 1 FROM collectors WHERE collector_id = ?1) AS known,
+    COUNT(*) AS samples,
+    MIN(average_cpu) AS min_cpu,
+    MAX(average_cpu) AS max_cpu,
+    AVG(average_cpu) AS avg_cpu,
+    MIN(used_memory) AS min_used_memory,
+    MAX(used_memory) AS max_used_memory,
+    AVG(used_memory) AS avg_used_memory
+    FROM timeseries
+    WHERE collector_id = ?1
+    AND (?2 IS NULL OR received >= ?2)
+    AND (?3 IS NULL OR received <= ?3)";
+
+pub async fn collector_stats(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    uuid: Path<String>,
+    Query(window): Query<StatsWindow>,
+) -> Result<Json<CollectorStats>, StatusCode> {
+    let stats = sqlx::query_as::<_, CollectorStats>(STATS_SQL)
+        .bind(uuid.as_str())
+        .bind(window.from)
+        .bind(window.to)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    if !stats.known {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(stats))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn collectors_json_shows_online_status() {
+        let pool = crate::test_pool().await;
+        let now = unix_now();
+        sqlx::query("INSERT INTO collectors (collector_id, last_seen, os, arch, cores) VALUES ('recent', ?, 'linux', 'x86_64', 4)")
+            .bind(now - 5)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ('old', ?, 100, 50, 1.0)")
+            .bind(now - 3600)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let Json(collectors) = collectors_json(Extension(pool)).await;
+        assert_eq!(collectors.len(), 2);
+
+        let recent = collectors.iter().find(|c| c.collector_id == "recent").unwrap();
+        assert!(recent.online);
+        assert_eq!(recent.last_seen, now - 5);
+        assert_eq!(recent.os.as_deref(), Some("linux"));
+        assert_eq!(recent.cores, Some(4));
+
+        let old = collectors.iter().find(|c| c.collector_id == "old").unwrap();
+        assert!(!old.online);
+        assert_eq!(old.os, None);
+
+        // Check the JSON shape tools will see
+        let json = serde_json::to_value(recent).unwrap();
+        for field in ["collector_id", "last_seen", "online", "os", "arch", "cores"] {
+            assert!(json.get(field).is_some(), "missing {field}");
+        }
+    }
+
+    #[tokio::test]
+    async fn show_all_pages() {
+        let pool = crate::test_pool().await;
+        for received in 1..=5 {
+            sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ('c1', ?, 100, 50, 1.0)")
+                .bind(received * 100)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let received = |page: &Page<DataPoint>| page.rows.iter().map(|row| row.received).collect::<Vec<_>>();
+
+        let Json(page) = show_all(Extension(pool.clone()), Query(Paging::default())).await;
+        assert_eq!(received(&page), vec![100, 200, 300, 400, 500]);
+        assert_eq!((page.limit, page.offset, page.since), (DEFAULT_PAGE_SIZE, 0, None));
+
+        let paging = Paging { limit: Some(2), offset: Some(1), since: None };
+        let Json(page) = show_all(Extension(pool.clone()), Query(paging)).await;
+        assert_eq!(received(&page), vec![200, 300]);
+
+        let paging = Paging { limit: None, offset: None, since: Some(300) };
+        let Json(page) = show_all(Extension(pool.clone()), Query(paging)).await;
+        assert_eq!(received(&page), vec![300, 400, 500]);
+        assert_eq!(page.since, Some(300));
+
+        // Silly values are reined in
+        let paging = Paging { limit: Some(1_000_000), offset: Some(-5), since: None };
+        let Json(page) = show_all(Extension(pool), Query(paging)).await;
+        assert_eq!((page.limit, page.offset), (MAX_PAGE_SIZE, 0));
+        assert_eq!(page.rows.len(), 5);
+        assert!(page.rows.iter().all(|row| row.id > 0));
+    }
+
+    #[tokio::test]
+    async fn collector_status_from_samples() {
+        let pool = crate::test_pool().await;
+        let now = unix_now();
+        for (collector_id, received) in [("c1", now - 100), ("c1", now - 5), ("c2", now - 120), ("c2", now - 60)] {
+            sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, ?, 100, 50, 1.0)")
+                .bind(collector_id)
+                .bind(received)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let Json(status) = collector_status(Extension(pool)).await;
+        assert_eq!(
+            status,
+            vec![
+                CollectorStatus { collector_id: "c1".to_string(), last_sample: now - 5, online: true },
+                CollectorStatus { collector_id: "c2".to_string(), last_sample: now - 60, online: false },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn collector_data_time_range() {
+        let pool = crate::test_pool().await;
+        let now = unix_now();
+        for received in [100, now - 2 * 60 * 60, now - 60] {
+            sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ('c1', ?, 100, 50, 1.0)")
+                .bind(received)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let received = |range| {
+            let pool = pool.clone();
+            async move {
+                let Json(rows) = collector_data(Extension(pool), Path("c1".to_string()), Query(range), Query(Downsample::default()))
+                    .await
+                    .unwrap();
+                rows.into_iter().map(|row| row.received).collect::<Vec<i64>>()
+            }
+        };
+
+        // The last hour by default
+        assert_eq!(received(TimeRange::default()).await, vec![now - 60]);
+        assert_eq!(received(TimeRange { since: Some(50), until: Some(100) }).await, vec![100]);
+        // Everything, for dashboards that want it all
+        let everything = TimeRange { since: Some(0), until: Some(i64::MAX) };
+        assert_eq!(received(everything).await, vec![100, now - 2 * 60 * 60, now - 60]);
+
+        let range = TimeRange { since: Some(200), until: Some(100) };
+        let (status, Json(error)) = collector_data(Extension(pool), Path("c1".to_string()), Query(range), Query(Downsample::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.error, "since (200) must not be after until (100)");
+    }
+
+    #[tokio::test]
+    async fn collector_data_buckets() {
+        let pool = crate::test_pool().await;
+        let samples = [(100, 10, 1.0), (110, 30, 3.0), (125, 50, 5.0), (130, 70, 8.0), (139, 90, 8.0), (150, 20, 2.0)];
+        for (received, used_memory, cpu) in samples {
+            sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ('c1', ?, 100, ?, ?)")
+                .bind(received)
+                .bind(used_memory)
+                .bind(cpu)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let range = || Query(TimeRange { since: Some(0), until: Some(1000) });
+
+        let downsample = Query(Downsample { bucket: Some(20) });
+        let Json(rows) = collector_data(Extension(pool.clone()), Path("c1".to_string()), range(), downsample).await.unwrap();
+        let buckets: Vec<(i64, i64, i64, f32)> =
+            rows.iter().map(|row| (row.received, row.total_memory, row.used_memory, row.average_cpu)).collect();
+        assert_eq!(buckets, vec![(100, 100, 20, 2.0), (120, 100, 70, 7.0), (140, 100, 20, 2.0)]);
+
+        // Without a bucket, every sample comes back
+        let Json(rows) = collector_data(Extension(pool.clone()), Path("c1".to_string()), range(), Query(Downsample::default()))
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), samples.len());
+
+        let downsample = Query(Downsample { bucket: Some(0) });
+        let (status, _) = collector_data(Extension(pool), Path("c1".to_string()), range(), downsample).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn delete_collector_removes_its_history() {
+        let pool = crate::test_pool().await;
+        for (collector_id, received) in [("c1", 100), ("c1", 200), ("c2", 300)] {
+            sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, ?, 100, 50, 1.0)")
+                .bind(collector_id)
+                .bind(received)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("INSERT INTO timeseries_cores (sample_id, core, cpu) SELECT rowid, 0, 1.0 FROM timeseries")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO collectors (collector_id, last_seen) VALUES ('c1', 200)").execute(&pool).await.unwrap();
+
+        let Json(deleted) = delete_collector(Extension(pool.clone()), Path("c1".to_string())).await.unwrap();
+        assert_eq!(deleted.deleted, 3);
+
+        // Only c2 is left
+        let remaining: Vec<String> = sqlx::query_scalar("SELECT collector_id FROM timeseries UNION SELECT collector_id FROM collectors")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, vec!["c2".to_string()]);
+        let cores: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM timeseries_cores").fetch_one(&pool).await.unwrap();
+        assert_eq!(cores, 1);
+
+        // Deleting it again is a 404, not a 500
+        let (status, Json(error)) = delete_collector(Extension(pool), Path("c1".to_string())).await.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error.error, "no collector c1");
+    }
+
+    #[tokio::test]
+    async fn collector_cores_groups_by_sample() {
+        let pool = crate::test_pool().await;
+        for (sample_id, received, cpu) in [(1, 100, vec![1.0, 2.0]), (2, 200, vec![3.0, 4.0])] {
+            sqlx::query("INSERT INTO timeseries (rowid, collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, 'c1', ?, 100, 50, 0)")
+                .bind(sample_id)
+                .bind(received)
+                .execute(&pool)
+                .await
+                .unwrap();
+            for (core, cpu) in cpu.into_iter().enumerate() {
+                sqlx::query("INSERT INTO timeseries_cores (sample_id, core, cpu) VALUES (?, ?, ?)")
+                    .bind(sample_id)
+                    .bind(core as i64)
+                    .bind(cpu)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let Json(samples) = collector_cores(Extension(pool), Path("c1".to_string())).await;
+        assert_eq!(
+            samples,
+            vec![
+                CoreSample { id: 1, received: 100, cpu: vec![1.0, 2.0] },
+                CoreSample { id: 2, received: 200, cpu: vec![3.0, 4.0] },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn collector_stats_aggregates() {
+        let pool = crate::test_pool().await;
+        for (received, used_memory, cpu) in [(100, 10, 1.0), (200, 30, 3.0), (300, 50, 8.0)] {
+            sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ('c1', ?, 100, ?, ?)")
+                .bind(received)
+                .bind(used_memory)
+                .bind(cpu)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let Json(stats) = collector_stats(Extension(pool.clone()), Path("c1".to_string()), Query(StatsWindow::default()))
+            .await
+            .unwrap();
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.min_cpu, Some(1.0));
+        assert_eq!(stats.max_cpu, Some(8.0));
+        assert_eq!(stats.avg_cpu, Some(4.0));
+        assert_eq!(stats.min_used_memory, Some(10));
+        assert_eq!(stats.max_used_memory, Some(50));
+        assert_eq!(stats.avg_used_memory, Some(30.0));
+
+        // Only the last two samples
+        let window = StatsWindow { from: Some(150), to: None };
+        let Json(stats) = collector_stats(Extension(pool.clone()), Path("c1".to_string()), Query(window))
+            .await
+            .unwrap();
+        assert_eq!(stats.samples, 2);
+        assert_eq!(stats.min_cpu, Some(3.0));
+        assert_eq!(stats.avg_used_memory, Some(40.0));
+
+        // A window with nothing in it is fine, but a stranger isn't
+        let window = StatsWindow { from: Some(1000), to: Some(2000) };
+        let Json(stats) = collector_stats(Extension(pool.clone()), Path("c1".to_string()), Query(window))
+            .await
+            .unwrap();
+        assert_eq!(stats.samples, 0);
+        assert_eq!(stats.avg_cpu, None);
+
+        let missing = collector_stats(Extension(pool), Path("nobody".to_string()), Query(StatsWindow::default())).await;
+        assert_eq!(missing.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+}
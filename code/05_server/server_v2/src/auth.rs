@@ -0,0 +1,115 @@
+use auth_login_manager::LoginAction;
+use axum::{
+    extract::Form,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+const SESSION_COOKIE: &str = "session";
+
+/// Logged in users: session token -> username
+pub type Sessions = Arc<Mutex<HashMap<String, String>>>;
+
+pub async fn login_page() -> Html<String> {
+    let path = std::path::Path::new("src/login.html");
+    let content = tokio::fs::read_to_string(path).await.unwrap();
+    Html(content)
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+pub async fn login(Extension(sessions): Extension<Sessions>, Form(form): Form<LoginForm>) -> Response {
+    // Checking the password reads the users file, so keep it off the async threads
+    let username = form.username.clone();
+    let action = tokio::task::spawn_blocking(move || auth_login_manager::login(&username, &form.password))
+        .await
+        .unwrap();
+
+    match action.as_ref().and_then(LoginAction::granted_role) {
+        // Every role may see the dashboard
+        Some(_) => {
+            let token = uuid::Uuid::new_v4().to_string();
+            sessions.lock().unwrap().insert(token.clone(), form.username);
+            let cookie = format!("{SESSION_COOKIE}={token}; HttpOnly; SameSite=Strict; Path=/");
+            ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response()
+        }
+        // Don't tell the user whether it was the username or the password
+        _ => Redirect::to("/login?failed=1").into_response(),
+    }
+}
+
+/// Find our session token in the request's cookies
+fn session_token<B>(request: &Request<B>) -> Option<String> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Middleware: only let requests with a valid session through. API calls get
+/// a 401, people browsing the site are sent to the login page.
+pub async fn require_session<B>(
+    Extension(sessions): Extension<Sessions>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let logged_in = session_token(&request)
+        .map(|token| sessions.lock().unwrap().contains_key(&token))
+        .unwrap_or(false);
+
+    if logged_in {
+        next.run(request).await
+    } else if request.uri().path().starts_with("/api") {
+        StatusCode::UNAUTHORIZED.into_response()
+    } else {
+        Redirect::to("/login").into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn api_requires_a_session() {
+        let app = crate::app(crate::test_pool().await, Sessions::default());
+        let response = app
+            .oneshot(Request::builder().uri("/api/all").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn api_allows_a_session() {
+        let sessions = Sessions::default();
+        sessions.lock().unwrap().insert("test-token".to_string(), "admin".to_string());
+        let app = crate::app(crate::test_pool().await, sessions);
+
+        let request = Request::builder()
+            .uri("/api/all")
+            .header(header::COOKIE, format!("{SESSION_COOKIE}=test-token"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
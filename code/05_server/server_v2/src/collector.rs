@@ -1,55 +1,671 @@
-use std::net::SocketAddr;
-use shared_v3::{DATA_COLLECTOR_ADDRESS, decode_v1, CollectorCommandV1};
+use std::{net::{IpAddr, SocketAddr}, collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use shared_v3::{fragment::{self, Reassembler}, DATA_COLLECTOR_ADDRESS, CollectorCommandV1, encode_response_v1, CollectorResponseV1, DecodeError};
 use sqlx::{Pool, Sqlite};
-use tokio::{net::{TcpListener, TcpStream}, io::AsyncReadExt};
+use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}, task::JoinSet};
+use tokio_util::sync::CancellationToken;
 
-pub async fn data_collector(cnn: Pool<Sqlite>) -> anyhow::Result<()> {
+/// Give up on a fragmented frame if it isn't complete after this long
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 16;
+const DEFAULT_MAX_FRAMES_PER_SECOND: u32 = 50;
+
+/// How hard a single host may lean on the collector port.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    pub max_connections_per_ip: usize,
+    pub max_frames_per_second: u32,
+}
+
+impl RateLimits {
+    /// Read `COLLECTOR_MAX_CONNECTIONS_PER_IP` and `COLLECTOR_MAX_FRAMES_PER_SECOND`,
+    /// using the defaults for anything missing or invalid.
+    pub fn from_env() -> Self {
+        fn read<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+        }
+        Self {
+            max_connections_per_ip: read("COLLECTOR_MAX_CONNECTIONS_PER_IP", DEFAULT_MAX_CONNECTIONS_PER_IP),
+            max_frames_per_second: read("COLLECTOR_MAX_FRAMES_PER_SECOND", DEFAULT_MAX_FRAMES_PER_SECOND),
+        }
+    }
+}
+
+/// Counts the open connections from each address.
+#[derive(Clone)]
+struct ConnectionTracker {
+    limit: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// An open connection - the count goes back down when it's dropped.
+struct ConnectionSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionTracker {
+    fn new(limit: usize) -> Self {
+        Self { limit, open: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Claim a slot for `ip`, or `None` if it already has too many connections.
+    fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot { ip, open: self.open.clone() })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Counts the frames on one connection, in one second windows.
+struct FrameLimiter {
+    limit: u32,
+    window_start: Instant,
+    frames: u32,
+}
+
+impl FrameLimiter {
+    fn new(limit: u32) -> Self {
+        Self { limit, window_start: Instant::now(), frames: 0 }
+    }
+
+    /// Count a frame. Returns how long to wait before handling it, if the
+    /// connection is over its limit.
+    fn check(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.frames = 0;
+        }
+        self.frames += 1;
+        if self.frames > self.limit {
+            Some(Duration::from_secs(1).saturating_sub(elapsed))
+        } else {
+            None
+        }
+    }
+}
+
+/// How long to wait for open connections to finish when shutting down
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Accept collector connections until `shutdown` is cancelled. Then stop
+/// accepting, and give the open connections a chance to finish what
+/// they're sending before returning.
+pub async fn data_collector(cnn: Pool<Sqlite>, shutdown: CancellationToken) -> anyhow::Result<()> {
     // Listen for TCP connections on the data collector address
     let listener = TcpListener::bind(DATA_COLLECTOR_ADDRESS).await?;
+    let limits = RateLimits::from_env();
+    let tracker = ConnectionTracker::new(limits.max_connections_per_ip);
+    // If there's a key, every frame must be signed with it
+    let hmac_key = shared_v3::hmac_key_from_env();
+    let mut connections = JoinSet::new();
 
-    // Loop forever, accepting connections
+    // Loop until we're told to stop, accepting connections
     loop {
         // Wait for a new connection
         let cnn = cnn.clone();
-        let (socket, address) = listener.accept().await?;
-        tokio::spawn(new_connection(socket, address, cnn));
+        let (socket, address) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            // Tidy up after connections that have finished
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            accepted = listener.accept() => accepted?,
+        };
+        let Some(slot) = tracker.try_acquire(address.ip()) else {
+            // Dropping the socket closes it
+            println!("Too many connections from {}, rejecting", address.ip());
+            continue;
+        };
+        let hmac_key = hmac_key.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let flushed = new_connection(socket, address, cnn, limits.max_frames_per_second, hmac_key, shutdown).await;
+            drop(slot);
+            flushed
+        });
     }
+
+    // Let the open connections finish, but don't wait forever
+    let open = connections.len();
+    let mut flushed = 0;
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while let Some(result) = connections.join_next().await {
+            flushed += result.unwrap_or(0);
+        }
+    })
+    .await;
+    if drained.is_err() {
+        println!("Gave up waiting for {} connection(s)", connections.len());
+        connections.abort_all();
+    }
+    println!("Collector stopped: flushed {flushed} pending sample(s) from {open} open connection(s)");
+    Ok(())
 }
 
-async fn new_connection(mut socket: TcpStream, address: SocketAddr, cnn: Pool<Sqlite>) {
+/// Handle frames from one collector until it hangs up - or, once
+/// `shutdown` is cancelled, until it stops sending. Returns how many
+/// commands were handled after shutdown was requested.
+async fn new_connection(
+    mut socket: TcpStream,
+    address: SocketAddr,
+    cnn: Pool<Sqlite>,
+    max_frames_per_second: u32,
+    hmac_key: Option<Vec<u8>>,
+    shutdown: CancellationToken,
+) -> usize {
     let mut buf = vec![0u8; 1024];
+    let mut limiter = FrameLimiter::new(max_frames_per_second);
+    let mut reassembler = Reassembler::new(FRAGMENT_TIMEOUT);
+    let mut flushed = 0;
     loop {
-        let n = socket
-            .read(&mut buf)
-            .await
-            .expect("failed to read data from socket");
-
-        if n == 0 {
-            println!("No data received - connection closed");
-            return;
+        // Only stop between frames. Anything already on its way is handled
+        // first; anything unacknowledged, the collector will send again.
+        tokio::select! {
+            biased;
+            readable = socket.readable() => {
+                if readable.is_err() {
+                    return flushed;
+                }
+            }
+            _ = shutdown.cancelled() => return flushed,
         }
 
-        let received_data = decode_v1(&buf[0..n]);
+        // Read a whole frame, however the network splits it up
+        let (mut bytes, resyncing) = match shared_v3::read_frame_async(&mut socket).await {
+            Ok(frame) => (frame, false),
+            Err(DecodeError::Io(std::io::ErrorKind::UnexpectedEof)) => {
+                println!("No data received - connection closed");
+                return flushed;
+            }
+            Err(DecodeError::Io(kind)) => {
+                println!("Unable to read from {address:?}: {kind:?}");
+                return flushed;
+            }
+            // We can't check a signature without the whole frame
+            Err(e) if hmac_key.is_some() => {
+                println!("Bad frame from {address:?} ({e:?}), closing the connection");
+                return flushed;
+            }
+            Err(e) => {
+                // We've lost our place. Take whatever has arrived, and let the
+                // decoder skip ahead to the next frame in it.
+                println!("Bad frame header from {address:?} ({e:?}), resynchronizing");
+                match socket.read(&mut buf).await {
+                    Ok(0) | Err(_) => return flushed,
+                    Ok(n) => (buf[..n].to_vec(), true),
+                }
+            }
+        };
+
+        // Signed frames have the signature after them
+        if hmac_key.is_some() {
+            let frame_length = bytes.len();
+            bytes.resize(frame_length + shared_v3::HMAC_SIZE, 0);
+            if let Err(e) = socket.read_exact(&mut bytes[frame_length..]).await {
+                println!("Unable to read a frame signature from {address:?}: {e:?}");
+                return flushed;
+            }
+        }
 
-        match received_data {
-            (timestamp, CollectorCommandV1::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage }) => {
-                let collector_id = uuid::Uuid::from_u128(collector_id);
-                let collector_id = collector_id.to_string();
+        // Slow down anyone sending too fast
+        if let Some(wait) = limiter.check(Instant::now()) {
+            println!("{address:?} is sending too many frames, throttling");
+            tokio::time::sleep(wait).await;
+        }
 
-                let result = sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ($1, $2, $3, $4, $5)")
-                    .bind(collector_id)
-                    .bind(timestamp)
-                    .bind(total_memory as i64)
-                    .bind(used_memory as i64)
-                    .bind(average_cpu_usage)
-                    .execute(&cnn)
-                    .await;
+        let frame = match &hmac_key {
+            Some(key) => match shared_v3::verify_frame(&bytes, key) {
+                Some(frame) => frame,
+                None => {
+                    println!("Rejecting a frame with a bad signature from {address:?}");
+                    return flushed;
+                }
+            },
+            None => &bytes[..],
+        };
 
-                if result.is_err() {
-                    println!("Error inserting data into the database: {result:?}");
+        // Oversized frames arrive in pieces - acknowledge each one, and
+        // handle the frame once it's all here
+        let responses: Vec<CollectorResponseV1> = if !resyncing && fragment::is_fragment(frame) {
+            let now = Instant::now();
+            let dropped = reassembler.expire(now);
+            if dropped > 0 {
+                println!("Discarded {dropped} incomplete frame(s) from {address:?}");
+            }
+            // Acknowledge bad fragments anyway: sending the same garbage again won't help
+            let whole = match fragment::decode_fragment(frame) {
+                Ok(piece) => reassembler.add(piece, now).unwrap_or_else(|e| {
+                    println!("Dropping a fragment from {address:?}: {e:?}");
+                    None
+                }),
+                Err(e) => {
+                    println!("Dropping a bad fragment from {address:?}: {e:?}");
+                    None
                 }
+            };
+            match whole.map(|frame| shared_v3::try_decode_v1(&frame)) {
+                Some(Ok((timestamp, command, _))) => {
+                    if shutdown.is_cancelled() {
+                        flushed += 1;
+                    }
+                    handle_command(&cnn, timestamp, command).await.into_iter().collect()
+                }
+                Some(Err(e)) => {
+                    println!("Dropping a reassembled frame from {address:?}: {e:?}");
+                    vec![CollectorResponseV1::Ack]
+                }
+                None => vec![CollectorResponseV1::Ack],
+            }
+        } else {
+            // Skip past anything that isn't a valid frame, rather than giving up
+            let decoded = shared_v3::decode_frames_v1(frame);
+            if decoded.skipped > 0 {
+                println!("Skipped {} unreadable bytes from {address:?}", decoded.skipped);
+            }
+            if shutdown.is_cancelled() {
+                flushed += decoded.frames.iter().map(Vec::len).sum::<usize>();
+            }
+            let mut responses = Vec::new();
+            for commands in decoded.frames {
+                responses.extend(handle_frame(&cnn, commands).await);
             }
-            _ => {} // Do nothing
-        }        
+            responses
+        };
+        for response in responses {
+            let bytes = encode_response_v1(response);
+            if let Err(e) = socket.write_all(&bytes).await {
+                println!("Unable to reply to {address:?}: {e:?}");
+                return flushed;
+            }
+        }
+    }
+}
+
+/// What a collector told us about the machine it runs on
+struct HostInfo<'a> {
+    os: &'a str,
+    arch: &'a str,
+    cores: u32,
+}
+
+/// Record that a collector was seen at `last_seen`, adding it if it's new.
+/// A collector that reconnects updates its existing row. Host details are
+/// only replaced when we have new ones.
+async fn upsert_collector(cnn: &Pool<Sqlite>, collector_id: &str, last_seen: u32, host: Option<HostInfo<'_>>) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO collectors (collector_id, last_seen, os, arch, cores) VALUES ($1, $2, $3, $4, $5) \
+        ON CONFLICT(collector_id) DO UPDATE SET last_seen = excluded.last_seen, \
+        os = COALESCE(excluded.os, collectors.os), \
+        arch = COALESCE(excluded.arch, collectors.arch), \
+        cores = COALESCE(excluded.cores, collectors.cores)",
+    )
+    .bind(collector_id)
+    .bind(last_seen)
+    .bind(host.as_ref().map(|host| host.os))
+    .bind(host.as_ref().map(|host| host.arch))
+    .bind(host.as_ref().map(|host| host.cores))
+    .execute(cnn)
+    .await?;
+    Ok(())
+}
+
+/// Store a sample, along with the usage of each core for drilling down.
+async fn insert_sample(
+    cnn: &Pool<Sqlite>,
+    collector_id: &str,
+    received: u32,
+    total_memory: u64,
+    used_memory: u64,
+    average_cpu: f32,
+    per_core: &[f32],
+) -> sqlx::Result<()> {
+    let mut tx = cnn.begin().await?;
+    let sample_id = sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ($1, $2, $3, $4, $5)")
+        .bind(collector_id)
+        .bind(received)
+        .bind(total_memory as i64)
+        .bind(used_memory as i64)
+        .bind(average_cpu)
+        .execute(&mut tx)
+        .await?
+        .last_insert_rowid();
+    for (core, cpu) in per_core.iter().enumerate() {
+        sqlx::query("INSERT INTO timeseries_cores (sample_id, core, cpu) VALUES ($1, $2, $3)")
+            .bind(sample_id)
+            .bind(core as i64)
+            .bind(cpu)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+/// Act on every command in a frame, returning the one reply the frame gets.
+/// A batch is acknowledged as a whole, and only once every command in it
+/// has been handled - otherwise the collector sends all of it again. Each
+/// command is stored with its own timestamp.
+async fn handle_frame(cnn: &Pool<Sqlite>, mut commands: Vec<(u32, CollectorCommandV1)>) -> Option<CollectorResponseV1> {
+    if commands.len() == 1 {
+        let (timestamp, command) = commands.remove(0);
+        return handle_command(cnn, timestamp, command).await;
     }
-}
\ No newline at end of file
+    let mut handled = true;
+    for (timestamp, command) in commands {
+        handled &= handle_command(cnn, timestamp, command).await.is_some();
+    }
+    handled.then_some(CollectorResponseV1::Ack)
+}
+
+/// Act on a command from a collector, returning the reply (if there is one).
+async fn handle_command(cnn: &Pool<Sqlite>, timestamp: u32, command: CollectorCommandV1) -> Option<CollectorResponseV1> {
+    if let Err(e) = shared_v3::validate_command(&command) {
+        // Acknowledge it anyway: sending the same garbage again won't help
+        println!("Dropping an invalid command ({e}): {command:?}");
+        return Some(CollectorResponseV1::Ack);
+    }
+
+    match command {
+        // Old collectors only send the average, so that's all we can store
+        CollectorCommandV1::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage } => {
+            let collector_id = uuid::Uuid::from_u128(collector_id).to_string();
+            let result = insert_sample(cnn, &collector_id, timestamp, total_memory, used_memory, average_cpu_usage, &[]).await;
+
+            if result.is_err() {
+                println!("Error inserting data into the database: {result:?}");
+                None
+            } else {
+                Some(CollectorResponseV1::Ack)
+            }
+        }
+        CollectorCommandV1::SubmitDataV2 { collector_id, total_memory, used_memory, cpu_usage, .. } => {
+            let collector_id = uuid::Uuid::from_u128(collector_id).to_string();
+            // The average of the per-core numbers, so it always matches them
+            let average_cpu = shared_v3::average_cpu_usage(&cpu_usage);
+            let result = insert_sample(cnn, &collector_id, timestamp, total_memory, used_memory, average_cpu, &cpu_usage).await;
+
+            if result.is_err() {
+                println!("Error inserting data into the database: {result:?}");
+                None
+            } else {
+                Some(CollectorResponseV1::Ack)
+            }
+        }
+        CollectorCommandV1::Hello { collector_id, os, arch, cores } => {
+            let collector_id = uuid::Uuid::from_u128(collector_id).to_string();
+            let host = HostInfo { os: &os, arch: &arch, cores };
+            let result = upsert_collector(cnn, &collector_id, timestamp, Some(host)).await;
+
+            if result.is_err() {
+                println!("Error recording collector details: {result:?}");
+                None
+            } else {
+                Some(CollectorResponseV1::Ack)
+            }
+        }
+        // This server doesn't hand out work
+        CollectorCommandV1::RequestWork(_) => Some(CollectorResponseV1::NoWork),
+        CollectorCommandV1::Ping { collector_id, nonce } => {
+            // A ping means the collector is alive, even if it has nothing to report
+            let collector_id = uuid::Uuid::from_u128(collector_id).to_string();
+            let result = upsert_collector(cnn, &collector_id, timestamp, None).await;
+
+            if result.is_err() {
+                println!("Error recording a ping: {result:?}");
+            }
+            Some(CollectorResponseV1::Pong(nonce))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared_v3::decode_v1;
+
+    #[test]
+    fn connection_cap_per_address() {
+        let tracker = ConnectionTracker::new(2);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let first = tracker.try_acquire(ip).unwrap();
+        let _second = tracker.try_acquire(ip).unwrap();
+        assert!(tracker.try_acquire(ip).is_none());
+
+        // Other hosts aren't affected
+        assert!(tracker.try_acquire("10.0.0.2".parse().unwrap()).is_some());
+
+        // Closing a connection frees up a slot
+        drop(first);
+        assert!(tracker.try_acquire(ip).is_some());
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_throttled() {
+        let mut limiter = FrameLimiter::new(3);
+        let start = limiter.window_start;
+        for _ in 0..3 {
+            assert_eq!(limiter.check(start), None);
+        }
+        assert_eq!(limiter.check(start + Duration::from_millis(250)), Some(Duration::from_millis(750)));
+
+        // A new window starts afresh
+        assert_eq!(limiter.check(start + Duration::from_secs(1)), None);
+    }
+
+    #[tokio::test]
+    async fn shutdown_handles_frames_already_sent() {
+        let pool = crate::test_pool().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, address) = listener.accept().await.unwrap();
+
+        // A sample is on its way when we're told to stop
+        let command = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory: 50,
+            cpu_usage: vec![12.5],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        client.write_all(&shared_v3::encode_v1(&command)).await.unwrap();
+        socket.readable().await.unwrap();
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+
+        // It's stored, then the idle connection closes rather than waiting
+        let flushed = new_connection(socket, address, pool.clone(), 50, None, shutdown).await;
+        assert_eq!(flushed, 1);
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM timeseries").fetch_one(&pool).await.unwrap();
+        assert_eq!(rows, 1);
+    }
+
+    #[tokio::test]
+    async fn a_batch_gets_one_ack() {
+        let pool = crate::test_pool().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, address) = listener.accept().await.unwrap();
+
+        let sample = |used_memory| CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory,
+            cpu_usage: vec![12.5],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        let batch = [(100, sample(10)), (101, sample(20)), (102, sample(30))];
+        client.write_all(&shared_v3::encode_batch_v1(&batch)).await.unwrap();
+        client.write_all(&shared_v3::encode_batch_v1_compressed(&[(103, sample(40))])).await.unwrap();
+        client.shutdown().await.unwrap();
+        new_connection(socket, address, pool.clone(), 50, None, CancellationToken::new()).await;
+
+        // One reply per frame, however many commands were in it
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(shared_v3::decode_responses_v1(&replies), vec![CollectorResponseV1::Ack, CollectorResponseV1::Ack]);
+
+        // Every sample keeps the time it was taken, not when it was sent
+        let rows: Vec<(i64, i64)> = sqlx::query_as("SELECT received, used_memory FROM timeseries ORDER BY received")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, vec![(100, 10), (101, 20), (102, 30), (103, 40)]);
+    }
+
+    #[tokio::test]
+    async fn ping_gets_a_matching_pong() {
+        let pool = crate::test_pool().await;
+        let ping = shared_v3::encode_v1(&CollectorCommandV1::Ping { collector_id: 1, nonce: 42 });
+        let (timestamp, command) = decode_v1(&ping);
+
+        let response = handle_command(&pool, timestamp, command).await;
+        assert_eq!(response, Some(CollectorResponseV1::Pong(42)));
+
+        // And the collector was marked as seen
+        let last_seen: i64 = sqlx::query_scalar("SELECT last_seen FROM collectors WHERE collector_id = ?")
+            .bind(uuid::Uuid::from_u128(1).to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(last_seen, timestamp as i64);
+    }
+
+    #[tokio::test]
+    async fn compressed_frames_are_stored() {
+        let pool = crate::test_pool().await;
+        let command = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory: 50,
+            cpu_usage: vec![12.5; 8],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        // What the collector sends with COLLECTOR_COMPRESS=1
+        let frame = shared_v3::encode_v1_compressed(&command);
+        let (timestamp, decoded) = decode_v1(&frame);
+        assert_eq!(decoded, command);
+
+        let response = handle_command(&pool, timestamp, decoded).await;
+        assert_eq!(response, Some(CollectorResponseV1::Ack));
+        let used_memory: i64 = sqlx::query_scalar("SELECT used_memory FROM timeseries WHERE collector_id = ?")
+            .bind(uuid::Uuid::from_u128(1).to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(used_memory, 50);
+    }
+
+    #[tokio::test]
+    async fn per_core_usage_is_kept() {
+        let pool = crate::test_pool().await;
+        let command = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory: 50,
+            cpu_usage: vec![10.0, 90.0, 20.0, 0.0],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        assert_eq!(handle_command(&pool, 100, command).await, Some(CollectorResponseV1::Ack));
+
+        // The average comes from the cores
+        let average_cpu: f32 = sqlx::query_scalar("SELECT average_cpu FROM timeseries").fetch_one(&pool).await.unwrap();
+        assert_eq!(average_cpu, 30.0);
+        let cores: Vec<(i64, f32)> = sqlx::query_as("SELECT core, cpu FROM timeseries_cores ORDER BY core")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(cores, vec![(0, 10.0), (1, 90.0), (2, 20.0), (3, 0.0)]);
+
+        // Without per-core numbers, the reported average is all we have
+        let old = CollectorCommandV1::SubmitData { collector_id: 2, total_memory: 100, used_memory: 50, average_cpu_usage: 42.0 };
+        assert_eq!(handle_command(&pool, 101, old).await, Some(CollectorResponseV1::Ack));
+        let average_cpu: f32 = sqlx::query_scalar("SELECT average_cpu FROM timeseries WHERE received = 101")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(average_cpu, 42.0);
+        let cores: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM timeseries_cores").fetch_one(&pool).await.unwrap();
+        assert_eq!(cores, 4);
+    }
+
+    #[tokio::test]
+    async fn invalid_submissions_are_dropped() {
+        let pool = crate::test_pool().await;
+        let command = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory: 200,
+            cpu_usage: vec![12.5],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+
+        let response = handle_command(&pool, 100, command).await;
+        assert_eq!(response, Some(CollectorResponseV1::Ack));
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM timeseries").fetch_one(&pool).await.unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[tokio::test]
+    async fn hello_stores_host_details() {
+        let pool = crate::test_pool().await;
+        let hello = CollectorCommandV1::Hello {
+            collector_id: 1,
+            os: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            cores: 8,
+        };
+
+        let response = handle_command(&pool, 100, hello).await;
+        assert_eq!(response, Some(CollectorResponseV1::Ack));
+
+        let (os, arch, cores): (String, String, i64) = sqlx::query_as("SELECT os, arch, cores FROM collectors WHERE collector_id = ?")
+            .bind(uuid::Uuid::from_u128(1).to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!((os.as_str(), arch.as_str(), cores), ("linux", "x86_64", 8));
+    }
+
+    #[tokio::test]
+    async fn reconnecting_collectors_update_their_row() {
+        let pool = crate::test_pool().await;
+        let host = HostInfo { os: "linux", arch: "x86_64", cores: 4 };
+        upsert_collector(&pool, "c1", 100, Some(host)).await.unwrap();
+        let host = HostInfo { os: "linux", arch: "aarch64", cores: 8 };
+        upsert_collector(&pool, "c1", 200, Some(host)).await.unwrap();
+
+        let rows: Vec<(i64, String, i64)> = sqlx::query_as("SELECT last_seen, arch, cores FROM collectors WHERE collector_id = 'c1'")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, vec![(200, "aarch64".to_string(), 8)]);
+
+        // A ping moves last_seen along, but keeps what we know about the host
+        upsert_collector(&pool, "c1", 300, None).await.unwrap();
+        let rows: Vec<(i64, String, i64)> = sqlx::query_as("SELECT last_seen, arch, cores FROM collectors WHERE collector_id = 'c1'")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, vec![(300, "aarch64".to_string(), 8)]);
+    }
+}
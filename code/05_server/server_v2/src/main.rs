@@ -1,8 +1,30 @@
 use std::net::SocketAddr;
-use axum::{Router, routing::get, Extension};
+use axum::{Router, routing::get, Extension, middleware};
 mod collector;
 mod api;
 mod web;
+mod auth;
+
+fn app(pool: sqlx::SqlitePool, sessions: auth::Sessions) -> Router {
+    // Everything except the login page needs a logged in user
+    let protected = Router::new()
+        .route("/", get(web::index))
+        .route("/collector.html", get(web::collector))
+        .route("/api/all", get(api::show_all))
+        .route("/api/collectors", get(api::show_collectors))
+        .route("/api/collectors.json", get(api::collectors_json))
+        .route("/api/status", get(api::collector_status))
+        .route("/api/collector/:uuid", get(api::collector_data).delete(api::delete_collector))
+        .route("/api/collector/:uuid/stats", get(api::collector_stats))
+        .route("/api/collector/:uuid/cores", get(api::collector_cores))
+        .route_layer(middleware::from_fn(auth::require_session));
+
+    Router::new()
+        .route("/login", get(auth::login_page).post(auth::login))
+        .merge(protected)
+        .layer(Extension(pool))
+        .layer(Extension(sessions))
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -13,24 +35,43 @@ async fn main() -> anyhow::Result<()> {
     // Get a database connection pool
     let pool = sqlx::SqlitePool::connect(&db_url).await?;
 
-    // Spawn the collector
-    let handle = tokio::spawn(collector::data_collector(pool.clone()));
+    // Run Migrations
+    sqlx::migrate!("./migrations").run(&pool).await?;
 
-    // Start the web server
-    let app = Router::new()
-        .route("/", get(web::index))
-        .route("/collector.html", get(web::collector))
-        .route("/api/all", get(api::show_all))
-        .route("/api/collectors", get(api::show_collectors))
-        .route("/api/collector/:uuid", get(api::collector_data))        
-        .layer(Extension(pool));
+    // Spawn the collector, with a way to tell it to stop
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let handle = tokio::spawn(collector::data_collector(pool.clone(), shutdown.clone()));
+
+    // Start the web server, which runs until Ctrl-C
+    let app = app(pool.clone(), auth::Sessions::default());
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));    
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+            println!("Shutting down");
+        })
         .await
         .unwrap();
 
-    // Wait for the data collector to finish
+    // Stop the data collector, and wait for it to store what it has
+    shutdown.cancel();
     handle.await??; // Two question marks - we're unwrapping the task result, and the result from running the collector.
+
+    // Nothing else will write now, so close the database cleanly
+    pool.close().await;
     Ok(())
 }
+
+/// An empty in-memory database, with the migrations applied
+#[cfg(test)]
+async fn test_pool() -> sqlx::SqlitePool {
+    // Every connection to ":memory:" gets its own database, so only use one
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool
+}
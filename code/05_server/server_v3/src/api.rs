@@ -2,7 +2,7 @@ use axum::{Extension, Json, extract::Path};
 use sqlx::FromRow;
 use serde::Serialize;
 
-use crate::commands::add_command;
+use crate::commands::send_command;
 
 #[derive(FromRow, Debug, Serialize)]
 pub struct DataPoint {
@@ -55,5 +55,6 @@ pub async fn collector_data(Extension(pool): Extension<sqlx::SqlitePool>, uuid:
 pub async fn shutdown_collector(uuid: Path<String>) {
     let uuid = uuid::Uuid::parse_str(uuid.as_str()).unwrap();
     let uuid = uuid.as_u128();
-    add_command(uuid, shared_v3::TaskType::Shutdown);
+    // Delivered right away if the collector is connected, otherwise queued
+    send_command(uuid, shared_v3::TaskType::Shutdown);
 }
\ No newline at end of file
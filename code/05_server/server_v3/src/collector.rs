@@ -1,70 +1,264 @@
 use std::net::SocketAddr;
-use shared_v3::{DATA_COLLECTOR_ADDRESS, decode_v1, CollectorCommandV1, encode_response_v1, CollectorResponseV1};
+use shared_v3::{DATA_COLLECTOR_ADDRESS, CollectorCommandV1, encode_response_v1, CollectorResponseV1, DecodeError};
 use sqlx::{Pool, Sqlite};
-use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}};
-use crate::commands::get_commands;
+use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}, sync::mpsc::UnboundedReceiver};
+use shared_v3::TaskType;
+use crate::commands::{self, get_commands};
 
 pub async fn data_collector(cnn: Pool<Sqlite>) -> anyhow::Result<()> {
     // Listen for TCP connections on the data collector address
     let listener = TcpListener::bind(DATA_COLLECTOR_ADDRESS).await?;
+    // If there's a key, every frame must be signed with it
+    let hmac_key = shared_v3::hmac_key_from_env();
 
     // Loop forever, accepting connections
     loop {
         // Wait for a new connection
         let cnn = cnn.clone();
         let (socket, address) = listener.accept().await?;
-        tokio::spawn(new_connection(socket, address, cnn));
+        tokio::spawn(new_connection(socket, address, cnn, hmac_key.clone()));
     }
 }
 
-async fn new_connection(mut socket: TcpStream, address: SocketAddr, cnn: Pool<Sqlite>) {
-    let mut buf = vec![0u8; 1024];
-    loop {
-        let n = socket
-            .read(&mut buf)
-            .await
-            .expect("failed to read data from socket");
+/// Which collector sent a command
+fn sender_id(command: &CollectorCommandV1) -> u128 {
+    match command {
+        CollectorCommandV1::SubmitData { collector_id, .. } => *collector_id,
+        CollectorCommandV1::RequestWork(collector_id) => *collector_id,
+        CollectorCommandV1::Hello { collector_id, .. } => *collector_id,
+        CollectorCommandV1::Ping { collector_id, .. } => *collector_id,
+        CollectorCommandV1::SubmitDataV2 { collector_id, .. } => *collector_id,
+    }
+}
+
+/// Store a sample; only the average CPU usage is kept
+async fn insert_sample(cnn: &Pool<Sqlite>, collector_id: u128, timestamp: u32, total_memory: u64, used_memory: u64, average_cpu: f32) -> Result<(), sqlx::Error> {
+    let collector_id = uuid::Uuid::from_u128(collector_id);
+    let collector_id = collector_id.to_string();
+
+    sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ($1, $2, $3, $4, $5)")
+        .bind(collector_id)
+        .bind(timestamp)
+        .bind(total_memory as i64)
+        .bind(used_memory as i64)
+        .bind(average_cpu)
+        .execute(cnn)
+        .await?;
+    Ok(())
+}
+
+/// The next task pushed to this connection. Until we know who the
+/// collector is, there's nothing to wait for.
+async fn next_push(pushes: &mut Option<(u128, UnboundedReceiver<TaskType>)>) -> Option<TaskType> {
+    match pushes {
+        Some((_, rx)) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Act on a command from a collector, returning the reply (if there is one).
+async fn handle_command(cnn: &Pool<Sqlite>, timestamp: u32, command: CollectorCommandV1) -> Option<CollectorResponseV1> {
+    match command {
+        CollectorCommandV1::RequestWork(collector_id) => match get_commands(collector_id) {
+            Some(commands) => Some(CollectorResponseV1::Task(commands)),
+            None => Some(CollectorResponseV1::NoWork),
+        },
+        CollectorCommandV1::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage } => {
+            let result = insert_sample(cnn, collector_id, timestamp, total_memory, used_memory, average_cpu_usage).await;
+
+            if result.is_err() {
+                println!("Error inserting data into the database: {result:?}");
+                None
+            } else {
+                Some(CollectorResponseV1::Ack)
+            }
+        }
+        CollectorCommandV1::SubmitDataV2 { collector_id, total_memory, used_memory, cpu_usage, .. } => {
+            let average_cpu = shared_v3::average_cpu_usage(&cpu_usage);
+            let result = insert_sample(cnn, collector_id, timestamp, total_memory, used_memory, average_cpu).await;
 
-        if n == 0 {
-            println!("No data received - connection closed");
-            return;
+            if result.is_err() {
+                println!("Error inserting data into the database: {result:?}");
+                None
+            } else {
+                Some(CollectorResponseV1::Ack)
+            }
+        }
+        CollectorCommandV1::Hello { .. } => Some(CollectorResponseV1::Ack),
+        CollectorCommandV1::Ping { nonce, .. } => Some(CollectorResponseV1::Pong(nonce)),
+    }
+}
+
+/// Act on every command in a frame, returning the one reply the frame gets.
+/// A batch is acknowledged once every command in it has been handled, each
+/// stored with its own timestamp.
+async fn handle_frame(cnn: &Pool<Sqlite>, mut commands: Vec<(u32, CollectorCommandV1)>) -> Option<CollectorResponseV1> {
+    if commands.len() == 1 {
+        let (timestamp, command) = commands.remove(0);
+        return handle_command(cnn, timestamp, command).await;
+    }
+    let mut handled = true;
+    for (timestamp, command) in commands {
+        handled &= handle_command(cnn, timestamp, command).await.is_some();
+    }
+    handled.then_some(CollectorResponseV1::Ack)
+}
+
+async fn new_connection(mut socket: TcpStream, address: SocketAddr, cnn: Pool<Sqlite>, hmac_key: Option<Vec<u8>>) {
+    let mut pushes = None;
+    loop {
+        // Wait for something to read without starting on it, so a push
+        // can't interrupt a frame half way through
+        tokio::select! {
+            readable = socket.readable() => {
+                if let Err(e) = readable {
+                    println!("Error reading from {address}: {e}");
+                    break;
+                }
+            }
+            Some(task) = next_push(&mut pushes) => {
+                let bytes = encode_response_v1(CollectorResponseV1::Push(task.clone()));
+                if socket.write_all(&bytes).await.is_err() {
+                    // Try again when it reconnects
+                    if let Some((collector_id, _)) = &pushes {
+                        commands::add_command(*collector_id, task);
+                    }
+                    break;
+                }
+                continue;
+            }
         }
 
-        let received_data = decode_v1(&buf[0..n]);
-
-        match received_data {
-            (_timestamp, CollectorCommandV1::RequestWork(collector_id)) => {
-                if let Some(commands) = get_commands(collector_id) {
-                    let work = CollectorResponseV1::Task(commands);
-                    let bytes = encode_response_v1(work);
-                    socket.write_all(&bytes).await.unwrap();
-                } else {
-                    let no_work = CollectorResponseV1::NoWork;
-                    let bytes = encode_response_v1(no_work);
-                    socket.write_all(&bytes).await.unwrap();
+        // Read a whole frame, however the network splits it up
+        let mut bytes = match shared_v3::read_frame_async(&mut socket).await {
+            Ok(bytes) => bytes,
+            Err(DecodeError::Io(std::io::ErrorKind::UnexpectedEof)) => {
+                println!("No data received - connection closed");
+                break;
+            }
+            Err(e) => {
+                println!("Invalid frame from {address}: {e:?}");
+                break;
+            }
+        };
+
+        // Signed frames have the signature after them
+        let frame = match &hmac_key {
+            Some(key) => {
+                let frame_length = bytes.len();
+                bytes.resize(frame_length + shared_v3::HMAC_SIZE, 0);
+                if let Err(e) = socket.read_exact(&mut bytes[frame_length..]).await {
+                    println!("Unable to read a frame signature from {address}: {e}");
+                    break;
+                }
+                match shared_v3::verify_frame(&bytes, key) {
+                    Some(frame) => frame,
+                    None => {
+                        println!("Rejecting a frame with a bad signature from {address}");
+                        break;
+                    }
                 }
             }
-            (timestamp, CollectorCommandV1::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage }) => {
-                let collector_id = uuid::Uuid::from_u128(collector_id);
-                let collector_id = collector_id.to_string();
-
-                let result = sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ($1, $2, $3, $4, $5)")
-                    .bind(collector_id)
-                    .bind(timestamp)
-                    .bind(total_memory as i64)
-                    .bind(used_memory as i64)
-                    .bind(average_cpu_usage)
-                    .execute(&cnn)
-                    .await;
-
-                if result.is_err() {
-                    println!("Error inserting data into the database: {result:?}");
-                } else {
-                    let ack = CollectorResponseV1::Ack;
-                    let bytes = encode_response_v1(ack);
-                    socket.write_all(&bytes).await.unwrap();
+            None => &bytes[..],
+        };
+
+        // A frame holds one command, or a batch of them
+        let Some(commands) = shared_v3::decode_frames_v1(frame).frames.pop() else {
+            println!("Invalid frame from {address}");
+            break;
+        };
+
+        // Now we know who this is, so the server can push tasks to it
+        if pushes.is_none() {
+            if let Some((_, command)) = commands.first() {
+                let collector_id = sender_id(command);
+                pushes = Some((collector_id, commands::connect(collector_id)));
+            }
+        }
+
+        if let Some(reply) = handle_frame(&cnn, commands).await {
+            if let Err(e) = socket.write_all(&encode_response_v1(reply.clone())).await {
+                println!("Error replying to {address}: {e}");
+                // A task it asked for can go out next time instead
+                if let (CollectorResponseV1::Task(task), Some((collector_id, _))) = (reply, &pushes) {
+                    commands::add_command(*collector_id, task);
                 }
+                break;
             }
-        }        
+        }
+    }
+
+    if let Some((collector_id, rx)) = pushes {
+        commands::disconnect(collector_id, rx);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared_v3::encode_v1;
+
+    fn sample(used_memory: u64) -> CollectorCommandV1 {
+        CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory,
+            cpu_usage: vec![12.5],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_batch_gets_one_ack() {
+        let pool = crate::test_pool().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, address) = listener.accept().await.unwrap();
+
+        // What a collector sends when it starts: its hello and first sample together
+        let hello = CollectorCommandV1::Hello { collector_id: 1, os: "linux".to_string(), arch: "x86_64".to_string(), cores: 1 };
+        client.write_all(&shared_v3::encode_batch_v1(&[(100, hello), (100, sample(10))])).await.unwrap();
+        client.write_all(&shared_v3::encode_batch_v1_compressed(&[(101, sample(20)), (102, sample(30))])).await.unwrap();
+        client.write_all(&encode_v1(&sample(40))).await.unwrap();
+        client.shutdown().await.unwrap();
+        new_connection(socket, address, pool.clone(), None).await;
+
+        // One reply per frame, however many commands were in it
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(shared_v3::decode_responses_v1(&replies), vec![CollectorResponseV1::Ack; 3]);
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM timeseries").fetch_one(&pool).await.unwrap();
+        assert_eq!(rows, 4);
+
+        // Batched samples keep the time they were taken
+        let received: Vec<(i64, i64)> = sqlx::query_as("SELECT received, used_memory FROM timeseries WHERE used_memory < 40 ORDER BY used_memory")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(received, vec![(100, 10), (101, 20), (102, 30)]);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn unsigned_frames_are_rejected_when_there_is_a_key() {
+        let pool = crate::test_pool().await;
+        let key = b"a key for testing".to_vec();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, address) = listener.accept().await.unwrap();
+
+        // A signed sample is stored, but the unsigned one after it ends the connection
+        client.write_all(&shared_v3::sign_frame(encode_v1(&sample(10)), &key)).await.unwrap();
+        client.write_all(&encode_v1(&sample(20))).await.unwrap();
+        client.write_all(&[0; shared_v3::HMAC_SIZE]).await.unwrap();
+        client.write_all(&shared_v3::sign_frame(encode_v1(&sample(30)), &key)).await.unwrap();
+        client.shutdown().await.unwrap();
+        new_connection(socket, address, pool.clone(), Some(key)).await;
+
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(shared_v3::decode_responses_v1(&replies), vec![CollectorResponseV1::Ack]);
+        let stored: Vec<i64> = sqlx::query_scalar("SELECT used_memory FROM timeseries").fetch_all(&pool).await.unwrap();
+        assert_eq!(stored, vec![10]);
+    }
+}
@@ -2,9 +2,13 @@ use once_cell::sync::Lazy;
 use shared_v3::TaskType;
 use std::sync::Mutex;
 use std::collections::HashMap;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 static COMMANDS: Lazy<Mutex<HashMap<u128, TaskType>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
+/// Collectors with an open connection, and how to push tasks down it
+static CONNECTIONS: Lazy<Mutex<HashMap<u128, UnboundedSender<TaskType>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
 pub fn add_command(collector_id: u128, command: TaskType) {
     let mut commands = COMMANDS.lock().unwrap();
     commands.insert(collector_id, command);
@@ -13,4 +17,89 @@ pub fn add_command(collector_id: u128, command: TaskType) {
 pub fn get_commands(collector_id: u128) -> Option<TaskType> {
     let mut commands = COMMANDS.lock().unwrap();
     commands.remove(&collector_id)
-}
\ No newline at end of file
+}
+
+/// Send a task to a collector right away if it's connected. Otherwise it's
+/// queued, and goes out when the collector next connects (or asks for work).
+/// Returns true if it was pushed immediately.
+pub fn send_command(collector_id: u128, command: TaskType) -> bool {
+    let connections = CONNECTIONS.lock().unwrap();
+    let command = match connections.get(&collector_id) {
+        Some(tx) => match tx.send(command) {
+            Ok(()) => return true,
+            // The connection is closing - get the command back
+            Err(e) => e.0,
+        },
+        None => command,
+    };
+    add_command(collector_id, command);
+    false
+}
+
+/// A collector has connected. Returns where its pushed tasks will arrive -
+/// starting with anything that was queued while it was away.
+pub fn connect(collector_id: u128) -> UnboundedReceiver<TaskType> {
+    let (tx, rx) = unbounded_channel();
+    if let Some(command) = get_commands(collector_id) {
+        // Can't fail, we're holding the receiver
+        let _ = tx.send(command);
+    }
+    CONNECTIONS.lock().unwrap().insert(collector_id, tx);
+    rx
+}
+
+/// A collector's connection has closed. Takes the receiver from `connect`,
+/// so we only forget the connection if a newer one hasn't replaced it.
+/// Anything pushed that the connection never sent is queued for next time.
+pub fn disconnect(collector_id: u128, mut rx: UnboundedReceiver<TaskType>) {
+    // Holding the lock means nothing can be pushed while we drain
+    let mut connections = CONNECTIONS.lock().unwrap();
+    rx.close();
+    while let Ok(command) = rx.try_recv() {
+        add_command(collector_id, command);
+    }
+    drop(rx);
+    if connections.get(&collector_id).map(|tx| tx.is_closed()).unwrap_or(false) {
+        connections.remove(&collector_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The registry is global, so each test uses its own collector ids
+
+    #[test]
+    fn queued_command_is_delivered_on_connect() {
+        assert!(!send_command(1, TaskType::Shutdown));
+        let mut rx = connect(1);
+        assert_eq!(rx.try_recv().unwrap(), TaskType::Shutdown);
+        // It only goes out once
+        assert!(get_commands(1).is_none());
+        disconnect(1, rx);
+    }
+
+    #[test]
+    fn connected_collectors_get_pushed_commands() {
+        let mut rx = connect(2);
+        assert!(rx.try_recv().is_err());
+        assert!(send_command(2, TaskType::Shutdown));
+        assert_eq!(rx.try_recv().unwrap(), TaskType::Shutdown);
+
+        // Once it has gone away, commands wait for next time
+        disconnect(2, rx);
+        assert!(!send_command(2, TaskType::Shutdown));
+        assert_eq!(get_commands(2), Some(TaskType::Shutdown));
+    }
+
+    #[test]
+    fn unsent_pushes_are_kept_on_disconnect() {
+        let rx = connect(3);
+        assert!(send_command(3, TaskType::Shutdown));
+
+        // The connection closed before it could send the task on
+        disconnect(3, rx);
+        assert_eq!(get_commands(3), Some(TaskType::Shutdown));
+    }
+}
@@ -36,3 +36,16 @@ async fn main() -> anyhow::Result<()> {
     handle.await??; // Two question marks - we're unwrapping the task result, and the result from running the collector.
     Ok(())
 }
+
+/// An empty in-memory database, with the migrations applied
+#[cfg(test)]
+async fn test_pool() -> sqlx::SqlitePool {
+    // Every connection to ":memory:" gets its own database, so only use one
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool
+}
@@ -0,0 +1,33 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use shared_v3::{decode_v1, encode_v1, encode_v1_compressed, CollectorCommandV1};
+
+/// A sample the way the collector sends it, with uneven per-core usage
+fn submit_data(cores: usize) -> CollectorCommandV1 {
+    CollectorCommandV1::SubmitDataV2 {
+        collector_id: 0x1234_5678_9abc_def0_0fed_cba9_8765_4321,
+        total_memory: 32 * 1024 * 1024 * 1024,
+        used_memory: 13_123_456_789,
+        cpu_usage: (0..cores).map(|core| (core * 37 % 100) as f32 + 0.3).collect(),
+        bytes_received: 48_213,
+        bytes_transmitted: 9_876,
+    }
+}
+
+fn compression(c: &mut Criterion) {
+    for cores in [8, 16, 64] {
+        let command = submit_data(cores);
+        let plain = encode_v1(&command);
+        let compressed = encode_v1_compressed(&command);
+        println!("{cores} cores: {} bytes, {} compressed", plain.len(), compressed.len());
+
+        let mut group = c.benchmark_group(format!("submit_data_{cores}_cores"));
+        group.bench_function("encode", |b| b.iter(|| encode_v1(black_box(&command))));
+        group.bench_function("encode_compressed", |b| b.iter(|| encode_v1_compressed(black_box(&command))));
+        group.bench_function("decode", |b| b.iter(|| decode_v1(black_box(&plain))));
+        group.bench_function("decode_compressed", |b| b.iter(|| decode_v1(black_box(&compressed))));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
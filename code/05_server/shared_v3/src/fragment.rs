@@ -0,0 +1,327 @@
+//! Splitting a frame that's too big to send in one go, and putting it back
+//! together on the other side.
+//!
+//! A fragment frame looks like this (big-endian):
+//! magic (2), version with `FRAGMENT_FLAG` set (2), collector id (16),
+//! sequence (4), fragment index (2), fragment count (2), chunk size (4),
+//! chunk, CRC of the chunk (4).
+//!
+//! The chunks, joined in order, are an ordinary frame for `decode_v1`.
+use super::{DecodeError, MAGIC_NUMBER, VERSION_NUMBER};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Set in the version field of fragment frames
+pub(crate) const FRAGMENT_FLAG: u16 = 0x4000;
+pub(crate) const HEADER_SIZE: usize = 32;
+
+/// One piece of a larger frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragment {
+    pub collector_id: u128,
+    pub sequence: u32,
+    pub index: u16,
+    pub count: u16,
+    pub data: Vec<u8>,
+}
+
+/// Split an encoded frame into fragment frames, each carrying at most
+/// `max_chunk` bytes of it. `sequence` tells one batch from the next.
+pub fn split_frame(frame: &[u8], collector_id: u128, sequence: u32, max_chunk: usize) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = frame.chunks(max_chunk.max(1)).collect();
+    assert!(chunks.len() <= u16::MAX as usize, "too many fragments");
+    let count = chunks.len() as u16;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut result = Vec::with_capacity(HEADER_SIZE + chunk.len() + 4);
+            result.extend_from_slice(&MAGIC_NUMBER.to_be_bytes());
+            result.extend_from_slice(&(VERSION_NUMBER | FRAGMENT_FLAG).to_be_bytes());
+            result.extend_from_slice(&collector_id.to_be_bytes());
+            result.extend_from_slice(&sequence.to_be_bytes());
+            result.extend_from_slice(&(index as u16).to_be_bytes());
+            result.extend_from_slice(&count.to_be_bytes());
+            result.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            result.extend_from_slice(chunk);
+            result.extend_from_slice(&crc32fast::hash(chunk).to_be_bytes());
+            result
+        })
+        .collect()
+}
+
+/// Is this a fragment, rather than a whole frame?
+pub fn is_fragment(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && u16::from_be_bytes([bytes[2], bytes[3]]) & FRAGMENT_FLAG != 0
+}
+
+/// Read a fragment frame, checking everything a sender could get wrong
+pub fn decode_fragment(bytes: &[u8]) -> Result<Fragment, DecodeError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(DecodeError::TooShort);
+    }
+    let magic_number = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let version_number = u16::from_be_bytes([bytes[2], bytes[3]]);
+    if magic_number != MAGIC_NUMBER {
+        return Err(DecodeError::BadMagic);
+    }
+    if version_number & !FRAGMENT_FLAG != VERSION_NUMBER {
+        return Err(DecodeError::BadVersion);
+    }
+    let collector_id = u128::from_be_bytes(bytes[4..20].try_into().unwrap());
+    let sequence = u32::from_be_bytes(bytes[20..24].try_into().unwrap());
+    let index = u16::from_be_bytes([bytes[24], bytes[25]]);
+    let count = u16::from_be_bytes([bytes[26], bytes[27]]);
+    let size = u32::from_be_bytes(bytes[28..32].try_into().unwrap()) as usize;
+    if index >= count {
+        // Also catches a count of zero
+        return Err(DecodeError::BadPayload);
+    }
+
+    let data_end = HEADER_SIZE.checked_add(size).ok_or(DecodeError::TooLarge)?;
+    let crc_bytes = bytes.get(data_end..data_end + 4).ok_or(DecodeError::TooShort)?;
+    let data = &bytes[HEADER_SIZE..data_end];
+    if u32::from_be_bytes(crc_bytes.try_into().unwrap()) != crc32fast::hash(data) {
+        return Err(DecodeError::BadCrc);
+    }
+
+    Ok(Fragment { collector_id, sequence, index, count, data: data.to_vec() })
+}
+
+/// At most this many frames can be waiting on more fragments, per connection
+pub const DEFAULT_MAX_PENDING: usize = 16;
+/// At most this much fragment data is held, per connection
+pub const DEFAULT_MAX_BUFFERED_BYTES: usize = 4 * 1024 * 1024;
+
+/// Why `Reassembler::add` refused a fragment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReassemblyError {
+    /// Starting another frame would go over the pending limit
+    TooManyPending,
+    /// Keeping the fragment would go over the byte limit. The frame it
+    /// belongs to is dropped.
+    TooManyBytes,
+    /// The fragment disagrees with earlier ones about how many pieces there
+    /// are. The frame it belongs to is dropped.
+    CountMismatch,
+}
+
+/// The fragments of one frame, as they arrive.
+struct Partial {
+    first_seen: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+    /// Counted against the byte limit: the data, and the slots for it
+    bytes: usize,
+}
+
+/// Collects fragments, keyed by (collector id, sequence), until every
+/// piece of a frame has arrived. Sets that don't complete within `timeout`
+/// are thrown away by `expire`. The number of sets and the bytes held are
+/// limited, so a sender can't make us buffer without end.
+pub struct Reassembler {
+    timeout: Duration,
+    max_pending: usize,
+    max_bytes: usize,
+    buffered: usize,
+    pending: HashMap<(u128, u32), Partial>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self::with_limits(timeout, DEFAULT_MAX_PENDING, DEFAULT_MAX_BUFFERED_BYTES)
+    }
+
+    pub fn with_limits(timeout: Duration, max_pending: usize, max_bytes: usize) -> Self {
+        Self { timeout, max_pending, max_bytes, buffered: 0, pending: HashMap::new() }
+    }
+
+    /// Add a fragment. Returns the whole frame once the last piece is in.
+    pub fn add(&mut self, fragment: Fragment, now: Instant) -> Result<Option<Vec<u8>>, ReassemblyError> {
+        let key = (fragment.collector_id, fragment.sequence);
+        if !self.pending.contains_key(&key) {
+            if self.pending.len() >= self.max_pending {
+                return Err(ReassemblyError::TooManyPending);
+            }
+            let slots = fragment.count as usize * std::mem::size_of::<Option<Vec<u8>>>();
+            self.pending.insert(key, Partial { first_seen: now, parts: vec![None; fragment.count as usize], bytes: slots });
+            self.buffered += slots;
+        }
+
+        let partial = &self.pending[&key];
+        // A mismatched count means something's wrong with the sender
+        if partial.parts.len() != fragment.count as usize {
+            self.remove(key);
+            return Err(ReassemblyError::CountMismatch);
+        }
+        // A repeated fragment replaces the earlier copy
+        let replaced = partial.parts[fragment.index as usize].as_ref().map_or(0, Vec::len);
+        if self.buffered - replaced + fragment.data.len() > self.max_bytes {
+            self.remove(key);
+            return Err(ReassemblyError::TooManyBytes);
+        }
+
+        let partial = self.pending.get_mut(&key).unwrap();
+        partial.bytes = partial.bytes - replaced + fragment.data.len();
+        self.buffered = self.buffered - replaced + fragment.data.len();
+        partial.parts[fragment.index as usize] = Some(fragment.data);
+
+        if partial.parts.iter().all(Option::is_some) {
+            let partial = self.remove(key).unwrap();
+            Ok(Some(partial.parts.into_iter().flatten().flatten().collect()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn remove(&mut self, key: (u128, u32)) -> Option<Partial> {
+        let partial = self.pending.remove(&key)?;
+        self.buffered -= partial.bytes;
+        Some(partial)
+    }
+
+    /// Discard incomplete sets older than the timeout, returning how many
+    /// were dropped.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired: Vec<(u128, u32)> = self
+            .pending
+            .iter()
+            .filter(|(_, partial)| now.duration_since(partial.first_seen) >= self.timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &expired {
+            self.remove(*key);
+        }
+        expired.len()
+    }
+
+    /// How many bytes are held for incomplete frames
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered
+    }
+
+    /// How many frames are waiting on more fragments
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_v1, encode_v1, CollectorCommandV1};
+
+    fn big_command() -> CollectorCommandV1 {
+        CollectorCommandV1::SubmitDataV2 {
+            collector_id: 7,
+            total_memory: 100,
+            used_memory: 50,
+            cpu_usage: (0..256).map(|n| n as f32).collect(),
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        }
+    }
+
+    fn three_fragments(frame: &[u8]) -> Vec<Vec<u8>> {
+        let fragments = split_frame(frame, 7, 1, frame.len() / 3 + 1);
+        assert_eq!(fragments.len(), 3);
+        fragments
+    }
+
+    #[test]
+    fn three_fragments_reassemble() {
+        let command = big_command();
+        let frame = encode_v1(&command);
+        let fragments = three_fragments(&frame);
+        assert!(fragments.iter().all(|fragment| is_fragment(fragment)));
+        assert!(!is_fragment(&frame));
+
+        // Out of order is fine
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        assert_eq!(reassembler.add(decode_fragment(&fragments[2]).unwrap(), now), Ok(None));
+        assert_eq!(reassembler.add(decode_fragment(&fragments[0]).unwrap(), now), Ok(None));
+        let whole = reassembler.add(decode_fragment(&fragments[1]).unwrap(), now).unwrap().unwrap();
+        assert_eq!(whole, frame);
+        assert_eq!(decode_v1(&whole).1, command);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn missing_fragment_times_out() {
+        let frame = encode_v1(&big_command());
+        let fragments = three_fragments(&frame);
+
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        reassembler.add(decode_fragment(&fragments[0]).unwrap(), start).unwrap();
+        reassembler.add(decode_fragment(&fragments[1]).unwrap(), start).unwrap();
+
+        // Not yet...
+        assert_eq!(reassembler.expire(start + Duration::from_secs(1)), 0);
+        // ...but now it's given up on
+        assert_eq!(reassembler.expire(start + Duration::from_secs(5)), 1);
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered_bytes(), 0);
+
+        // So the straggler doesn't complete anything
+        let late = start + Duration::from_secs(6);
+        assert_eq!(reassembler.add(decode_fragment(&fragments[2]).unwrap(), late), Ok(None));
+    }
+
+    #[test]
+    fn malformed_fragments_are_errors() {
+        let frame = encode_v1(&big_command());
+        let fragment = three_fragments(&frame).remove(0);
+        assert!(decode_fragment(&fragment).is_ok());
+
+        // Cut short, in the header and in the data
+        assert_eq!(decode_fragment(&fragment[..10]), Err(DecodeError::TooShort));
+        assert_eq!(decode_fragment(&fragment[..fragment.len() - 1]), Err(DecodeError::TooShort));
+
+        let mut damaged = fragment.clone();
+        damaged[HEADER_SIZE] ^= 0xFF;
+        assert_eq!(decode_fragment(&damaged), Err(DecodeError::BadCrc));
+
+        let mut wrong_magic = fragment.clone();
+        wrong_magic[0] ^= 0xFF;
+        assert_eq!(decode_fragment(&wrong_magic), Err(DecodeError::BadMagic));
+
+        // Piece 5 of 3, and piece 0 of 0
+        let mut past_the_end = fragment.clone();
+        past_the_end[24..26].copy_from_slice(&5u16.to_be_bytes());
+        assert_eq!(decode_fragment(&past_the_end), Err(DecodeError::BadPayload));
+        let mut no_pieces = fragment.clone();
+        no_pieces[26..28].copy_from_slice(&0u16.to_be_bytes());
+        assert_eq!(decode_fragment(&no_pieces), Err(DecodeError::BadPayload));
+
+        // A size far past the end of the frame
+        let mut huge = fragment;
+        huge[28..32].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decode_fragment(&huge).is_err());
+    }
+
+    #[test]
+    fn reassembly_is_limited() {
+        let now = Instant::now();
+        let first_of = |sequence: u32, size: usize| Fragment { collector_id: 7, sequence, index: 0, count: 2, data: vec![0; size] };
+
+        // Only two frames at a time
+        let mut reassembler = Reassembler::with_limits(Duration::from_secs(5), 2, 1024 * 1024);
+        assert_eq!(reassembler.add(first_of(1, 10), now), Ok(None));
+        assert_eq!(reassembler.add(first_of(2, 10), now), Ok(None));
+        assert_eq!(reassembler.add(first_of(3, 10), now), Err(ReassemblyError::TooManyPending));
+        assert_eq!(reassembler.pending(), 2);
+
+        // Only 1000 bytes at a time - the frame that goes over is dropped
+        let mut reassembler = Reassembler::with_limits(Duration::from_secs(5), 16, 1000);
+        assert_eq!(reassembler.add(first_of(1, 500), now), Ok(None));
+        assert_eq!(reassembler.add(first_of(2, 600), now), Err(ReassemblyError::TooManyBytes));
+        assert_eq!(reassembler.pending(), 1);
+        let last = Fragment { index: 1, ..first_of(1, 400) };
+        assert_eq!(reassembler.add(last, now).unwrap().unwrap().len(), 900);
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+}
@@ -1,9 +1,24 @@
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{borrow::Cow, time::{SystemTime, UNIX_EPOCH}};
+
+pub mod fragment;
 
 pub const DATA_COLLECTOR_ADDRESS: &str = "127.0.0.1:9004";
 const MAGIC_NUMBER: u16 = 1234;
-const VERSION_NUMBER: u16 = 1;
+/// Frames with a bincode payload, sending usage as `SubmitDataV2`
+const VERSION_NUMBER: u16 = 4;
+/// Frames with a JSON payload, from collectors built on `shared_v1` or
+/// `shared_v2`. The first `shared_v3` collectors sent bincode with this
+/// version too - both only ever had the single-float `SubmitData`.
+const VERSION_JSON: u16 = 1;
+/// Bincode frames from before `SubmitDataV2`, when per-core usage and the
+/// network counters were squeezed into `SubmitData`
+const VERSION_BINCODE_V3: u16 = 3;
+/// Set in the version field when the payload is deflate-compressed
+const COMPRESSED_FLAG: u16 = 0x8000;
+/// Set in the version field when the payload is a list of commands. From
+/// version 4 on, each command comes with its own timestamp.
+const BATCH_FLAG: u16 = 0x2000;
 
 fn unix_now() -> u32 {
     let start = SystemTime::now();
@@ -15,6 +30,8 @@ fn unix_now() -> u32 {
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum CollectorCommandV1 {
+    /// The original sample, from older collectors. Bincode identifies
+    /// variants by position, so new ones only ever go at the end.
     SubmitData {
         collector_id: u128,
         total_memory: u64,
@@ -22,13 +39,163 @@ pub enum CollectorCommandV1 {
         average_cpu_usage: f32,
     },
     RequestWork(u128),
+    /// Sent once when the collector starts, describing the host it's running on.
+    Hello {
+        collector_id: u128,
+        os: String,
+        arch: String,
+        cores: u32,
+    },
+    /// "Are you there?" - sent when the collector has been quiet for a while.
+    /// The server replies with a `Pong` carrying the same nonce.
+    Ping {
+        collector_id: u128,
+        nonce: u32,
+    },
+    /// A sample with the usage (0-100%) of each core, rather than just the
+    /// average, and the bytes sent and received since the last sample.
+    SubmitDataV2 {
+        collector_id: u128,
+        total_memory: u64,
+        used_memory: u64,
+        cpu_usage: Vec<f32>,
+        bytes_received: u64,
+        bytes_transmitted: u64,
+    },
+}
+
+/// The mean of per-core usage figures, or 0 if there are none
+pub fn average_cpu_usage(cpu_usage: &[f32]) -> f32 {
+    if cpu_usage.is_empty() {
+        0.0
+    } else {
+        cpu_usage.iter().sum::<f32>() / cpu_usage.len() as f32
+    }
+}
+
+/// Format a collector id the same way as a hyphenated UUID, so it matches
+/// what the server stores.
+fn format_collector_id(collector_id: u128) -> String {
+    let hex = format!("{collector_id:032x}");
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Format a byte count for people to read, e.g. `1.5 MiB`.
+pub fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if n < 1024 {
+        return format!("{n} B");
+    }
+
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    // Move up a unit if rounding would display "1024.0"
+    while value >= 1023.95 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+impl std::fmt::Display for CollectorCommandV1 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollectorCommandV1::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage, .. } => write!(
+                f,
+                "collector {}: cpu {average_cpu_usage:.1}%, mem {}/{}",
+                format_collector_id(*collector_id),
+                format_bytes(*used_memory),
+                format_bytes(*total_memory),
+            ),
+            CollectorCommandV1::RequestWork(collector_id) => {
+                write!(f, "collector {}: requesting work", format_collector_id(*collector_id))
+            }
+            CollectorCommandV1::Hello { collector_id, os, arch, cores } => {
+                write!(f, "collector {}: hello from {os}/{arch} with {cores} cores", format_collector_id(*collector_id))
+            }
+            CollectorCommandV1::Ping { collector_id, nonce } => {
+                write!(f, "collector {}: ping {nonce}", format_collector_id(*collector_id))
+            }
+            CollectorCommandV1::SubmitDataV2 { collector_id, total_memory, used_memory, cpu_usage, .. } => write!(
+                f,
+                "collector {}: cpu {:.1}% over {} cores, mem {}/{}",
+                format_collector_id(*collector_id),
+                average_cpu_usage(cpu_usage),
+                cpu_usage.len(),
+                format_bytes(*used_memory),
+                format_bytes(*total_memory),
+            ),
+        }
+    }
+}
+
+/// Why a decoded command can't be trusted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationError {
+    /// Collector ids are random, so zero is a placeholder rather than an id
+    ZeroCollectorId,
+    /// More memory in use than the machine has
+    UsedMemoryOverTotal { used_memory: u64, total_memory: u64 },
+    /// A CPU usage figure isn't a percentage, or isn't a number
+    CpuOutOfRange(f32),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::ZeroCollectorId => write!(f, "the collector id is zero"),
+            ValidationError::UsedMemoryOverTotal { used_memory, total_memory } => {
+                write!(f, "used memory ({used_memory}) is more than the total ({total_memory})")
+            }
+            ValidationError::CpuOutOfRange(usage) => write!(f, "CPU usage {usage} isn't between 0 and 100"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Check that a command makes sense before acting on it. Decoding only
+/// proves the bytes arrived intact, not that what they say is plausible.
+pub fn validate_command(cmd: &CollectorCommandV1) -> Result<(), ValidationError> {
+    let collector_id = match cmd {
+        CollectorCommandV1::SubmitData { collector_id, .. }
+        | CollectorCommandV1::RequestWork(collector_id)
+        | CollectorCommandV1::Hello { collector_id, .. }
+        | CollectorCommandV1::Ping { collector_id, .. }
+        | CollectorCommandV1::SubmitDataV2 { collector_id, .. } => *collector_id,
+    };
+    if collector_id == 0 {
+        return Err(ValidationError::ZeroCollectorId);
+    }
+
+    let (total_memory, used_memory, cpu_usage) = match cmd {
+        CollectorCommandV1::SubmitData { total_memory, used_memory, average_cpu_usage, .. } => {
+            (total_memory, used_memory, std::slice::from_ref(average_cpu_usage))
+        }
+        CollectorCommandV1::SubmitDataV2 { total_memory, used_memory, cpu_usage, .. } => {
+            (total_memory, used_memory, cpu_usage.as_slice())
+        }
+        _ => return Ok(()),
+    };
+    if used_memory > total_memory {
+        return Err(ValidationError::UsedMemoryOverTotal { used_memory: *used_memory, total_memory: *total_memory });
+    }
+    // A NaN isn't in any range, so this catches it too
+    if let Some(usage) = cpu_usage.iter().find(|usage| !(0.0..=100.0).contains(*usage)) {
+        return Err(ValidationError::CpuOutOfRange(*usage));
+    }
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum CollectorResponseV1 {
     Ack,
     NoWork,
-    Task(TaskType)
+    Task(TaskType),
+    Pong(u32),
+    /// A task the server sent without being asked. Can arrive at any time
+    /// while the collector is connected.
+    Push(TaskType),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -37,7 +204,40 @@ pub enum TaskType {
 }
 
 pub fn encode_v1(command: &CollectorCommandV1) -> Vec<u8> {
-    let payload_bytes = bincode::serialize(command).unwrap();
+    encode_frame(command, false)
+}
+
+/// Encode with a compressed payload. `decode_v1` handles either kind of frame.
+/// The CRC is of the compressed bytes, so damage is caught before we try to
+/// inflate them.
+///
+/// Per-core usage doesn't repeat much, so the savings are modest. Run
+/// `cargo bench` for the sizes and the time it costs.
+pub fn encode_v1_compressed(command: &CollectorCommandV1) -> Vec<u8> {
+    encode_frame(command, true)
+}
+
+/// Encode several commands as one frame, with one header and one CRC -
+/// cheaper than a frame each when there's a backlog to send. Each command
+/// goes with the timestamp it was made at, not when the batch was sent.
+pub fn encode_batch_v1(commands: &[(u32, CollectorCommandV1)]) -> Vec<u8> {
+    frame_payload(bincode::serialize(commands).unwrap(), VERSION_NUMBER | BATCH_FLAG, false)
+}
+
+/// `encode_batch_v1`, with the payload compressed like `encode_v1_compressed`
+pub fn encode_batch_v1_compressed(commands: &[(u32, CollectorCommandV1)]) -> Vec<u8> {
+    frame_payload(bincode::serialize(commands).unwrap(), VERSION_NUMBER | BATCH_FLAG, true)
+}
+
+fn encode_frame(command: &CollectorCommandV1, compress: bool) -> Vec<u8> {
+    frame_payload(bincode::serialize(command).unwrap(), VERSION_NUMBER, compress)
+}
+
+fn frame_payload(mut payload_bytes: Vec<u8>, mut version: u16, compress: bool) -> Vec<u8> {
+    if compress {
+        payload_bytes = miniz_oxide::deflate::compress_to_vec(&payload_bytes, 6);
+        version |= COMPRESSED_FLAG;
+    }
     //let json = serde_json::to_string(&command).unwrap();
     //let json_bytes = json.as_bytes();
     let crc = crc32fast::hash(&payload_bytes);
@@ -47,7 +247,7 @@ pub fn encode_v1(command: &CollectorCommandV1) -> Vec<u8> {
     // Encode into bytes
     let mut result = Vec::with_capacity(140);
     result.extend_from_slice(&MAGIC_NUMBER.to_be_bytes());
-    result.extend_from_slice(&VERSION_NUMBER.to_be_bytes());
+    result.extend_from_slice(&version.to_be_bytes());
     result.extend_from_slice(&timestamp.to_be_bytes());
     result.extend_from_slice(&payload_size.to_be_bytes());
     result.extend_from_slice(&payload_bytes);
@@ -55,31 +255,485 @@ pub fn encode_v1(command: &CollectorCommandV1) -> Vec<u8> {
     result
 }
 
-pub fn decode_v1(bytes: &[u8]) -> (u32, CollectorCommandV1) {
+/// Why a frame couldn't be decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeError {
+    /// There aren't enough bytes for the header, or for the payload it describes
+    TooShort,
+    BadMagic,
+    BadVersion,
+    BadCrc,
+    /// The frame was intact, but the payload didn't decompress or deserialize
+    BadPayload,
+    /// The header claims a payload far bigger than any we send
+    TooLarge,
+    /// Reading the frame failed. `UnexpectedEof` means the stream ended.
+    Io(std::io::ErrorKind),
+}
+
+impl From<std::io::Error> for DecodeError {
+    fn from(e: std::io::Error) -> Self {
+        DecodeError::Io(e.kind())
+    }
+}
+
+/// Check the header and CRC of the frame at the start of `bytes`, returning
+/// its timestamp, the (decompressed) payload and the frame's length.
+fn try_unpack_frame(bytes: &[u8]) -> Result<(u32, Cow<[u8]>, usize), DecodeError> {
+    match try_unpack_any_frame(bytes)? {
+        (VERSION_NUMBER, timestamp, payload, frame_length) => Ok((timestamp, payload, frame_length)),
+        _ => Err(DecodeError::BadVersion),
+    }
+}
+
+/// `try_unpack_frame` for any version we have a payload decoder for. The
+/// version comes first, without the compression flag.
+fn try_unpack_any_frame(bytes: &[u8]) -> Result<(u16, u32, Cow<[u8]>, usize), DecodeError> {
+    if bytes.len() < 12 {
+        return Err(DecodeError::TooShort);
+    }
     let magic_number = u16::from_be_bytes([bytes[0], bytes[1]]);
     let version_number = u16::from_be_bytes([bytes[2], bytes[3]]);
     let timestamp = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
-    let payload_size = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
-    let payload = &bytes[12..12 + payload_size as usize];
-    let crc = u32::from_be_bytes([
-        bytes[12 + payload_size as usize],
-        bytes[13 + payload_size as usize],
-        bytes[14 + payload_size as usize],
-        bytes[15 + payload_size as usize],
-    ]);
+    let payload_size = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
 
     // Verify the magic number
-    assert_eq!(magic_number, MAGIC_NUMBER);
+    if magic_number != MAGIC_NUMBER {
+        return Err(DecodeError::BadMagic);
+    }
 
     // Verify the version number
-    assert_eq!(version_number, VERSION_NUMBER);
+    let version = version_number & !COMPRESSED_FLAG;
+    if !is_known_version(version) {
+        return Err(DecodeError::BadVersion);
+    }
+
+    let frame_length = payload_size.checked_add(16).ok_or(DecodeError::TooShort)?;
+    if bytes.len() < frame_length {
+        return Err(DecodeError::TooShort);
+    }
+    let payload = &bytes[12..12 + payload_size];
+    let crc = u32::from_be_bytes([
+        bytes[12 + payload_size],
+        bytes[13 + payload_size],
+        bytes[14 + payload_size],
+        bytes[15 + payload_size],
+    ]);
+
+    // Verify the CRC (of the payload as sent)
+    if crc != crc32fast::hash(payload) {
+        return Err(DecodeError::BadCrc);
+    }
+
+    let payload = if version_number & COMPRESSED_FLAG != 0 {
+        Cow::Owned(miniz_oxide::inflate::decompress_to_vec(payload).map_err(|_| DecodeError::BadPayload)?)
+    } else {
+        Cow::Borrowed(payload)
+    };
+    Ok((version, timestamp, payload, frame_length))
+}
+
+/// Check a frame's header and CRC, returning its timestamp and the
+/// (decompressed) payload. Panics if the frame is damaged.
+fn unpack_frame(bytes: &[u8]) -> (u32, Cow<[u8]>) {
+    match try_unpack_frame(bytes) {
+        Ok((timestamp, payload, _)) => (timestamp, payload),
+        Err(e) => panic!("Invalid frame: {e:?}"),
+    }
+}
+
+/// Decode the frame at the start of `bytes`, returning its timestamp, the
+/// command and how many bytes the frame took up. Unlike `decode_v1`, bad
+/// input is an error rather than a panic.
+pub fn try_decode_v1(bytes: &[u8]) -> Result<(u32, CollectorCommandV1, usize), DecodeError> {
+    let (timestamp, payload, frame_length) = try_unpack_frame(bytes)?;
+    let command = bincode::deserialize(&payload).map_err(|_| DecodeError::BadPayload)?;
+    Ok((timestamp, command, frame_length))
+}
+
+/// Turns a frame's payload into a command, or `None` if it doesn't parse
+type PayloadDecoder = fn(&[u8]) -> Option<CollectorCommandV1>;
+
+/// The payload decoder for each version we understand
+const PAYLOAD_DECODERS: [(u16, PayloadDecoder); 3] = [
+    (VERSION_JSON, decode_version_1_payload),
+    (VERSION_BINCODE_V3, decode_version_3_payload),
+    (VERSION_NUMBER, decode_bincode_payload),
+];
+
+fn payload_decoder(version: u16) -> Option<PayloadDecoder> {
+    PAYLOAD_DECODERS
+        .iter()
+        .find(|(decoder_version, _)| *decoder_version == version)
+        .map(|(_, decoder)| *decoder)
+}
+
+/// A version (without the compression flag) that we can decode
+fn is_known_version(version: u16) -> bool {
+    version == VERSION_NUMBER | BATCH_FLAG
+        || version == VERSION_BINCODE_V3 | BATCH_FLAG
+        || payload_decoder(version).is_some()
+}
+
+fn decode_bincode_payload(payload: &[u8]) -> Option<CollectorCommandV1> {
+    bincode::deserialize(payload).ok()
+}
+
+/// `shared_v1` and `shared_v2` sent JSON, and the first `shared_v3`
+/// bincode. Neither knew anything newer than `SubmitData`, which hasn't
+/// changed since.
+fn decode_version_1_payload(payload: &[u8]) -> Option<CollectorCommandV1> {
+    if payload.first() == Some(&b'{') {
+        serde_json::from_slice(payload).ok()
+    } else {
+        decode_bincode_payload(payload)
+    }
+}
+
+/// The commands as version 3 sent them
+#[derive(Deserialize)]
+enum CommandV3 {
+    SubmitData {
+        collector_id: u128,
+        total_memory: u64,
+        used_memory: u64,
+        average_cpu_usage: f32,
+        per_core_cpu_usage: Vec<f32>,
+        bytes_received: u64,
+        bytes_transmitted: u64,
+    },
+    RequestWork(u128),
+    Hello {
+        collector_id: u128,
+        os: String,
+        arch: String,
+        cores: u32,
+    },
+    Ping {
+        collector_id: u128,
+        nonce: u32,
+    },
+}
+
+impl From<CommandV3> for CollectorCommandV1 {
+    fn from(command: CommandV3) -> Self {
+        match command {
+            // Without per-core figures, the average is all we have
+            CommandV3::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage, per_core_cpu_usage, .. }
+                if per_core_cpu_usage.is_empty() =>
+            {
+                CollectorCommandV1::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage }
+            }
+            CommandV3::SubmitData {
+                collector_id,
+                total_memory,
+                used_memory,
+                per_core_cpu_usage,
+                bytes_received,
+                bytes_transmitted,
+                ..
+            } => CollectorCommandV1::SubmitDataV2 {
+                collector_id,
+                total_memory,
+                used_memory,
+                cpu_usage: per_core_cpu_usage,
+                bytes_received,
+                bytes_transmitted,
+            },
+            CommandV3::RequestWork(collector_id) => CollectorCommandV1::RequestWork(collector_id),
+            CommandV3::Hello { collector_id, os, arch, cores } => CollectorCommandV1::Hello { collector_id, os, arch, cores },
+            CommandV3::Ping { collector_id, nonce } => CollectorCommandV1::Ping { collector_id, nonce },
+        }
+    }
+}
 
-    // Verify the CRC
-    let computed_crc = crc32fast::hash(payload);
-    assert_eq!(crc, computed_crc);
+fn decode_version_3_payload(payload: &[u8]) -> Option<CollectorCommandV1> {
+    bincode::deserialize::<CommandV3>(payload).ok().map(CollectorCommandV1::from)
+}
+
+/// `try_decode_v1` for a frame of any version, also returning the version.
+fn try_decode_any(bytes: &[u8]) -> Result<(u16, u32, CollectorCommandV1, usize), DecodeError> {
+    let (version, timestamp, payload, frame_length) = try_unpack_any_frame(bytes)?;
+    let decoder = payload_decoder(version).ok_or(DecodeError::BadVersion)?;
+    let command = decoder(&payload).ok_or(DecodeError::BadPayload)?;
+    Ok((version, timestamp, command, frame_length))
+}
+
+/// Decode a frame from any collector: JSON or bincode from version 1, and
+/// bincode from versions 3 and 4. Returns the version, so the caller can tell which it was,
+/// along with the timestamp and command.
+pub fn decode_any(bytes: &[u8]) -> Result<(u16, u32, CollectorCommandV1), DecodeError> {
+    let (version, timestamp, command, _) = try_decode_any(bytes)?;
+    Ok((version, timestamp, command))
+}
+
+/// Decode a frame holding either one command or a batch of them, each with
+/// its timestamp
+fn try_decode_commands(bytes: &[u8]) -> Result<(Vec<(u32, CollectorCommandV1)>, usize), DecodeError> {
+    let (version, timestamp, payload, frame_length) = try_unpack_any_frame(bytes)?;
+    let commands = if version == VERSION_NUMBER | BATCH_FLAG {
+        bincode::deserialize(&payload).map_err(|_| DecodeError::BadPayload)?
+    } else if version == VERSION_BINCODE_V3 | BATCH_FLAG {
+        // Version 3 batches only had the one timestamp
+        let commands: Vec<CommandV3> = bincode::deserialize(&payload).map_err(|_| DecodeError::BadPayload)?;
+        commands.into_iter().map(|command| (timestamp, command.into())).collect()
+    } else {
+        let decoder = payload_decoder(version).ok_or(DecodeError::BadVersion)?;
+        vec![(timestamp, decoder(&payload).ok_or(DecodeError::BadPayload)?)]
+    };
+    Ok((commands, frame_length))
+}
+
+/// Decode a frame from `encode_batch_v1`, returning every command in it with
+/// its timestamp. A single-command frame comes back as a batch of one.
+/// Panics if the frame is damaged.
+pub fn decode_batch_v1(bytes: &[u8]) -> Vec<(u32, CollectorCommandV1)> {
+    match try_decode_commands(bytes) {
+        Ok((commands, _)) => commands,
+        Err(e) => panic!("Invalid frame: {e:?}"),
+    }
+}
+
+/// Every frame found in a run of bytes, and how many bytes were skipped
+/// because they weren't part of a valid frame.
+#[derive(Debug, Default, PartialEq)]
+pub struct DecodedStream {
+    pub frames: Vec<(u32, CollectorCommandV1)>,
+    pub skipped: usize,
+}
+
+/// The position of the next magic number at or after `from`
+fn find_magic_number(bytes: &[u8], from: usize) -> Option<usize> {
+    let magic = MAGIC_NUMBER.to_be_bytes();
+    bytes.get(from..)?.windows(2).position(|window| window == magic).map(|position| from + position)
+}
+
+/// Decode one frame after another, of any version. Batches are flattened,
+/// each command keeping its own timestamp. When something doesn't
+/// decode, skip ahead to the next magic number and carry on from there - so
+/// one bad byte costs a frame, not the rest of the connection.
+pub fn decode_stream_v1(bytes: &[u8]) -> DecodedStream {
+    let frames = decode_frames_v1(bytes);
+    DecodedStream {
+        frames: frames.frames.into_iter().flatten().collect(),
+        skipped: frames.skipped,
+    }
+}
+
+/// Like `DecodedStream`, but with the commands of each frame kept together
+#[derive(Debug, Default, PartialEq)]
+pub struct DecodedFrames {
+    pub frames: Vec<Vec<(u32, CollectorCommandV1)>>,
+    pub skipped: usize,
+}
+
+/// Decode every frame in `bytes`, like `decode_stream_v1`, but with one
+/// entry per frame - a batch stays together, so it can be answered once.
+pub fn decode_frames_v1(bytes: &[u8]) -> DecodedFrames {
+    let mut decoded = DecodedFrames::default();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        match try_decode_commands(&bytes[offset..]) {
+            Ok((commands, frame_length)) => {
+                decoded.frames.push(commands);
+                offset += frame_length;
+            }
+            Err(_) => {
+                let next = find_magic_number(bytes, offset + 1).unwrap_or(bytes.len());
+                decoded.skipped += next - offset;
+                offset = next;
+            }
+        }
+    }
+    decoded
+}
+
+pub fn decode_v1(bytes: &[u8]) -> (u32, CollectorCommandV1) {
+    let (timestamp, payload) = unpack_frame(bytes);
 
     // Decode the payload
-    (timestamp, bincode::deserialize(payload).unwrap())
+    (timestamp, bincode::deserialize(&payload).unwrap())
+}
+
+/// The fields of `CollectorCommandV1::SubmitDataV2`, as a struct that can
+/// be decoded into over and over without allocating.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SubmitDataV2 {
+    pub collector_id: u128,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    pub cpu_usage: Vec<f32>,
+    pub bytes_received: u64,
+    pub bytes_transmitted: u64,
+}
+
+impl From<SubmitDataV2> for CollectorCommandV1 {
+    fn from(data: SubmitDataV2) -> Self {
+        CollectorCommandV1::SubmitDataV2 {
+            collector_id: data.collector_id,
+            total_memory: data.total_memory,
+            used_memory: data.used_memory,
+            cpu_usage: data.cpu_usage,
+            bytes_received: data.bytes_received,
+            bytes_transmitted: data.bytes_transmitted,
+        }
+    }
+}
+
+/// The result of `decode_submit_into`.
+#[derive(Debug, PartialEq)]
+pub enum DecodedInto {
+    /// The frame was `SubmitDataV2`, and the buffer now holds it
+    Submit { timestamp: u32 },
+    /// Any other command, decoded the usual way
+    Other { timestamp: u32, command: CollectorCommandV1 },
+}
+
+/// Decode a frame, filling `data` in place if it's `SubmitDataV2` - the hot
+/// path. The per-core vector is reused, so once it has grown to fit there
+/// are no allocations. Other commands fall back to `decode_v1`.
+pub fn decode_submit_into(bytes: &[u8], data: &mut SubmitDataV2) -> DecodedInto {
+    let (timestamp, payload) = unpack_frame(bytes);
+    if read_submit_data(&payload, data).is_some() {
+        DecodedInto::Submit { timestamp }
+    } else {
+        DecodedInto::Other { timestamp, command: bincode::deserialize(&payload).unwrap() }
+    }
+}
+
+/// The position of `SubmitDataV2` in `CollectorCommandV1`, which bincode
+/// uses to tell the variants apart
+const SUBMIT_DATA_V2_VARIANT: u32 = 4;
+
+/// Read bincode's encoding of `SubmitDataV2` by hand: a little-endian `u32`
+/// variant index, then each field in order. Vectors are a `u64` length
+/// followed by the items. Returns `None` if it's some other variant.
+fn read_submit_data(payload: &[u8], data: &mut SubmitDataV2) -> Option<()> {
+    fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+        if bytes.len() < N {
+            return None;
+        }
+        let (head, rest) = bytes.split_at(N);
+        *bytes = rest;
+        head.try_into().ok()
+    }
+
+    let mut bytes = payload;
+    if u32::from_le_bytes(take(&mut bytes)?) != SUBMIT_DATA_V2_VARIANT {
+        return None;
+    }
+    data.collector_id = u128::from_le_bytes(take(&mut bytes)?);
+    data.total_memory = u64::from_le_bytes(take(&mut bytes)?);
+    data.used_memory = u64::from_le_bytes(take(&mut bytes)?);
+    let cores = u64::from_le_bytes(take(&mut bytes)?) as usize;
+    // Don't trust the length until we know the bytes are there
+    if bytes.len() < cores.checked_mul(4)? {
+        return None;
+    }
+    data.cpu_usage.clear();
+    for _ in 0..cores {
+        data.cpu_usage.push(f32::from_le_bytes(take(&mut bytes)?));
+    }
+    data.bytes_received = u64::from_le_bytes(take(&mut bytes)?);
+    data.bytes_transmitted = u64::from_le_bytes(take(&mut bytes)?);
+    bytes.is_empty().then_some(())
+}
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+/// The size of the signature `sign_frame` appends
+pub const HMAC_SIZE: usize = 32;
+
+/// The shared secret for signing frames, from `COLLECTOR_HMAC_KEY`.
+/// `None` if it isn't set, in which case frames aren't signed or checked.
+pub fn hmac_key_from_env() -> Option<Vec<u8>> {
+    std::env::var("COLLECTOR_HMAC_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(String::into_bytes)
+}
+
+/// Append an HMAC-SHA256 of the whole frame, proving it came from someone
+/// who knows the key. The CRC only catches accidents; this catches forgery.
+pub fn sign_frame(mut frame: Vec<u8>, key: &[u8]) -> Vec<u8> {
+    use hmac::Mac;
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(&frame);
+    frame.extend_from_slice(&mac.finalize().into_bytes());
+    frame
+}
+
+/// Check the HMAC on the end of a signed frame, returning the frame
+/// without it - or `None` if the signature is missing or wrong.
+pub fn verify_frame<'a>(bytes: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    use hmac::Mac;
+    let (frame, tag) = bytes.split_at(bytes.len().checked_sub(HMAC_SIZE)?);
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(frame);
+    // verify_slice compares in constant time
+    mac.verify_slice(tag).ok()?;
+    Some(frame)
+}
+
+/// No frame we send comes close to this. A bigger size means we're reading
+/// junk, and shouldn't allocate for it.
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long the header is, from the magic number and version at the start
+/// of it. Fragments have a longer header than ordinary frames.
+fn frame_header_length(start: &[u8]) -> Result<usize, DecodeError> {
+    let magic_number = u16::from_be_bytes([start[0], start[1]]);
+    let version_number = u16::from_be_bytes([start[2], start[3]]);
+    if magic_number != MAGIC_NUMBER {
+        return Err(DecodeError::BadMagic);
+    }
+    if version_number & !fragment::FRAGMENT_FLAG == VERSION_NUMBER && version_number & fragment::FRAGMENT_FLAG != 0 {
+        Ok(fragment::HEADER_SIZE)
+    } else if is_known_version(version_number & !COMPRESSED_FLAG) {
+        Ok(12)
+    } else {
+        Err(DecodeError::BadVersion)
+    }
+}
+
+/// Both kinds of header end with the payload size
+fn frame_payload_size(header: &[u8]) -> Result<usize, DecodeError> {
+    let size_bytes = &header[header.len() - 4..];
+    let payload_size = u32::from_be_bytes([size_bytes[0], size_bytes[1], size_bytes[2], size_bytes[3]]) as usize;
+    if payload_size > MAX_PAYLOAD_SIZE {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(payload_size)
+}
+
+/// Read exactly one frame (or fragment) from a stream: the header, then as
+/// many bytes as it says the payload and CRC need. However the bytes are
+/// split up on the way, the result is the whole frame - ready for
+/// `decode_v1` or `fragment::decode_fragment`. The CRC isn't checked here.
+pub fn read_frame<R: std::io::Read>(reader: &mut R) -> Result<Vec<u8>, DecodeError> {
+    let mut frame = vec![0; 4];
+    reader.read_exact(&mut frame)?;
+    let header_length = frame_header_length(&frame)?;
+    frame.resize(header_length, 0);
+    reader.read_exact(&mut frame[4..])?;
+    let payload_size = frame_payload_size(&frame)?;
+    frame.resize(header_length + payload_size + 4, 0);
+    reader.read_exact(&mut frame[header_length..])?;
+    Ok(frame)
+}
+
+/// `read_frame`, for tokio streams
+#[cfg(feature = "tokio")]
+pub async fn read_frame_async<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, DecodeError> {
+    use tokio::io::AsyncReadExt;
+    let mut frame = vec![0; 4];
+    reader.read_exact(&mut frame).await?;
+    let header_length = frame_header_length(&frame)?;
+    frame.resize(header_length, 0);
+    reader.read_exact(&mut frame[4..]).await?;
+    let payload_size = frame_payload_size(&frame)?;
+    frame.resize(header_length + payload_size + 4, 0);
+    reader.read_exact(&mut frame[header_length..]).await?;
+    Ok(frame)
 }
 
 pub fn encode_response_v1(command: CollectorResponseV1) -> Vec<u8> {
@@ -90,17 +744,99 @@ pub fn decode_response_v1(bytes: &[u8]) -> CollectorResponseV1 {
     bincode::deserialize(bytes).unwrap()
 }
 
+/// Decode every response in `bytes`. A `Push` from the server can arrive
+/// in the same read as the reply we were waiting for.
+pub fn decode_responses_v1(bytes: &[u8]) -> Vec<CollectorResponseV1> {
+    let mut reader = bytes;
+    let mut responses = Vec::new();
+    while !reader.is_empty() {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(response) => responses.push(response),
+            Err(_) => break,
+        }
+    }
+    responses
+}
+
+/// Why `request_work` didn't get an answer
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// Writing the request failed
+    Send(std::io::ErrorKind),
+    /// Reading the reply failed
+    Receive(std::io::ErrorKind),
+    /// The server hung up without replying
+    Closed,
+    /// The reply didn't decode
+    BadResponse,
+    /// A reply that doesn't answer a work request
+    Unexpected(CollectorResponseV1),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Send(kind) => write!(f, "unable to send the request: {kind}"),
+            ProtocolError::Receive(kind) => write!(f, "unable to read the reply: {kind}"),
+            ProtocolError::Closed => write!(f, "the server closed the connection"),
+            ProtocolError::BadResponse => write!(f, "the reply didn't decode"),
+            ProtocolError::Unexpected(response) => write!(f, "unexpected reply: {response:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Ask the server for work, returning the task it hands out - or `None` if
+/// there isn't any. The request is signed if there's an `hmac_key`.
+/// A task pushed while we wait is returned if the reply itself has none,
+/// so it isn't lost.
+pub fn request_work<S: std::io::Read + std::io::Write>(
+    stream: &mut S,
+    collector_id: u128,
+    hmac_key: Option<&[u8]>,
+) -> Result<Option<TaskType>, ProtocolError> {
+    let mut frame = encode_v1(&CollectorCommandV1::RequestWork(collector_id));
+    if let Some(key) = hmac_key {
+        frame = sign_frame(frame, key);
+    }
+    stream.write_all(&frame).map_err(|e| ProtocolError::Send(e.kind()))?;
+
+    let mut pushed = None;
+    let mut buf = vec![0u8; 512];
+    loop {
+        let bytes_read = stream.read(&mut buf).map_err(|e| ProtocolError::Receive(e.kind()))?;
+        if bytes_read == 0 {
+            return Err(ProtocolError::Closed);
+        }
+        let responses = decode_responses_v1(&buf[..bytes_read]);
+        if responses.is_empty() {
+            return Err(ProtocolError::BadResponse);
+        }
+        for response in responses {
+            match response {
+                CollectorResponseV1::Push(task) => pushed = pushed.or(Some(task)),
+                CollectorResponseV1::Task(task) => return Ok(Some(task)),
+                CollectorResponseV1::NoWork => return Ok(pushed),
+                unexpected => return Err(ProtocolError::Unexpected(unexpected)),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_encode_decode() {
-        let command = CollectorCommandV1::SubmitData {
+        let command = CollectorCommandV1::SubmitDataV2 {
             collector_id: 123123123123213123123123123123123,
             total_memory: 100,
             used_memory: 50,
-            average_cpu_usage: 0.5,
+            cpu_usage: vec![0.25, 0.75],
+            bytes_received: 1024,
+            bytes_transmitted: 2048,
         };
         let encoded = encode_v1(&command);
         let (timestamp, decoded) = decode_v1(&encoded);
@@ -108,6 +844,476 @@ mod tests {
         assert!(timestamp > 0);
     }
 
+    #[test]
+    fn test_compressed_round_trip() {
+        let command = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory: 50,
+            // Lots of repetition, so it compresses well
+            cpu_usage: vec![0.5; 64],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        let plain = encode_v1(&command);
+        let compressed = encode_v1_compressed(&command);
+        assert!(compressed.len() < plain.len());
+        assert_eq!(decode_v1(&compressed).1, command);
+
+        // Damage to the compressed bytes fails the CRC, before inflating
+        let mut damaged = compressed.clone();
+        damaged[14] ^= 0xff;
+        assert_eq!(try_decode_v1(&damaged), Err(DecodeError::BadCrc));
+    }
+
+    #[test]
+    fn test_decode_submit_into() {
+        let command = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 123123123123213123123123123123123,
+            total_memory: 100,
+            used_memory: 50,
+            cpu_usage: vec![0.25, 0.75, 0.5],
+            bytes_received: 1024,
+            bytes_transmitted: 2048,
+        };
+        let mut data = SubmitDataV2::default();
+        for encoded in [encode_v1(&command), encode_v1_compressed(&command)] {
+            let (timestamp, decoded) = decode_v1(&encoded);
+            assert_eq!(decode_submit_into(&encoded, &mut data), DecodedInto::Submit { timestamp });
+            assert_eq!(CollectorCommandV1::from(data.clone()), decoded);
+        }
+
+        // Reusing the buffer for a frame with fewer cores
+        let smaller = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 2,
+            used_memory: 1,
+            cpu_usage: vec![9.0],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        decode_submit_into(&encode_v1(&smaller), &mut data);
+        assert_eq!(CollectorCommandV1::from(data.clone()), smaller);
+
+        // Anything else falls back to the normal decoder
+        let ping = CollectorCommandV1::Ping { collector_id: 42, nonce: 7 };
+        match decode_submit_into(&encode_v1(&ping), &mut data) {
+            DecodedInto::Other { command, .. } => assert_eq!(command, ping),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_resync_after_junk() {
+        let first = CollectorCommandV1::RequestWork(1);
+        let second = CollectorCommandV1::Ping { collector_id: 2, nonce: 3 };
+        // Junk that even starts with the magic number, to make it harder
+        let junk = [4, 210, 0, 1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 9, 9, 9];
+
+        let mut bytes = encode_v1(&first);
+        bytes.extend_from_slice(&junk);
+        bytes.extend_from_slice(&encode_v1_compressed(&second));
+        let decoded = decode_stream_v1(&bytes);
+        let commands: Vec<CollectorCommandV1> = decoded.frames.into_iter().map(|(_, command)| command).collect();
+        assert_eq!(commands, vec![first.clone(), second]);
+        assert_eq!(decoded.skipped, junk.len());
+
+        // A damaged frame is skipped, not fatal
+        let mut damaged = encode_v1(&first);
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        assert_eq!(try_decode_v1(&damaged), Err(DecodeError::BadCrc));
+        let decoded = decode_stream_v1(&damaged);
+        assert!(decoded.frames.is_empty());
+        assert_eq!(decoded.skipped, damaged.len());
+        assert_eq!(try_decode_v1(&damaged[..5]), Err(DecodeError::TooShort));
+    }
+
+    #[test]
+    fn test_batch_round_trip() {
+        let batch = vec![
+            (100, CollectorCommandV1::SubmitDataV2 {
+                collector_id: 1,
+                total_memory: 100,
+                used_memory: 50,
+                cpu_usage: vec![0.25, 0.75],
+                bytes_received: 10,
+                bytes_transmitted: 20,
+            }),
+            (101, CollectorCommandV1::RequestWork(1)),
+            (102, CollectorCommandV1::SubmitDataV2 {
+                collector_id: 1,
+                total_memory: 100,
+                used_memory: 60,
+                cpu_usage: vec![0.5, 0.9],
+                bytes_received: 0,
+                bytes_transmitted: 0,
+            }),
+        ];
+        // Each command keeps its own timestamp
+        let encoded = encode_batch_v1(&batch);
+        assert_eq!(decode_batch_v1(&encoded), batch);
+        assert_eq!(read_frame(&mut encoded.as_slice()).unwrap(), encoded);
+        let compressed = encode_batch_v1_compressed(&batch);
+        assert_eq!(decode_batch_v1(&compressed), batch);
+        assert_eq!(decode_stream_v1(&compressed).frames, batch);
+
+        // The single-command decoders don't mistake a batch for a command
+        assert_eq!(try_decode_v1(&encoded), Err(DecodeError::BadVersion));
+        assert_eq!(decode_any(&encoded), Err(DecodeError::BadVersion));
+
+        // A single command is a batch of one, and streams flatten batches
+        let ping = encode_v1(&CollectorCommandV1::Ping { collector_id: 1, nonce: 2 });
+        assert_eq!(decode_batch_v1(&ping).len(), 1);
+        let stream = [ping, encoded].concat();
+        assert_eq!(decode_stream_v1(&stream).frames.len(), 4);
+        let frames = decode_frames_v1(&stream).frames;
+        assert_eq!(frames.iter().map(Vec::len).collect::<Vec<_>>(), vec![1, 3]);
+    }
+
+    /// A frame the way `shared_v2` encodes it, with a JSON payload
+    fn json_frame(json: &str) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&MAGIC_NUMBER.to_be_bytes());
+        frame.extend_from_slice(&VERSION_JSON.to_be_bytes());
+        frame.extend_from_slice(&1_686_000_000u32.to_be_bytes());
+        frame.extend_from_slice(&(json.len() as u32).to_be_bytes());
+        frame.extend_from_slice(json.as_bytes());
+        frame.extend_from_slice(&crc32fast::hash(json.as_bytes()).to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_decode_any() {
+        let old = json_frame(
+            r#"{"SubmitData":{"collector_id":1234,"total_memory":100,"used_memory":50,"average_cpu_usage":0.5}}"#,
+        );
+        let (version, timestamp, command) = decode_any(&old).unwrap();
+        assert_eq!((version, timestamp), (1, 1_686_000_000));
+        assert_eq!(
+            command,
+            CollectorCommandV1::SubmitData {
+                collector_id: 1234,
+                total_memory: 100,
+                used_memory: 50,
+                average_cpu_usage: 0.5,
+            }
+        );
+        assert_eq!(read_frame(&mut old.as_slice()).unwrap(), old);
+
+        let ping = CollectorCommandV1::Ping { collector_id: 1, nonce: 2 };
+        for new in [encode_v1(&ping), encode_v1_compressed(&ping)] {
+            let (version, _timestamp, command) = decode_any(&new).unwrap();
+            assert_eq!((version, command), (4, ping.clone()));
+        }
+
+        // Both kinds can arrive on the same connection
+        let mut mixed = old.clone();
+        mixed.extend(encode_v1(&ping));
+        assert_eq!(decode_stream_v1(&mixed).frames.len(), 2);
+
+        // Only the bincode decoder accepts old frames
+        assert_eq!(try_decode_v1(&old), Err(DecodeError::BadVersion));
+        assert_eq!(decode_any(&json_frame("not json")), Err(DecodeError::BadPayload));
+        let mut unknown = encode_v1(&ping);
+        unknown[2..4].copy_from_slice(&2u16.to_be_bytes());
+        assert_eq!(decode_any(&unknown), Err(DecodeError::BadVersion));
+    }
+
+    /// A bincode frame with the given version, built by hand
+    fn bincode_frame(version: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&MAGIC_NUMBER.to_be_bytes());
+        frame.extend_from_slice(&version.to_be_bytes());
+        frame.extend_from_slice(&1_686_000_000u32.to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_decode_old_bincode_frames() {
+        // The first shared_v3 collectors: version 1, and SubmitData with a
+        // single average
+        let mut payload = 0u32.to_le_bytes().to_vec();
+        payload.extend_from_slice(&1234u128.to_le_bytes());
+        payload.extend_from_slice(&100u64.to_le_bytes());
+        payload.extend_from_slice(&50u64.to_le_bytes());
+        payload.extend_from_slice(&0.5f32.to_le_bytes());
+        let old = CollectorCommandV1::SubmitData {
+            collector_id: 1234,
+            total_memory: 100,
+            used_memory: 50,
+            average_cpu_usage: 0.5,
+        };
+        assert_eq!(decode_any(&bincode_frame(1, &payload)).unwrap(), (1, 1_686_000_000, old.clone()));
+        // It's still encoded the same way today
+        assert_eq!(bincode::serialize(&old).unwrap(), payload);
+
+        // Version 3 squeezed per-core usage and the network counters in
+        payload.extend_from_slice(&2u64.to_le_bytes());
+        payload.extend_from_slice(&0.25f32.to_le_bytes());
+        payload.extend_from_slice(&0.75f32.to_le_bytes());
+        payload.extend_from_slice(&10u64.to_le_bytes());
+        payload.extend_from_slice(&20u64.to_le_bytes());
+        let v2 = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1234,
+            total_memory: 100,
+            used_memory: 50,
+            cpu_usage: vec![0.25, 0.75],
+            bytes_received: 10,
+            bytes_transmitted: 20,
+        };
+        let frame = bincode_frame(3, &payload);
+        assert_eq!(decode_any(&frame).unwrap(), (3, 1_686_000_000, v2.clone()));
+        assert_eq!(try_decode_commands(&frame).unwrap().0, vec![(1_686_000_000, v2)]);
+        // Only the current version is accepted by the bincode decoder
+        assert_eq!(try_decode_v1(&frame), Err(DecodeError::BadVersion));
+
+        // Other version 3 commands come through unchanged
+        let ping = [3u32.to_le_bytes().as_slice(), &42u128.to_le_bytes(), &7u32.to_le_bytes()].concat();
+        let (_, _, command) = decode_any(&bincode_frame(3, &ping)).unwrap();
+        assert_eq!(command, CollectorCommandV1::Ping { collector_id: 42, nonce: 7 });
+    }
+
+    /// Hands out one byte per read, like a very slow network
+    struct OneByteAtATime<'a>(&'a [u8]);
+
+    impl std::io::Read for OneByteAtATime<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match (self.0.split_first(), buf.is_empty()) {
+                (Some((byte, rest)), false) => {
+                    buf[0] = *byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    fn frames_for_reading() -> Vec<Vec<u8>> {
+        let ping = encode_v1(&CollectorCommandV1::Ping { collector_id: 1, nonce: 2 });
+        let compressed = encode_v1_compressed(&CollectorCommandV1::RequestWork(3));
+        let fragments = fragment::split_frame(&ping, 1, 1, 10);
+        let mut frames = vec![ping, compressed];
+        frames.extend(fragments);
+        frames
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let frames = frames_for_reading();
+        let stream = frames.concat();
+        let mut reader = OneByteAtATime(&stream);
+        for frame in &frames {
+            assert_eq!(&read_frame(&mut reader).unwrap(), frame);
+        }
+        assert_eq!(read_frame(&mut reader), Err(DecodeError::Io(std::io::ErrorKind::UnexpectedEof)));
+
+        let mut junk: &[u8] = &[1, 2, 3, 4, 5, 6];
+        assert_eq!(read_frame(&mut junk), Err(DecodeError::BadMagic));
+        let mut huge = encode_v1(&CollectorCommandV1::RequestWork(3));
+        huge[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(read_frame(&mut huge.as_slice()), Err(DecodeError::TooLarge));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_read_frame_async() {
+        let frames = frames_for_reading();
+        let stream = frames.concat();
+        let mut reader = stream.as_slice();
+        for frame in &frames {
+            assert_eq!(&read_frame_async(&mut reader).await.unwrap(), frame);
+        }
+        assert_eq!(read_frame_async(&mut reader).await, Err(DecodeError::Io(std::io::ErrorKind::UnexpectedEof)));
+    }
+
+    #[test]
+    fn test_hmac() {
+        let ping = CollectorCommandV1::Ping { collector_id: 42, nonce: 1234 };
+        let signed = sign_frame(encode_v1(&ping), b"right key");
+
+        let frame = verify_frame(&signed, b"right key").unwrap();
+        assert_eq!(decode_v1(frame).1, ping);
+
+        assert!(verify_frame(&signed, b"wrong key").is_none());
+        assert!(verify_frame(&sign_frame(encode_v1(&ping), b"wrong key"), b"right key").is_none());
+        // An unsigned frame doesn't pass either
+        assert!(verify_frame(&encode_v1(&ping), b"right key").is_none());
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        let ping = CollectorCommandV1::Ping { collector_id: 42, nonce: 1234 };
+        let (_timestamp, decoded) = decode_v1(&encode_v1(&ping));
+        assert_eq!(decoded, ping);
+
+        let pong = CollectorResponseV1::Pong(1234);
+        assert_eq!(decode_response_v1(&encode_response_v1(pong.clone())), pong);
+    }
+
+    #[test]
+    fn test_validate_command() {
+        let submit = |collector_id, used_memory, usage| CollectorCommandV1::SubmitDataV2 {
+            collector_id,
+            total_memory: 100,
+            used_memory,
+            cpu_usage: vec![50.0, usage],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        assert_eq!(validate_command(&submit(1, 100, 0.0)), Ok(()));
+        assert_eq!(validate_command(&submit(1, 0, 100.0)), Ok(()));
+        assert_eq!(validate_command(&submit(0, 50, 50.0)), Err(ValidationError::ZeroCollectorId));
+        assert_eq!(
+            validate_command(&submit(1, 101, 50.0)),
+            Err(ValidationError::UsedMemoryOverTotal { used_memory: 101, total_memory: 100 })
+        );
+        assert_eq!(validate_command(&submit(1, 50, 100.5)), Err(ValidationError::CpuOutOfRange(100.5)));
+        assert_eq!(validate_command(&submit(1, 50, -1.0)), Err(ValidationError::CpuOutOfRange(-1.0)));
+        // NaN never equals itself, so match on it instead
+        assert!(matches!(validate_command(&submit(1, 50, f32::NAN)), Err(ValidationError::CpuOutOfRange(usage)) if usage.is_nan()));
+
+        // The old sample is checked the same way
+        let old = |used_memory, average_cpu_usage| CollectorCommandV1::SubmitData {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory,
+            average_cpu_usage,
+        };
+        assert_eq!(validate_command(&old(50, 50.0)), Ok(()));
+        assert_eq!(
+            validate_command(&old(101, 50.0)),
+            Err(ValidationError::UsedMemoryOverTotal { used_memory: 101, total_memory: 100 })
+        );
+        assert_eq!(validate_command(&old(50, 101.0)), Err(ValidationError::CpuOutOfRange(101.0)));
+
+        assert_eq!(validate_command(&CollectorCommandV1::RequestWork(0)), Err(ValidationError::ZeroCollectorId));
+        assert_eq!(validate_command(&CollectorCommandV1::Ping { collector_id: 7, nonce: 0 }), Ok(()));
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(1024 * 1024 - 1), "1.0 MiB");
+        assert_eq!(format_bytes(1024 * 1024), "1.0 MiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 + 512 * 1024), "5.5 MiB");
+        assert_eq!(format_bytes(1024 * 1024 * 1024), "1.0 GiB");
+        assert_eq!(format_bytes(16 * 1024 * 1024 * 1024), "16.0 GiB");
+    }
+
+    #[test]
+    fn test_display() {
+        let command = CollectorCommandV1::SubmitData {
+            collector_id: 1,
+            total_memory: 100 * 1024 * 1024,
+            used_memory: 50 * 1024 * 1024,
+            average_cpu_usage: 42.0,
+        };
+        assert_eq!(
+            command.to_string(),
+            "collector 00000000-0000-0000-0000-000000000001: cpu 42.0%, mem 50.0 MiB/100.0 MiB"
+        );
+        let command = CollectorCommandV1::SubmitDataV2 {
+            collector_id: 1,
+            total_memory: 100 * 1024 * 1024,
+            used_memory: 50 * 1024 * 1024,
+            cpu_usage: vec![40.0, 44.0],
+            bytes_received: 0,
+            bytes_transmitted: 0,
+        };
+        assert_eq!(
+            command.to_string(),
+            "collector 00000000-0000-0000-0000-000000000001: cpu 42.0% over 2 cores, mem 50.0 MiB/100.0 MiB"
+        );
+        assert_eq!(
+            CollectorCommandV1::RequestWork(0xff).to_string(),
+            "collector 00000000-0000-0000-0000-0000000000ff: requesting work"
+        );
+    }
+
+    #[test]
+    fn test_decode_several_responses() {
+        let mut bytes = encode_response_v1(CollectorResponseV1::Push(TaskType::Shutdown));
+        bytes.extend(encode_response_v1(CollectorResponseV1::Ack));
+        assert_eq!(
+            decode_responses_v1(&bytes),
+            vec![CollectorResponseV1::Push(TaskType::Shutdown), CollectorResponseV1::Ack]
+        );
+    }
+
+    /// Replies with canned bytes, and keeps whatever is written to it
+    struct FakeServer {
+        replies: Vec<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl std::io::Read for FakeServer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.replies.is_empty() {
+                return Ok(0);
+            }
+            let reply = self.replies.remove(0);
+            buf[..reply.len()].copy_from_slice(&reply);
+            Ok(reply.len())
+        }
+    }
+
+    impl std::io::Write for FakeServer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request_work_with(replies: Vec<CollectorResponseV1>) -> Result<Option<TaskType>, ProtocolError> {
+        let replies = replies.into_iter().map(encode_response_v1).collect();
+        let mut server = FakeServer { replies, written: Vec::new() };
+        let result = request_work(&mut server, 42, None);
+        assert_eq!(decode_v1(&server.written).1, CollectorCommandV1::RequestWork(42));
+        result
+    }
+
+    #[test]
+    fn test_signed_work_request() {
+        let key = b"a key for testing";
+        let mut server = FakeServer { replies: vec![encode_response_v1(CollectorResponseV1::NoWork)], written: Vec::new() };
+        assert_eq!(request_work(&mut server, 42, Some(key)), Ok(None));
+
+        // The server can check it came from us
+        assert_eq!(server.written.len(), encode_v1(&CollectorCommandV1::RequestWork(42)).len() + HMAC_SIZE);
+        let frame = verify_frame(&server.written, key).unwrap();
+        assert_eq!(decode_v1(frame).1, CollectorCommandV1::RequestWork(42));
+        assert_eq!(verify_frame(&server.written, b"some other key"), None);
+    }
+
+    #[test]
+    fn test_request_work() {
+        assert_eq!(request_work_with(vec![CollectorResponseV1::NoWork]), Ok(None));
+        assert_eq!(request_work_with(vec![CollectorResponseV1::Task(TaskType::Shutdown)]), Ok(Some(TaskType::Shutdown)));
+        assert_eq!(
+            request_work_with(vec![CollectorResponseV1::Ack]),
+            Err(ProtocolError::Unexpected(CollectorResponseV1::Ack))
+        );
+        assert_eq!(request_work_with(vec![]), Err(ProtocolError::Closed));
+
+        // A push while we wait isn't lost, even if the reply is in another read
+        assert_eq!(
+            request_work_with(vec![CollectorResponseV1::Push(TaskType::Shutdown), CollectorResponseV1::NoWork]),
+            Ok(Some(TaskType::Shutdown))
+        );
+    }
+
     #[test]
     fn test_encode_decode_response() {
         let response = CollectorResponseV1::Ack;
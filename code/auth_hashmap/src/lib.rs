@@ -9,6 +9,32 @@ pub fn read_line() -> String {
     input.trim().to_string()
 }
 
+/// Hash a password with a new random salt. The result is `salt$hash`, so
+/// two users with the same password don't end up with the same hash.
+pub fn hash_password(password: &str) -> String {
+    use std::hash::{BuildHasher, Hasher};
+    // Each RandomState has its own random keys, so this differs every time
+    let salt = format!("{:016X}", std::collections::hash_map::RandomState::new().build_hasher().finish());
+    let hash = salted_hash(&salt, password);
+    format!("{salt}${hash}")
+}
+
+fn salted_hash(salt: &str, password: &str) -> String {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(salt);
+    hasher.update(password);
+    format!("{:X}", hasher.finalize())
+}
+
+/// Does `candidate` match a hash from `hash_password`?
+pub fn verify_password(hash: &str, candidate: &str) -> bool {
+    match hash.split_once('$') {
+        Some((salt, hash)) => salted_hash(salt, candidate) == hash,
+        None => false,
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum LoginAction {
     Granted(LoginRole),
@@ -24,6 +50,7 @@ pub enum LoginRole {
 #[derive(Debug, Clone)]
 pub struct User {
     pub username: String,
+    /// From `hash_password` - never the password itself
     pub password: String,
     pub role: LoginRole,
 }
@@ -32,7 +59,7 @@ impl User {
     pub fn new(username: &str, password: &str, role: LoginRole) -> User {
         User {
             username: username.to_lowercase(),
-            password: password.to_string(),
+            password: hash_password(password),
             role,
         }
     }
@@ -45,11 +72,21 @@ pub fn get_users() -> HashMap<String, User> {
     users
 }
 
+/// Every user with the given role, sorted by username.
+pub fn users_with_role<'a>(users: &'a HashMap<String, User>, role: &LoginRole) -> Vec<&'a User> {
+    let mut matches: Vec<&User> = users.values().filter(|user| user.role == *role).collect();
+    matches.sort_by(|a, b| a.username.cmp(&b.username));
+    matches
+}
+
 pub fn login(username: &str, password: &str) -> Option<LoginAction> {
-    let users = get_users();
+    login_with(&get_users(), username, password)
+}
 
+/// Check a login against `users`. `None` means there's no such user.
+pub fn login_with(users: &HashMap<String, User>, username: &str, password: &str) -> Option<LoginAction> {
     if let Some(user) = users.get(username) {
-        if user.password == password {
+        if verify_password(&user.password, password) {
             Some(LoginAction::Granted(user.role.clone()))
         } else {
             Some(LoginAction::Denied)
@@ -69,4 +106,49 @@ mod test {
         assert_eq!(login("bob", "password"), Some(LoginAction::Granted(LoginRole::User)));
         assert_eq!(login("bob", "wrong"), Some(LoginAction::Denied));
     }
+
+    #[test]
+    fn test_login_with() {
+        let mut users = HashMap::new();
+        users.insert("carol".to_string(), User::new("carol", "secret", LoginRole::Admin));
+        users.insert("dave".to_string(), User::new("dave", "hunter2", LoginRole::User));
+
+        assert_eq!(login_with(&users, "carol", "secret"), Some(LoginAction::Granted(LoginRole::Admin)));
+        assert_eq!(login_with(&users, "dave", "hunter2"), Some(LoginAction::Granted(LoginRole::User)));
+        assert_eq!(login_with(&users, "dave", "secret"), Some(LoginAction::Denied));
+        // Only the users we gave it exist
+        assert_eq!(login_with(&users, "admin", "password"), None);
+        assert_eq!(login_with(&HashMap::new(), "carol", "secret"), None);
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let hash = hash_password("password");
+        assert_ne!(hash, "password");
+        assert!(verify_password(&hash, "password"));
+        assert!(!verify_password(&hash, "Password"));
+        assert!(!verify_password(&hash, ""));
+        // Something that isn't one of our hashes never matches
+        assert!(!verify_password("password", "password"));
+    }
+
+    #[test]
+    fn test_same_password_different_hashes() {
+        let users = get_users();
+        assert_ne!(users["admin"].password, users["bob"].password);
+        assert!(verify_password(&users["admin"].password, "password"));
+        assert!(verify_password(&users["bob"].password, "password"));
+    }
+
+    #[test]
+    fn test_users_with_role() {
+        let mut users = get_users();
+        users.insert("carol".to_string(), User::new("carol", "password", LoginRole::Admin));
+        users.insert("dave".to_string(), User::new("dave", "password", LoginRole::User));
+
+        let admins: Vec<&str> = users_with_role(&users, &LoginRole::Admin).iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(admins, vec!["admin", "carol"]);
+        let regular: Vec<&str> = users_with_role(&users, &LoginRole::User).iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(regular, vec!["bob", "dave"]);
+    }
 }
@@ -1,4 +1,6 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, fs::File, path::{Path, PathBuf}, io::Write, sync::mpsc::{self, Receiver}, time::{Duration, SystemTime}};
+use fs2::FileExt;
+use notify::{RecursiveMode, Watcher};
 use serde::{Serialize, Deserialize};
 
 pub fn read_line() -> String {
@@ -17,23 +19,163 @@ pub fn hash_password(password: &str) -> String {
     format!("{:X}", hasher.finalize())
 }
 
+/// The shortest password `validate_password_strength` accepts
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// A rule a new password has to follow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PasswordRule {
+    MinLength,
+    Digit,
+    Symbol,
+}
+
+impl std::fmt::Display for PasswordRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordRule::MinLength => write!(f, "must be at least {MIN_PASSWORD_LENGTH} characters long"),
+            PasswordRule::Digit => write!(f, "must contain a digit"),
+            PasswordRule::Symbol => write!(f, "must contain a character that isn't a letter or digit"),
+        }
+    }
+}
+
+/// Checks a new password, returning every rule it breaks
+pub fn validate_password_strength(pw: &str) -> Result<(), Vec<PasswordRule>> {
+    let mut broken = Vec::new();
+    if pw.chars().count() < MIN_PASSWORD_LENGTH {
+        broken.push(PasswordRule::MinLength);
+    }
+    if !pw.chars().any(|c| c.is_ascii_digit()) {
+        broken.push(PasswordRule::Digit);
+    }
+    if pw.chars().all(|c| c.is_alphanumeric()) {
+        broken.push(PasswordRule::Symbol);
+    }
+    if broken.is_empty() {
+        Ok(())
+    } else {
+        Err(broken)
+    }
+}
+
 #[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub enum LoginAction {
     Granted(LoginRole),
     Denied,
 }
 
+impl LoginAction {
+    /// Was the login granted, with the admin role?
+    pub fn is_admin(&self) -> bool {
+        matches!(self, LoginAction::Granted(LoginRole::Admin))
+    }
+
+    /// The role the user logged in with, if the login was granted
+    pub fn granted_role(&self) -> Option<&LoginRole> {
+        match self {
+            LoginAction::Granted(role) => Some(role),
+            LoginAction::Denied => None,
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum LoginRole {
     Admin,
+    /// Looks after other users, but can't remove them
+    Moderator,
     User,
 }
 
+/// Something only some roles may do
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Permission {
+    AddUser,
+    DeleteUser,
+    /// Change a user's name, password or role
+    EditUser,
+    ImportUsers,
+    ChangePassword,
+    ViewUsers,
+}
+
+impl Permission {
+    /// The least role that may do this - any role above it may too
+    pub fn required_role(self) -> LoginRole {
+        match self {
+            // These can all make someone an admin, so only an admin may
+            Permission::AddUser | Permission::EditUser | Permission::ImportUsers => LoginRole::Admin,
+            Permission::DeleteUser => LoginRole::Admin,
+            Permission::ChangePassword | Permission::ViewUsers => LoginRole::Moderator,
+        }
+    }
+}
+
+impl LoginRole {
+    /// Does this role have (at least) the `required` role's rights?
+    /// Admins can do anything a moderator can, and moderators anything a
+    /// user can.
+    pub fn satisfies(&self, required: LoginRole) -> bool {
+        match (self, required) {
+            (LoginRole::Admin, _) => true,
+            (LoginRole::Moderator, LoginRole::Moderator | LoginRole::User) => true,
+            (LoginRole::User, LoginRole::User) => true,
+            _ => false,
+        }
+    }
+
+    /// May this role do `action`?
+    pub fn can(&self, action: Permission) -> bool {
+        self.satisfies(action.required_role())
+    }
+}
+
+impl std::fmt::Display for LoginRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginRole::Admin => write!(f, "admin"),
+            LoginRole::Moderator => write!(f, "moderator"),
+            LoginRole::User => write!(f, "user"),
+        }
+    }
+}
+
+/// A role name that isn't "admin", "moderator" or "user"
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseRoleError(String);
+
+impl std::fmt::Display for ParseRoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not a role - use admin, moderator or user", self.0)
+    }
+}
+
+impl std::error::Error for ParseRoleError {}
+
+impl std::str::FromStr for LoginRole {
+    type Err = ParseRoleError;
+
+    /// Accepts the names `Display` produces, ignoring case and surrounding spaces
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role.trim().to_lowercase().as_str() {
+            "admin" => Ok(LoginRole::Admin),
+            "moderator" => Ok(LoginRole::Moderator),
+            "user" => Ok(LoginRole::User),
+            _ => Err(ParseRoleError(role.to_string())),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
     pub password: String,
     pub role: LoginRole,
+    /// When the user last logged in successfully. Older user files don't
+    /// have this, so it defaults to `None`.
+    #[serde(default)]
+    pub last_login: Option<SystemTime>,
 }
 
 impl User {
@@ -42,6 +184,7 @@ impl User {
             username: username.to_lowercase(),
             password: hash_password(password),
             role,
+            last_login: None,
         }
     }
 }
@@ -53,34 +196,217 @@ fn get_default_users() -> HashMap<String, User> {
     users
 }
 
-pub fn save_users(users: &HashMap<String, User>) {
-    let users_path = Path::new("users.json");
-    let users_json = serde_json::to_string(&users).unwrap();
-    std::fs::write(users_path, users_json).unwrap();
+const USERS_PATH: &str = "users.json";
+
+/// Take an advisory lock on `users.lock`, next to the users file. Readers
+/// share it, writers get it to themselves. It's released when the returned
+/// file is dropped.
+fn lock_users_file(path: &Path, exclusive: bool) -> std::io::Result<File> {
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_extension("lock"))?;
+    if exclusive {
+        lock.lock_exclusive()?;
+    } else {
+        lock.lock_shared()?;
+    }
+    Ok(lock)
 }
 
-pub fn get_users() -> HashMap<String, User> {
-    let users_path = Path::new("users.json");
-    if users_path.exists() {
+/// Users files ending in `.toml` are TOML, anything else is JSON
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "toml")
+}
+
+fn parse_users(path: &Path, text: &str) -> Result<HashMap<String, User>, String> {
+    if is_toml(path) {
+        toml::from_str(text).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(text).map_err(|e| e.to_string())
+    }
+}
+
+fn write_users(path: &Path, users: &HashMap<String, User>) {
+    let text = if is_toml(path) {
+        toml::to_string(&users).unwrap()
+    } else {
+        serde_json::to_string(&users).unwrap()
+    };
+    std::fs::write(path, text).unwrap();
+}
+
+/// Does this look like something `hash_password` produced? The earlier
+/// auth examples stored passwords as they were typed, and those won't be.
+/// (A 64 character upper-case hex password would fool it.)
+fn is_password_hash(password: &str) -> bool {
+    password.len() == 64 && password.chars().all(|c| matches!(c, '0'..='9' | 'A'..='F'))
+}
+
+fn has_plaintext_passwords(users: &HashMap<String, User>) -> bool {
+    users.values().any(|user| !is_password_hash(&user.password))
+}
+
+/// Hash any passwords that were stored as plain text. Returns how many there were.
+fn hash_plaintext_passwords(users: &mut HashMap<String, User>) -> usize {
+    let mut hashed = 0;
+    for user in users.values_mut().filter(|user| !is_password_hash(&user.password)) {
+        user.password = hash_password(&user.password);
+        hashed += 1;
+    }
+    hashed
+}
+
+/// Only call this while holding the exclusive lock. Plain text passwords
+/// are hashed, and the file rewritten, as they're found.
+fn read_or_create_users(path: &Path) -> HashMap<String, User> {
+    if path.exists() {
         // Load the file
-        let users_json = std::fs::read_to_string(users_path).unwrap();
-        let users: HashMap<String, User> = serde_json::from_str(&users_json).unwrap();
+        let text = std::fs::read_to_string(path).unwrap();
+        let mut users = parse_users(path, &text).unwrap();
+        if hash_plaintext_passwords(&mut users) > 0 {
+            write_users(path, &users);
+        }
         users
     } else {
         // Create a file and return it
         let users = get_default_users();
-        let users_json = serde_json::to_string(&users).unwrap();
-        std::fs::write(users_path, users_json).unwrap();
+        write_users(path, &users);
         users
     }
 }
 
+pub fn save_users(users: &HashMap<String, User>) {
+    save_users_to(Path::new(USERS_PATH), users)
+}
+
+pub fn save_users_to(path: &Path, users: &HashMap<String, User>) {
+    let _lock = lock_users_file(path, true).unwrap();
+    write_users(path, users);
+}
+
+pub fn get_users() -> HashMap<String, User> {
+    get_users_from(Path::new(USERS_PATH))
+}
+
+pub fn get_users_from(path: &Path) -> HashMap<String, User> {
+    {
+        let _lock = lock_users_file(path, false).unwrap();
+        if path.exists() {
+            let text = std::fs::read_to_string(path).unwrap();
+            let users = parse_users(path, &text).unwrap();
+            if !has_plaintext_passwords(&users) {
+                return users;
+            }
+        }
+    }
+    // Creating the file, or hashing old passwords, is a write. Someone else
+    // may beat us to it, so check again once we have the lock.
+    let _lock = lock_users_file(path, true).unwrap();
+    read_or_create_users(path)
+}
+
+/// Give a burst of file events this long to finish before reloading
+const WATCH_SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Is this event a change to the users file (rather than its lock file, or
+/// something else in the directory)?
+fn is_users_file_change(event: &notify::Result<notify::Event>, path: &Path) -> bool {
+    match event {
+        Ok(event) => {
+            (event.kind.is_create() || event.kind.is_modify())
+                && event.paths.iter().any(|changed| changed.file_name() == path.file_name())
+        }
+        Err(_) => false,
+    }
+}
+
+/// Read the users without changing the file. `None` if it can't be read -
+/// someone may be halfway through editing it by hand.
+fn try_read_users(path: &Path) -> Option<HashMap<String, User>> {
+    let _lock = lock_users_file(path, false).ok()?;
+    let text = std::fs::read_to_string(path).ok()?;
+    let mut users = parse_users(path, &text).ok()?;
+    // The next `get_users` saves these, but they have to work now
+    hash_plaintext_passwords(&mut users);
+    Some(users)
+}
+
+/// Re-read the users file whenever it changes, and send the new users down
+/// the channel. A long-running server can pick up changes without
+/// restarting. Watching stops once the receiver is dropped and the file
+/// changes again.
+pub fn watch_users(path: &Path) -> Receiver<HashMap<String, User>> {
+    let (users_tx, users_rx) = mpsc::channel();
+    let (event_tx, event_rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(event_tx).expect("Unable to watch the users file");
+    // Watch the directory: editors often replace a file rather than change it
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    watcher.watch(directory, RecursiveMode::NonRecursive).expect("Unable to watch the users file");
+
+    let path = path.to_path_buf();
+    std::thread::spawn(move || {
+        // Events stop when the watcher is dropped, so keep it here
+        let _watcher = watcher;
+        while let Ok(event) = event_rx.recv() {
+            if !is_users_file_change(&event, &path) {
+                continue;
+            }
+            // One save can be several events - wait for the last of them
+            while event_rx.recv_timeout(WATCH_SETTLE_TIME).is_ok() {}
+            let Some(users) = try_read_users(&path) else { continue };
+            if users_tx.send(users).is_err() {
+                // Nobody is listening any more
+                break;
+            }
+        }
+    });
+    users_rx
+}
+
+/// Load the users, change them with `change` and save them - holding the
+/// lock the whole time, so nobody else's changes are lost in between.
+/// Use this rather than `get_users` followed by `save_users`.
+pub fn update_users<R>(change: impl FnOnce(&mut HashMap<String, User>) -> R) -> R {
+    update_users_in(Path::new(USERS_PATH), change)
+}
+
+pub fn update_users_in<R>(path: &Path, change: impl FnOnce(&mut HashMap<String, User>) -> R) -> R {
+    let _lock = lock_users_file(path, true).unwrap();
+    let mut users = read_or_create_users(path);
+    let result = change(&mut users);
+    write_users(path, &users);
+    result
+}
+
+/// Check a login against the users file, saving the new login time if it
+/// succeeds. Checking only takes a shared lock, so failed attempts don't
+/// hold up anyone else or rewrite the file.
 pub fn login(username: &str, password: &str) -> Option<LoginAction> {
-    let users = get_users();
+    login_in(Path::new(USERS_PATH), username, password)
+}
+
+fn login_in(path: &Path, username: &str, password: &str) -> Option<LoginAction> {
+    match login_with(&mut get_users_from(path), username, password) {
+        // Check again once we have the lock to write - the password may have
+        // changed in between
+        Some(LoginAction::Granted(_)) => update_users_in(path, |users| login_with(users, username, password)),
+        outcome => outcome,
+    }
+}
+
+/// Check a login against `users`, recording the time of a successful login.
+/// `None` means there's no such user.
+pub fn login_with(users: &mut HashMap<String, User>, username: &str, password: &str) -> Option<LoginAction> {
     let password = hash_password(password);
 
-    if let Some(user) = users.get(username) {
+    if let Some(user) = users.get_mut(username) {
         if user.password == password {
+            user.last_login = Some(SystemTime::now());
             Some(LoginAction::Granted(user.role.clone()))
         } else {
             Some(LoginAction::Denied)
@@ -90,14 +416,279 @@ pub fn login(username: &str, password: &str) -> Option<LoginAction> {
     }
 }
 
+/// Records every login attempt as a line of JSON, for security reviews.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: u64,
+    username: &'a str,
+    outcome: &'static str,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn record(&self, username: &str, action: &Option<LoginAction>) -> std::io::Result<()> {
+        let outcome = match action {
+            Some(LoginAction::Granted(_)) => "granted",
+            Some(LoginAction::Denied) => "denied",
+            None => "unknown_user",
+        };
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        let line = serde_json::to_string(&AuditEntry { timestamp, username, outcome })?;
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{line}")?;
+        file.flush()
+    }
+}
+
+/// Like `login`, but records the attempt in an audit log
+pub fn login_audited(username: &str, password: &str, audit: &AuditLog) -> Option<LoginAction> {
+    let action = login(username, password);
+    if let Err(e) = audit.record(username, &action) {
+        eprintln!("Unable to write to the audit log: {e}");
+    }
+    action
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_password_strength() {
+        assert_eq!(validate_password_strength("s3cret!pw"), Ok(()));
+        assert_eq!(validate_password_strength("s3c!"), Err(vec![PasswordRule::MinLength]));
+        assert_eq!(validate_password_strength("secret!pw"), Err(vec![PasswordRule::Digit]));
+        assert_eq!(validate_password_strength("s3cretpw"), Err(vec![PasswordRule::Symbol]));
+        assert_eq!(
+            validate_password_strength("1"),
+            Err(vec![PasswordRule::MinLength, PasswordRule::Symbol])
+        );
+        assert_eq!(
+            validate_password_strength(""),
+            Err(vec![PasswordRule::MinLength, PasswordRule::Digit, PasswordRule::Symbol])
+        );
+    }
+
     #[test]
     fn test_login() {
         assert_eq!(login("admin", "password"), Some(LoginAction::Granted(LoginRole::Admin)));
         assert_eq!(login("bob", "password"), Some(LoginAction::Granted(LoginRole::User)));
         assert_eq!(login("bob", "wrong"), Some(LoginAction::Denied));
     }
+
+    #[test]
+    fn test_last_login() {
+        let mut users = HashMap::new();
+        users.insert("carol".to_string(), User::new("carol", "secret", LoginRole::User));
+
+        assert_eq!(login_with(&mut users, "carol", "wrong"), Some(LoginAction::Denied));
+        assert_eq!(users["carol"].last_login, None);
+
+        let before = SystemTime::now();
+        assert_eq!(login_with(&mut users, "carol", "secret"), Some(LoginAction::Granted(LoginRole::User)));
+        let last_login = users["carol"].last_login.unwrap();
+        assert!(last_login >= before);
+
+        // A failed attempt leaves the last good one alone
+        login_with(&mut users, "carol", "wrong");
+        assert_eq!(users["carol"].last_login, Some(last_login));
+
+        // It survives saving, and files from before it existed still load
+        let json = serde_json::to_string(&users).unwrap();
+        let loaded: HashMap<String, User> = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded["carol"].last_login, Some(last_login));
+        let old: User = serde_json::from_str(r#"{"username":"dave","password":"X","role":"User"}"#).unwrap();
+        assert_eq!(old.last_login, None);
+    }
+
+    #[test]
+    fn test_failed_logins_leave_the_file_alone() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        save_users_to(&path, &get_default_users());
+        let saved = std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(login_in(&path, "bob", "wrong"), Some(LoginAction::Denied));
+        assert_eq!(login_in(&path, "nobody", "password"), None);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), saved);
+
+        // A good login is saved
+        assert_eq!(login_in(&path, "bob", "password"), Some(LoginAction::Granted(LoginRole::User)));
+        assert!(get_users_from(&path)["bob"].last_login.is_some());
+    }
+
+    #[test]
+    fn test_concurrent_updates() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+
+        let threads: Vec<_> = ["carol", "dave"]
+            .into_iter()
+            .map(|name| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    update_users_in(&path, |users| {
+                        // Give the other thread every chance to get in the way
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                        users.insert(name.to_string(), User::new(name, "password", LoginRole::User));
+                    })
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+        let users = get_users_from(&path);
+        let mut names: Vec<&str> = users.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["admin", "bob", "carol", "dave"]);
+    }
+
+    #[test]
+    fn test_plaintext_passwords_are_hashed() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        // From one of the older examples, before passwords were hashed
+        let hashed = hash_password("secret");
+        std::fs::write(
+            &path,
+            format!(r#"{{"carol":{{"username":"carol","password":"hunter2","role":"User"}},"dave":{{"username":"dave","password":"{hashed}","role":"Admin"}}}}"#),
+        )
+        .unwrap();
+
+        let mut users = get_users_from(&path);
+        assert_eq!(users["carol"].password, hash_password("hunter2"));
+        assert_eq!(users["dave"].password, hashed);
+
+        // The file was rewritten, and loading it again changes nothing
+        let saved: HashMap<String, User> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved["carol"].password, hash_password("hunter2"));
+        assert_eq!(get_users_from(&path)["carol"].password, hash_password("hunter2"));
+
+        assert_eq!(login_with(&mut users, "carol", "hunter2"), Some(LoginAction::Granted(LoginRole::User)));
+        assert_eq!(login_with(&mut users, "dave", "secret"), Some(LoginAction::Granted(LoginRole::Admin)));
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let directory = tempfile::tempdir().unwrap();
+        let audit = AuditLog::new(directory.path().join("audit.log"));
+        login_audited("admin", "password", &audit);
+        login_audited("bob", "wrong", &audit);
+
+        let contents = std::fs::read_to_string(directory.path().join("audit.log")).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["username"], "admin");
+        assert_eq!(lines[0]["outcome"], "granted");
+        assert!(lines[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["username"], "bob");
+        assert_eq!(lines[1]["outcome"], "denied");
+    }
+
+    #[test]
+    fn test_toml_users_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.toml");
+        let mut users = get_users_from(&path);
+        assert_eq!(users.len(), 2);
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("[admin]"));
+
+        users.insert("carol".to_string(), User::new("carol", "secret", LoginRole::Moderator));
+        save_users_to(&path, &users);
+        let loaded = get_users_from(&path);
+        assert_eq!(loaded["carol"].role, LoginRole::Moderator);
+        assert_eq!(loaded["carol"].password, hash_password("secret"));
+    }
+
+    #[test]
+    fn test_watch_users() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.toml");
+        get_users_from(&path);
+        let updates = watch_users(&path);
+
+        update_users_in(&path, |users| {
+            users.insert("carol".to_string(), User::new("carol", "secret", LoginRole::User));
+        });
+
+        // Keep reading until the change shows up - there may be a few updates
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            let users = updates.recv_timeout(remaining).expect("the change was never reported");
+            if users.contains_key("carol") {
+                assert_eq!(users.len(), 3);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_login_action_helpers() {
+        let admin = LoginAction::Granted(LoginRole::Admin);
+        assert!(admin.is_admin());
+        assert_eq!(admin.granted_role(), Some(&LoginRole::Admin));
+
+        let user = LoginAction::Granted(LoginRole::User);
+        assert!(!user.is_admin());
+        assert_eq!(user.granted_role(), Some(&LoginRole::User));
+
+        assert!(!LoginAction::Denied.is_admin());
+        assert_eq!(LoginAction::Denied.granted_role(), None);
+    }
+
+    #[test]
+    fn test_role_names() {
+        for role in [LoginRole::Admin, LoginRole::Moderator, LoginRole::User] {
+            assert_eq!(role.to_string().parse::<LoginRole>(), Ok(role));
+        }
+        assert_eq!(LoginRole::Admin.to_string(), "admin");
+        assert_eq!(" User ".parse::<LoginRole>(), Ok(LoginRole::User));
+
+        let error = "superuser".parse::<LoginRole>().unwrap_err();
+        assert_eq!(error, ParseRoleError("superuser".to_string()));
+        assert_eq!(error.to_string(), "superuser is not a role - use admin, moderator or user");
+    }
+
+    #[test]
+    fn test_role_hierarchy() {
+        assert!(LoginRole::Admin.satisfies(LoginRole::Admin));
+        assert!(LoginRole::Admin.satisfies(LoginRole::User));
+        assert!(LoginRole::User.satisfies(LoginRole::User));
+        assert!(!LoginRole::User.satisfies(LoginRole::Admin));
+        assert!(LoginRole::Moderator.satisfies(LoginRole::User));
+        assert!(!LoginRole::Moderator.satisfies(LoginRole::Admin));
+        assert!(!LoginRole::User.satisfies(LoginRole::Moderator));
+    }
+
+    #[test]
+    fn test_permissions() {
+        use Permission::*;
+        for action in [AddUser, DeleteUser, EditUser, ImportUsers, ChangePassword, ViewUsers] {
+            assert!(LoginRole::Admin.can(action));
+            assert!(!LoginRole::User.can(action));
+        }
+        assert!(LoginRole::Moderator.can(ViewUsers));
+        assert!(LoginRole::Moderator.can(ChangePassword));
+        assert!(!LoginRole::Moderator.can(DeleteUser));
+        assert!(!LoginRole::Moderator.can(EditUser));
+    }
 }
@@ -1,5 +1,9 @@
-use auth_login_manager::{get_users, save_users, LoginRole, User};
+use auth_login_manager::{get_users, update_users, LoginAction, LoginRole, Permission, User};
+use anyhow::{bail, Context};
 use clap::{Parser, Subcommand};
+use comfy_table::{presets::UTF8_FULL, Cell, Color, Table};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::IsTerminal, time::SystemTime};
 
 #[derive(Parser)]
 #[command()]
@@ -11,8 +15,12 @@ struct Args {
 #[derive(Subcommand)]
 enum Commands {
     /// List all users.
-    List,
-    /// Add a user.
+    List {
+        /// Plain text, even on a terminal. Color is always off when piped.
+        #[arg(long)]
+        no_color: bool,
+    },
+    /// Add a user. Only admins may.
     Add {
         /// Username
         username: String,
@@ -23,84 +31,595 @@ enum Commands {
         /// Optional - mark as an admin
         #[arg(long)]
         admin: Option<bool>,
+
+        /// Who's asking - you'll be asked for their password
+        #[arg(long = "as")]
+        caller: String,
     },
-    /// Delete a user
+    /// Delete a user. Only admins may.
     Delete {
         /// Username
         username: String,
+
+        /// Who's asking - you'll be asked for their password
+        #[arg(long = "as")]
+        caller: String,
     },
-    /// Change a password
+    /// Change a password. Admins and moderators may.
     ChangePassword {
         /// Username
         username: String,
 
         /// New Password
         new_password: String,
+
+        /// Who's asking - you'll be asked for their password
+        #[arg(long = "as")]
+        caller: String,
+    },
+    /// Show how many users there are
+    Count {
+        /// Also show how many have each role
+        #[arg(long)]
+        by_role: bool,
+    },
+    /// Check a username and password, as a login would. The exit status is
+    /// 0 if granted, 3 if denied and 4 for an unknown user - 1 is any other
+    /// error, and 2 a bad command line.
+    Verify {
+        /// Username
+        username: String,
+
+        /// Password
+        password: String,
+    },
+    /// Change several things about a user at once. Only admins may.
+    Edit {
+        /// Username
+        username: String,
+
+        /// New password
+        #[arg(long)]
+        password: Option<String>,
+
+        /// New role: admin, moderator or user
+        #[arg(long)]
+        role: Option<String>,
+
+        /// New username
+        #[arg(long)]
+        rename: Option<String>,
+
+        /// Who's asking - you'll be asked for their password
+        #[arg(long = "as")]
+        caller: String,
+    },
+    /// Write every username and role (but no passwords) to a CSV file
+    Export {
+        /// The CSV file to write
+        path: String,
+    },
+    /// Add users from a CSV file with username and role columns. Users
+    /// that already exist are skipped. Only admins may.
+    Import {
+        /// The CSV file to read
+        path: String,
+
+        /// Every new user starts with this password
+        default_password: String,
+
+        /// Who's asking - you'll be asked for their password
+        #[arg(long = "as")]
+        caller: String,
     },
 }
 
-fn delete_user(username: &str) {
-    let mut users = get_users();
-    if users.contains_key(username) {
-        users.remove(username);
-        save_users(&users);
-    } else {
-        println!("{username} does not exist");
+/// Is the outcome of logging in as `caller` a role that may do `permission`?
+fn authorize(action: Option<LoginAction>, caller: &str, permission: Permission) -> Result<(), String> {
+    match action.as_ref().and_then(LoginAction::granted_role) {
+        Some(role) if role.can(permission) => Ok(()),
+        Some(role) => Err(format!("{caller} ({role}) isn't allowed to do that")),
+        None => Err(format!("Unable to log in as {caller}")),
     }
 }
 
-fn list_users() {
-    println!("{:<20}{:<20}", "Username", "Login Action");
-    println!("{:-<40}", "");
+/// Ask for the caller's password, and fail unless they may do `permission`
+fn require_permission(caller: &str, permission: Permission) -> anyhow::Result<()> {
+    println!("Password for {caller}:");
+    let password = auth_login_manager::read_line();
+    authorize(auth_login_manager::login(caller, &password), caller, permission).map_err(anyhow::Error::msg)
+}
 
-    let users = get_users();
-    users.iter().for_each(|(_, user)| {
-        println!("{:<20}{:<20?}", user.username, user.role);
-    });
+/// Fail with every broken rule if `password` is too weak
+fn require_strong_password(password: &str) -> anyhow::Result<()> {
+    if let Err(broken) = auth_login_manager::validate_password_strength(password) {
+        let rules: Vec<String> = broken.iter().map(|rule| format!("The password {rule}")).collect();
+        bail!(rules.join("\n"));
+    }
+    Ok(())
 }
 
-fn add_user(username: String, password: String, admin: bool) {
-    let mut users = get_users();
-    if users.contains_key(&username) {
-        println!("{username} already exists");
-        return;
+fn delete_user(username: &str) -> anyhow::Result<()> {
+    // Holding the lock from reading to saving, so concurrent changes aren't lost
+    update_users(|users| {
+        if users.remove(username).is_none() {
+            bail!("{username} does not exist");
+        }
+        Ok(())
+    })
+}
+
+/// How long ago a login was, roughly: "3d ago", "5m ago" or "never"
+fn last_login_text(last_login: Option<SystemTime>, now: SystemTime) -> String {
+    let Some(last_login) = last_login else {
+        return "never".to_string();
+    };
+    // A clock that went backwards counts as "just now"
+    let seconds = now.duration_since(last_login).map(|age| age.as_secs()).unwrap_or(0);
+    match seconds {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
     }
-    let role = if admin {
-        LoginRole::Admin
+}
+
+/// Every user in a table sized to fit, sorted by name. With `color`,
+/// admins stand out.
+fn users_table(users: &HashMap<String, User>, color: bool) -> String {
+    let mut sorted: Vec<&User> = users.values().collect();
+    sorted.sort_by(|a, b| a.username.cmp(&b.username));
+
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL).set_header(vec!["Username", "Role", "Last Login"]);
+    if color {
+        // We've already decided - don't second-guess it
+        table.enforce_styling();
     } else {
-        LoginRole::User
-    };
-    let user = User::new(&username, &password, role);
-    users.insert(username, user);
-    save_users(&users);
+        table.force_no_tty();
+    }
+    let now = SystemTime::now();
+    for user in sorted {
+        let mut role = Cell::new(user.role.to_string());
+        if color {
+            match user.role {
+                LoginRole::Admin => role = role.fg(Color::Yellow),
+                LoginRole::Moderator => role = role.fg(Color::Cyan),
+                LoginRole::User => {}
+            }
+        }
+        table.add_row(vec![Cell::new(&user.username), role, Cell::new(last_login_text(user.last_login, now))]);
+    }
+    table.to_string()
+}
+
+fn list_users(no_color: bool) {
+    let color = !no_color && std::io::stdout().is_terminal();
+    println!("{}", users_table(&get_users(), color));
+}
+
+#[derive(Debug, PartialEq)]
+struct UserCounts {
+    total: usize,
+    admins: usize,
+    moderators: usize,
+    users: usize,
+}
+
+fn count_users(users: &HashMap<String, User>) -> UserCounts {
+    let with_role = |role: LoginRole| users.values().filter(|user| user.role == role).count();
+    UserCounts {
+        total: users.len(),
+        admins: with_role(LoginRole::Admin),
+        moderators: with_role(LoginRole::Moderator),
+        users: with_role(LoginRole::User),
+    }
+}
+
+fn print_count(by_role: bool) {
+    let counts = count_users(&get_users());
+    println!("{} users", counts.total);
+    if by_role {
+        println!("{:<20}{}", "Admin", counts.admins);
+        println!("{:<20}{}", "Moderator", counts.moderators);
+        println!("{:<20}{}", "User", counts.users);
+    }
+}
+
+/// `verify`'s exit status for a wrong password. 1 is taken by errors, and
+/// 2 by clap for a bad command line, so scripts can tell them all apart.
+const EXIT_DENIED: i32 = 3;
+/// `verify`'s exit status for a user that doesn't exist
+const EXIT_UNKNOWN: i32 = 4;
+
+/// What `verify` prints, and the exit status that goes with it
+fn verify_outcome(action: &Option<LoginAction>) -> (String, i32) {
+    match action {
+        Some(LoginAction::Granted(role)) => (format!("GRANTED ({role})"), 0),
+        Some(LoginAction::Denied) => ("DENIED".to_string(), EXIT_DENIED),
+        None => ("UNKNOWN".to_string(), EXIT_UNKNOWN),
+    }
+}
+
+fn verify(username: &str, password: &str) -> i32 {
+    let (message, code) = verify_outcome(&auth_login_manager::login(username, password));
+    println!("{message}");
+    code
 }
 
-fn change_password(username: &str, password: &str) {
-    let mut users = get_users();
-    if let Some(user) = users.get_mut(username) {
+fn add_user(username: String, password: String, admin: bool) -> anyhow::Result<()> {
+    update_users(|users| {
+        if users.contains_key(&username) {
+            bail!("{username} already exists");
+        }
+        let role = if admin {
+            LoginRole::Admin
+        } else {
+            LoginRole::User
+        };
+        let user = User::new(&username, &password, role);
+        users.insert(username, user);
+        Ok(())
+    })
+}
+
+fn change_password(username: &str, password: &str) -> anyhow::Result<()> {
+    update_users(|users| {
+        let Some(user) = users.get_mut(username) else {
+            bail!("{username} does not exist");
+        };
         user.password = auth_login_manager::hash_password(password);
-        save_users(&users);
-    } else {
-        println!("{username} does not exist");
+        Ok(())
+    })
+}
+
+/// The changes to make in an edit. Anything left as `None` stays the same.
+#[derive(Debug, Default)]
+struct UserEdit {
+    password: Option<String>,
+    role: Option<String>,
+    rename: Option<String>,
+}
+
+/// Check everything first, then apply every change - so an edit either
+/// happens completely or not at all.
+fn apply_edit(users: &mut HashMap<String, User>, username: &str, edit: UserEdit) -> Result<(), String> {
+    if edit.password.is_none() && edit.role.is_none() && edit.rename.is_none() {
+        return Err("Nothing to change - use --password, --role or --rename".to_string());
+    }
+    if !users.contains_key(username) {
+        return Err(format!("{username} does not exist"));
+    }
+    let role = edit.role.as_deref().map(str::parse::<LoginRole>).transpose().map_err(|e| e.to_string())?;
+    if let Some(password) = &edit.password {
+        if password.trim().is_empty() {
+            return Err("The password can't be empty".to_string());
+        }
     }
+    let new_name = edit.rename.map(|name| name.trim().to_lowercase());
+    if let Some(new_name) = &new_name {
+        if new_name.is_empty() {
+            return Err("The username can't be empty".to_string());
+        }
+        if new_name != username && users.contains_key(new_name) {
+            return Err(format!("{new_name} already exists"));
+        }
+    }
+
+    let mut user = users.remove(username).unwrap();
+    if let Some(password) = edit.password {
+        user.password = auth_login_manager::hash_password(&password);
+    }
+    if let Some(role) = role {
+        user.role = role;
+    }
+    if let Some(new_name) = new_name {
+        user.username = new_name;
+    }
+    users.insert(user.username.clone(), user);
+    Ok(())
 }
 
-fn main() {
-    let cli = Args::parse();
-    match cli.command {
-        Some(Commands::List) => list_users(),
+fn edit_user(username: &str, edit: UserEdit) -> anyhow::Result<()> {
+    // apply_edit leaves the users alone if it fails, so saving them is harmless
+    update_users(|users| apply_edit(users, username, edit)).map_err(anyhow::Error::msg)
+}
+
+/// A line of an exported or imported users file
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvUser {
+    username: String,
+    role: String,
+}
+
+/// Every user, sorted by name. The password hashes stay where they are.
+fn export_users<W: std::io::Write>(users: &HashMap<String, User>, writer: W) -> Result<(), csv::Error> {
+    let mut sorted: Vec<&User> = users.values().collect();
+    sorted.sort_by(|a, b| a.username.cmp(&b.username));
+
+    let mut writer = csv::Writer::from_writer(writer);
+    for user in sorted {
+        writer.serialize(CsvUser { username: user.username.clone(), role: user.role.to_string() })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn export_users_to(path: &str) -> anyhow::Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("Unable to export to {path}"))?;
+    export_users(&get_users(), file).with_context(|| format!("Unable to export to {path}"))
+}
+
+/// Who `import_users` added, and who was already there
+#[derive(Debug, Default, PartialEq)]
+struct ImportReport {
+    added: Vec<String>,
+    skipped: Vec<String>,
+}
+
+/// Read every line first, so a bad role means nobody is added - then add
+/// the users that don't exist yet.
+fn import_users<R: std::io::Read>(
+    users: &mut HashMap<String, User>,
+    reader: R,
+    default_password: &str,
+) -> Result<ImportReport, String> {
+    let mut rows = Vec::new();
+    for (line, row) in csv::Reader::from_reader(reader).deserialize::<CsvUser>().enumerate() {
+        // Line 1 is the header
+        let line = line + 2;
+        let row = row.map_err(|e| format!("Line {line}: {e}"))?;
+        let username = row.username.trim().to_lowercase();
+        if username.is_empty() {
+            return Err(format!("Line {line}: the username can't be empty"));
+        }
+        let role = row.role.parse::<LoginRole>().map_err(|e| format!("Line {line}: {e}"))?;
+        rows.push((username, role));
+    }
+
+    let mut report = ImportReport::default();
+    for (username, role) in rows {
+        if users.contains_key(&username) {
+            report.skipped.push(username);
+            continue;
+        }
+        users.insert(username.clone(), User::new(&username, default_password, role));
+        report.added.push(username);
+    }
+    Ok(report)
+}
+
+fn import_users_from(path: &str, default_password: &str) -> anyhow::Result<()> {
+    let file = std::fs::File::open(path).with_context(|| format!("Unable to read {path}"))?;
+    let report = update_users(|users| import_users(users, file, default_password)).map_err(anyhow::Error::msg)?;
+    for username in &report.skipped {
+        println!("Warning: {username} already exists, skipping");
+    }
+    println!("Imported {} users", report.added.len());
+    Ok(())
+}
+
+/// Run one command. Anything that goes wrong comes back as an error, so
+/// `main` can report it and exit with a failure code.
+fn run(command: Option<Commands>) -> anyhow::Result<()> {
+    match command {
+        Some(Commands::List { no_color }) => list_users(no_color),
         Some(Commands::Add {
             username,
             password,
             admin,
-        }) => add_user(username, password, admin.unwrap_or(false)),
-        Some(Commands::Delete { username }) => delete_user(&username),
-        Some(Commands::ChangePassword { username, new_password }) => {
-            change_password(&username, &new_password)
+            caller,
+        }) => {
+            require_strong_password(&password)?;
+            require_permission(&caller, Permission::AddUser)?;
+            add_user(username, password, admin.unwrap_or(false))?
         }
-        None => {
-            println!("Run with --help to see instructions");
-            std::process::exit(0);
+        Some(Commands::Delete { username, caller }) => {
+            require_permission(&caller, Permission::DeleteUser)?;
+            delete_user(&username)?
         }
+        Some(Commands::ChangePassword { username, new_password, caller }) => {
+            require_strong_password(&new_password)?;
+            require_permission(&caller, Permission::ChangePassword)?;
+            change_password(&username, &new_password)?
+        }
+        Some(Commands::Count { by_role }) => print_count(by_role),
+        Some(Commands::Verify { username, password }) => {
+            std::process::exit(verify(&username, &password));
+        }
+        Some(Commands::Edit { username, password, role, rename, caller }) => {
+            if let Some(password) = &password {
+                require_strong_password(password)?;
+            }
+            require_permission(&caller, Permission::EditUser)?;
+            edit_user(&username, UserEdit { password, role, rename })?
+        }
+        Some(Commands::Export { path }) => export_users_to(&path)?,
+        Some(Commands::Import { path, default_password, caller }) => {
+            require_strong_password(&default_password)?;
+            require_permission(&caller, Permission::ImportUsers)?;
+            import_users_from(&path, &default_password)?
+        }
+        None => println!("Run with --help to see instructions"),
+    }
+    Ok(())
+}
+
+fn main() {
+    let cli = Args::parse();
+    if let Err(e) = run(cli.command) {
+        // {:#} includes the cause, e.g. "Unable to read x.csv: No such file"
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn users() -> HashMap<String, User> {
+        let mut users = HashMap::new();
+        users.insert("bob".to_string(), User::new("bob", "password", LoginRole::User));
+        users.insert("admin".to_string(), User::new("admin", "password", LoginRole::Admin));
+        users
+    }
+
+    #[test]
+    fn table_without_color() {
+        let mut users = users();
+        users.insert("a-very-long-username-indeed".to_string(), User::new("a-very-long-username-indeed", "password", LoginRole::User));
+
+        let plain = users_table(&users, false);
+        assert!(!plain.contains('\x1b'));
+        assert!(plain.contains("a-very-long-username-indeed"));
+        // Every line is the same width, long name or not
+        let widths: Vec<usize> = plain.lines().map(|line| line.chars().count()).collect();
+        assert!(widths.iter().all(|width| *width == widths[0]));
+
+        assert!(users_table(&users, true).contains('\x1b'));
+    }
+
+    #[test]
+    fn last_login_descriptions() {
+        use std::time::Duration;
+        let now = SystemTime::now();
+        assert_eq!(last_login_text(None, now), "never");
+        assert_eq!(last_login_text(Some(now - Duration::from_secs(5)), now), "just now");
+        assert_eq!(last_login_text(Some(now - Duration::from_secs(150)), now), "2m ago");
+        assert_eq!(last_login_text(Some(now - Duration::from_secs(7200)), now), "2h ago");
+        assert_eq!(last_login_text(Some(now - Duration::from_secs(3 * 86400)), now), "3d ago");
+        assert_eq!(last_login_text(Some(now + Duration::from_secs(60)), now), "just now");
+
+        let mut users = users();
+        users.get_mut("bob").unwrap().last_login = Some(now);
+        let table = users_table(&users, false);
+        assert!(table.contains("just now"));
+        assert!(table.contains("never"));
+    }
+
+    #[test]
+    fn count_by_role() {
+        let mut users = users();
+        users.insert("carol".to_string(), User::new("carol", "password", LoginRole::User));
+        users.insert("dave".to_string(), User::new("dave", "password", LoginRole::Moderator));
+        assert_eq!(count_users(&users), UserCounts { total: 4, admins: 1, moderators: 1, users: 2 });
+        assert_eq!(count_users(&HashMap::new()), UserCounts { total: 0, admins: 0, moderators: 0, users: 0 });
+    }
+
+    #[test]
+    fn verify_exit_codes() {
+        let granted = verify_outcome(&Some(LoginAction::Granted(LoginRole::Admin)));
+        assert_eq!(granted, ("GRANTED (admin)".to_string(), 0));
+        assert_eq!(verify_outcome(&Some(LoginAction::Denied)), ("DENIED".to_string(), 3));
+        assert_eq!(verify_outcome(&None), ("UNKNOWN".to_string(), 4));
+    }
+
+    #[test]
+    fn permission_checks() {
+        let as_role = |role| Some(LoginAction::Granted(role));
+        assert_eq!(authorize(as_role(LoginRole::Admin), "admin", Permission::DeleteUser), Ok(()));
+        assert_eq!(authorize(as_role(LoginRole::Moderator), "mod", Permission::ChangePassword), Ok(()));
+        assert_eq!(
+            authorize(as_role(LoginRole::Moderator), "mod", Permission::DeleteUser),
+            Err("mod (moderator) isn't allowed to do that".to_string())
+        );
+        assert!(authorize(as_role(LoginRole::User), "bob", Permission::ChangePassword).is_err());
+        assert_eq!(
+            authorize(Some(LoginAction::Denied), "bob", Permission::ViewUsers),
+            Err("Unable to log in as bob".to_string())
+        );
+        assert!(authorize(None, "nobody", Permission::ViewUsers).is_err());
+    }
+
+    #[test]
+    fn export_has_no_passwords() {
+        let mut csv = Vec::new();
+        export_users(&users(), &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv, "username,role\nadmin,admin\nbob,user\n");
+    }
+
+    #[test]
+    fn import_skips_existing_users() {
+        let mut users = users();
+        let csv = "username,role\nCarol,moderator\nbob,admin\ndave,user\n";
+        let report = import_users(&mut users, csv.as_bytes(), "welcome").unwrap();
+        assert_eq!(report.added, vec!["carol".to_string(), "dave".to_string()]);
+        assert_eq!(report.skipped, vec!["bob".to_string()]);
+
+        assert_eq!(users["carol"].role, LoginRole::Moderator);
+        assert_eq!(users["carol"].password, auth_login_manager::hash_password("welcome"));
+        // Bob was already there, and keeps his role
+        assert_eq!(users["bob"].role, LoginRole::User);
+
+        // What we export, we can import
+        let mut exported = Vec::new();
+        export_users(&users, &mut exported).unwrap();
+        let mut copy = HashMap::new();
+        let report = import_users(&mut copy, exported.as_slice(), "welcome").unwrap();
+        assert_eq!(report.added.len(), 4);
+        assert_eq!(copy["carol"].role, LoginRole::Moderator);
+    }
+
+    #[test]
+    fn import_with_a_bad_role_adds_nobody() {
+        let mut users = users();
+        let csv = "username,role\ncarol,user\ndave,superuser\n";
+        let error = import_users(&mut users, csv.as_bytes(), "welcome").unwrap_err();
+        assert_eq!(error, "Line 3: superuser is not a role - use admin, moderator or user");
+        assert!(!users.contains_key("carol"));
+    }
+
+    #[test]
+    fn edit_role_only() {
+        let mut users = users();
+        let edit = UserEdit { role: Some("Admin".to_string()), ..Default::default() };
+        apply_edit(&mut users, "bob", edit).unwrap();
+        assert_eq!(users["bob"].role, LoginRole::Admin);
+        assert_eq!(users["bob"].password, auth_login_manager::hash_password("password"));
+    }
+
+    #[test]
+    fn edit_password_only() {
+        let mut users = users();
+        let edit = UserEdit { password: Some("secret".to_string()), ..Default::default() };
+        apply_edit(&mut users, "bob", edit).unwrap();
+        assert_eq!(users["bob"].password, auth_login_manager::hash_password("secret"));
+        assert_eq!(users["bob"].role, LoginRole::User);
+    }
+
+    #[test]
+    fn edit_everything_at_once() {
+        let mut users = users();
+        let edit = UserEdit {
+            password: Some("secret".to_string()),
+            role: Some("admin".to_string()),
+            rename: Some("Robert".to_string()),
+        };
+        apply_edit(&mut users, "bob", edit).unwrap();
+        assert!(!users.contains_key("bob"));
+        let robert = &users["robert"];
+        assert_eq!(robert.username, "robert");
+        assert_eq!(robert.role, LoginRole::Admin);
+        assert_eq!(robert.password, auth_login_manager::hash_password("secret"));
+    }
+
+    #[test]
+    fn bad_edits_change_nothing() {
+        let mut users = users();
+        assert!(apply_edit(&mut users, "bob", UserEdit::default()).is_err());
+        assert!(apply_edit(&mut users, "nobody", UserEdit { role: Some("user".to_string()), ..Default::default() }).is_err());
+
+        // One bad field stops the good ones from being applied
+        let edit = UserEdit { password: Some("secret".to_string()), role: Some("superuser".to_string()), rename: None };
+        assert!(apply_edit(&mut users, "bob", edit).is_err());
+        let edit = UserEdit { password: Some(" ".to_string()), role: Some("admin".to_string()), rename: None };
+        assert!(apply_edit(&mut users, "bob", edit).is_err());
+        let edit = UserEdit { rename: Some("admin".to_string()), ..Default::default() };
+        assert!(apply_edit(&mut users, "bob", edit).is_err());
+
+        assert_eq!(users["bob"].role, LoginRole::User);
+        assert_eq!(users["bob"].password, auth_login_manager::hash_password("password"));
     }
 }
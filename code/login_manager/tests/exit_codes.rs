@@ -0,0 +1,81 @@
+use assert_cmd::Command;
+
+/// Run login_manager in a directory of its own, so it gets a fresh users file
+fn login_manager(directory: &tempfile::TempDir) -> Command {
+    let mut command = Command::cargo_bin("login_manager").unwrap();
+    command.current_dir(directory.path());
+    command
+}
+
+#[test]
+fn deleting_a_missing_user_fails() {
+    let directory = tempfile::tempdir().unwrap();
+    login_manager(&directory)
+        .args(["delete", "nobody", "--as", "admin"])
+        .write_stdin("password\n")
+        .assert()
+        .code(1)
+        .stderr("nobody does not exist\n");
+}
+
+#[test]
+fn deleting_a_user_succeeds() {
+    let directory = tempfile::tempdir().unwrap();
+    login_manager(&directory)
+        .args(["delete", "bob", "--as", "admin"])
+        .write_stdin("password\n")
+        .assert()
+        .success()
+        .stderr("");
+    login_manager(&directory).args(["list", "--no-color"]).assert().success();
+}
+
+/// Run a command as bob, who is only a user, and check that it's refused
+fn refused_for_bob(directory: &tempfile::TempDir, args: &[&str]) {
+    login_manager(directory)
+        .args(args)
+        .args(["--as", "bob"])
+        .write_stdin("password\n")
+        .assert()
+        .code(1)
+        .stderr("bob (user) isn't allowed to do that\n");
+}
+
+#[test]
+fn only_admins_can_change_users() {
+    let directory = tempfile::tempdir().unwrap();
+    refused_for_bob(&directory, &["add", "mallory", "Str0ng!pass", "--admin", "true"]);
+    refused_for_bob(&directory, &["edit", "bob", "--role", "admin"]);
+    std::fs::write(directory.path().join("users.csv"), "username,role\nmallory,admin\n").unwrap();
+    refused_for_bob(&directory, &["import", "users.csv", "Str0ng!pass"]);
+
+    // Nobody was added
+    login_manager(&directory).arg("count").assert().success().stdout("2 users\n");
+    login_manager(&directory).args(["verify", "mallory", "Str0ng!pass"]).assert().code(4);
+
+    // An admin can do all three
+    login_manager(&directory)
+        .args(["add", "carol", "Str0ng!pass", "--as", "admin"])
+        .write_stdin("password\n")
+        .assert()
+        .success();
+    login_manager(&directory)
+        .args(["edit", "carol", "--role", "moderator", "--as", "admin"])
+        .write_stdin("password\n")
+        .assert()
+        .success();
+    login_manager(&directory)
+        .args(["import", "users.csv", "Str0ng!pass", "--as", "admin"])
+        .write_stdin("password\n")
+        .assert()
+        .success();
+    login_manager(&directory).args(["verify", "mallory", "Str0ng!pass"]).assert().code(0);
+}
+
+#[test]
+fn verify_has_a_status_for_each_outcome() {
+    let directory = tempfile::tempdir().unwrap();
+    login_manager(&directory).args(["verify", "admin", "password"]).assert().code(0).stdout("GRANTED (admin)\n");
+    login_manager(&directory).args(["verify", "admin", "wrong"]).assert().code(3).stdout("DENIED\n");
+    login_manager(&directory).args(["verify", "nobody", "password"]).assert().code(4).stdout("UNKNOWN\n");
+}